@@ -0,0 +1,6 @@
+(mod (INDEX)
+     (include *standard-cl-21*)
+     (defun fact-base (VALUE) (x "reached the base" VALUE))
+     (defun factorial (VALUE) (if (= VALUE 1) (fact-base VALUE) (* VALUE (factorial (- VALUE 1)))))
+     (factorial INDEX)
+     )
@@ -0,0 +1,7 @@
+use clvm_tools_rs::classic::clvm_tools::cmds::cldiff;
+use std::env;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    cldiff(&args);
+}
@@ -470,6 +470,19 @@ impl Stream {
     }
 }
 
+impl std::io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(Stream::write(
+            self,
+            Bytes::new(Some(BytesFromType::Raw(buf.to_vec()))),
+        ))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
 pub fn bi_zero() -> Number {
     Zero::zero()
 }
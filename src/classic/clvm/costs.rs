@@ -69,3 +69,4 @@ pub const LOGNOT_COST_PER_BYTE: u64 = 3;
 
 pub const APPLY_COST: u64 = 90;
 pub const QUOTE_COST: u64 = 20;
+pub const OP_COST: u64 = 1;
@@ -76,7 +76,11 @@ pub fn detect_modern(allocator: &mut Allocator, sexp: NodePtr) -> Option<i32> {
     })
 }
 
-fn compile_clvm_text(
+/// Compile chialisp text with the compiler for its dialect: the modern one when
+/// it includes a *standard-cl-2x* dialect and the classic one otherwise.  The
+/// symbols of either are added to symbol_table; the classic compiler names each
+/// function by its hash.
+pub fn compile_clvm_text(
     allocator: &mut Allocator,
     search_paths: &[String],
     symbol_table: &mut HashMap<String, String>,
@@ -109,6 +113,7 @@ fn compile_clvm_text(
         let run_program = run_program_for_search_paths(input_path, search_paths, false);
         let run_program_output =
             run_program.run_program(allocator, compile_invoke_code, input_sexp, None)?;
+        symbol_table.extend(run_program.get_compiles());
         Ok(run_program_output.1)
     }
 }
@@ -1,33 +1,35 @@
 use core::cell::RefCell;
 
+use std::borrow::Borrow;
+
 use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::io;
-use std::io::Write;
-use std::mem::swap;
+use std::io::{BufRead, IsTerminal, Write};
 use std::rc::Rc;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::SystemTime;
 
 use core::cmp::max;
 
 use linked_hash_map::LinkedHashMap;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 use yaml_rust::{Yaml, YamlEmitter};
 
 use clvm_rs::allocator::{Allocator, NodePtr};
-use clvm_rs::reduction::EvalErr;
+use clvm_rs::cost::Cost;
 use clvm_rs::run_program::PreEval;
 
 use crate::classic::clvm::__type_compatibility__::{t, Bytes, BytesFromType, Stream, Tuple};
 use crate::classic::clvm::keyword_from_atom;
 use crate::classic::clvm::serialize::{sexp_from_stream, sexp_to_stream, SimpleCreateCLVMObject};
-use crate::classic::clvm::sexp::{enlist, proper_list, sexp_as_bin};
-use crate::classic::clvm_tools::binutils::{assemble_from_ir, disassemble, disassemble_with_kw};
+use crate::classic::clvm::sexp::{enlist, sexp_as_bin};
+use crate::classic::clvm_tools::binutils::{
+    assemble, assemble_from_ir, disassemble, disassemble_with_kw,
+};
 use crate::classic::clvm_tools::clvmc::detect_modern;
 use crate::classic::clvm_tools::debug::{
-    check_unused, trace_pre_eval, trace_to_table, trace_to_text,
+    check_unused, JsonTraceSink, TableTraceSink, TextTraceSink, TraceRecorder,
 };
 use crate::classic::clvm_tools::ir::reader::read_ir;
 use crate::classic::clvm_tools::sha256tree::sha256tree;
@@ -43,16 +45,39 @@ use crate::classic::platform::argparse::{
     TArgOptionAction, TArgumentParserProps,
 };
 
-use crate::compiler::cldb::{hex_to_modern_sexp, CldbNoOverride, CldbRun, CldbRunEnv};
-use crate::compiler::clvm::start_step;
-use crate::compiler::compiler::{compile_file, run_optimizer, DefaultCompilerOpts};
+use crate::compiler::backtrace::{backtrace_run, format_backtrace};
+use crate::compiler::cldb::{
+    compile_classic_program, hex_to_modern_sexp, is_classic_mod, program_helpers, CldbMockFunction,
+    CldbNoOverride, CldbOverrideBespokeCode, CldbRun, CldbRunEnv, CldbRunnable,
+    CldbSingleBespokeOverride,
+};
+use crate::compiler::cltest::{find_test_files, junit_xml, run_test_file};
+use crate::compiler::clvm::{convert_from_clvm_rs, convert_to_clvm_rs, start_step};
+use crate::compiler::compiler::{
+    compile_file, compose_run_function, run_optimizer, DefaultCompilerOpts,
+};
 use crate::compiler::comptypes::{CompileErr, CompilerOpts};
+use crate::compiler::coverage::{coverage_run, program_with_source_locations, Coverage};
+use crate::compiler::dap::serve_dap;
 use crate::compiler::debug::build_symbol_table_mut;
+use crate::compiler::debugger::CldbDebugger;
+use crate::compiler::differential::{differential_run, Verdict};
+use crate::compiler::equivalence::{
+    check_equivalence, compile_classic, compile_modern, inject_dialect,
+};
+use crate::compiler::evaluate::Evaluator;
+use crate::compiler::migrate::migrate;
 use crate::compiler::preprocessor::gather_dependencies;
 use crate::compiler::prims;
+use crate::compiler::profile::profile_run;
+use crate::compiler::proptest::{
+    compile_property, function_runner, program_helper_forms, ArgGenerator, PropertyCheck,
+};
+use crate::compiler::repl::{replay_transcript, Repl};
 use crate::compiler::sexp;
 use crate::compiler::sexp::{decode_string, parse_sexp};
 use crate::compiler::srcloc::Srcloc;
+use crate::compiler::trace::trace_run;
 
 use crate::util::collapse;
 use crate::util::version;
@@ -260,6 +285,78 @@ fn to_yaml(entries: &[BTreeMap<String, String>]) -> Yaml {
     Yaml::Array(result_array)
 }
 
+/// How cldb shows the rows it produces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CldbFormat {
+    Yaml,
+    Json,
+    /// One json object per row on its own line, printed as soon as the row is
+    /// produced.
+    JsonLines,
+}
+
+impl CldbFormat {
+    /// The format named by cldb's --format option.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "yaml" => Some(CldbFormat::Yaml),
+            "json" => Some(CldbFormat::Json),
+            "jsonl" => Some(CldbFormat::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub fn rows_string(&self, rows: Vec<BTreeMap<String, String>>) -> String {
+        match self {
+            CldbFormat::Yaml => {
+                let mut result = String::new();
+                let mut emitter = YamlEmitter::new(&mut result);
+                match emitter.dump(&to_yaml(&rows)) {
+                    Ok(_) => result,
+                    Err(e) => format!("error producing yaml: {e:?}"),
+                }
+            }
+            CldbFormat::Json => serde_json::to_string(&rows)
+                .unwrap_or_else(|e| format!("error producing json: {e:?}")),
+            CldbFormat::JsonLines => rows
+                .iter()
+                .map(|row| {
+                    serde_json::to_string(row)
+                        .unwrap_or_else(|e| format!("error producing json: {e:?}"))
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+fn cldb_interactive(allocator: &mut Allocator, debugger: &mut CldbDebugger, format: CldbFormat) {
+    let stdin = io::stdin();
+    let prompt = stdin.is_terminal();
+    loop {
+        if prompt {
+            print!("(cldb) ");
+            io::stdout().flush().ok();
+        }
+
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            _ => {}
+        }
+
+        let command = line.trim();
+        if command == "q" || command == "quit" {
+            return;
+        }
+
+        let rows = debugger.command(allocator, command);
+        if !rows.is_empty() {
+            println!("{}", format.rows_string(rows));
+        }
+    }
+}
+
 pub fn cldb(args: &[String]) {
     let tool_name = "cldb".to_string();
     let props = TArgumentParserProps {
@@ -295,6 +392,73 @@ pub fn cldb(args: &[String]) {
             .set_type(Rc::new(PathOrCodeConv {}))
             .set_help("path to symbol file".to_string()),
     );
+    parser.add_argument(
+        vec!["-m".to_string(), "--max-cost".to_string()],
+        Argument::new()
+            .set_type(Rc::new(IntConversion::new(Rc::new(|| "help".to_string()))))
+            .set_help("stop with a failure when the run costs more than this".to_string()),
+    );
+    parser.add_argument(
+        vec!["--dap".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help(
+                "serve the debug adapter protocol on stdin and stdout for an editor".to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--interactive".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help(
+                "read debugger commands (step, next, finish, continue, reverse-step, reverse-continue, break, delete, print, origin, watch, unwatch, backtrace, quit) from stdin"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--format".to_string()],
+        Argument::new()
+            .set_default(ArgumentValue::ArgString(None, "yaml".to_string()))
+            .set_help(
+                "output format: yaml, json, or jsonl to print each step as it's produced"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--mock".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help(
+                "replace calls to a function with a chialisp expression of its arguments, given as name=expression; mocked calls cost nothing"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["-w".to_string(), "--watch".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help("show the value of a chialisp expression at each step".to_string()),
+    );
+    parser.add_argument(
+        vec!["--env-diff".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help(
+                "show each apply's environment as the paths that changed since the last one"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--fold-unchanged".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help(
+                "with --env-diff, also show the environment with unchanged parts folded behind their tree hash"
+                    .to_string(),
+            ),
+    );
     parser.add_argument(
         vec!["path_or_code".to_string()],
         Argument::new()
@@ -327,6 +491,27 @@ pub fn cldb(args: &[String]) {
         Ok(pa) => pa,
     };
 
+    if let Some(ArgumentValue::ArgBool(true)) = parsed_args.get("dap") {
+        // The program and its environment come from the editor's launch request.
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        if let Err(e) = serve_dap(&mut stdin.lock(), &mut stdout.lock()) {
+            eprintln!("{e}");
+        }
+        return;
+    }
+
+    let format = match parsed_args.get("format") {
+        Some(ArgumentValue::ArgString(_, name)) => match CldbFormat::parse(name) {
+            Some(format) => format,
+            None => {
+                println!("FAIL: unknown format {name}, expected yaml, json or jsonl");
+                return;
+            }
+        },
+        _ => CldbFormat::Yaml,
+    };
+
     if let Some(ArgumentValue::ArgArray(v)) = parsed_args.get("include") {
         for p in v {
             if let ArgumentValue::ArgString(_, s) = p {
@@ -370,21 +555,37 @@ pub fn cldb(args: &[String]) {
         .set_search_paths(&search_paths);
 
     let mut use_symbol_table = symbol_table.unwrap_or_default();
-    let unopt_res = compile_file(
-        &mut allocator,
-        runner.clone(),
-        opts.clone(),
-        &input_program,
-        &mut use_symbol_table,
-    );
+    let is_hex = matches!(parsed_args.get("hex"), Some(ArgumentValue::ArgBool(true)));
+    // Classic programs are compiled as the classic compiler would so that they
+    // step through the same code as they'd run.
+    let classic = !is_hex && is_classic_mod(&mut allocator, &input_program);
+    let unopt_res = if classic {
+        compile_classic_program(
+            &mut allocator,
+            &search_paths,
+            &use_filename,
+            &input_program,
+            &mut use_symbol_table,
+        )
+        .map(|p| p.as_ref().clone())
+    } else {
+        compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts.clone(),
+            &input_program,
+            &mut use_symbol_table,
+        )
+    };
 
     let mut output = Vec::new();
-    let yamlette_string = |to_print: Vec<BTreeMap<String, String>>| {
-        let mut result = String::new();
-        let mut emitter = YamlEmitter::new(&mut result);
-        match emitter.dump(&to_yaml(&to_print)) {
-            Ok(_) => result,
-            Err(e) => format!("error producing yaml: {e:?}"),
+    let yamlette_string = |to_print: Vec<BTreeMap<String, String>>| format.rows_string(to_print);
+    // Rows are kept until the end of the run unless the format streams them.
+    let emit = |row: BTreeMap<String, String>, output: &mut Vec<BTreeMap<String, String>>| {
+        if format == CldbFormat::JsonLines {
+            println!("{}", format.rows_string(vec![row]));
+        } else {
+            output.push(row);
         }
     };
 
@@ -397,7 +598,7 @@ pub fn cldb(args: &[String]) {
         )
         .map_err(|_| CompileErr(prog_srcloc, "Failed to parse hex".to_string())),
         _ => {
-            if do_optimize {
+            if do_optimize && !classic {
                 unopt_res.and_then(|x| run_optimizer(&mut allocator, runner.clone(), Rc::new(x)))
             } else {
                 unopt_res.map(Rc::new)
@@ -460,46 +661,935 @@ pub fn cldb(args: &[String]) {
     }
     let program_lines: Vec<String> = input_program.lines().map(|x| x.to_string()).collect();
     let step = start_step(program, args);
-    let cldbenv = CldbRunEnv::new(
-        input_file,
-        program_lines,
-        Box::new(CldbNoOverride::new_symbols(use_symbol_table)),
+    let mocks = string_list_arg(&parsed_args, "mock");
+    let runnable: Box<dyn CldbRunnable> = if mocks.is_empty() {
+        Box::new(CldbNoOverride::new_symbols(use_symbol_table.clone()))
+    } else {
+        let source = if is_hex {
+            None
+        } else {
+            Some(input_program.as_str())
+        };
+        match read_mocks(
+            runner.clone(),
+            opts.clone(),
+            &use_symbol_table,
+            source,
+            &mocks,
+        ) {
+            Ok(overrides) => Box::new(CldbOverrideBespokeCode::new(
+                use_symbol_table.clone(),
+                overrides,
+            )),
+            Err(e) => {
+                let mut mock_error = BTreeMap::new();
+                mock_error.insert("Error".to_string(), e);
+                println!("{}", yamlette_string(vec![mock_error]));
+                return;
+            }
+        }
+    };
+    let mut cldbenv = CldbRunEnv::new(input_file, program_lines, runnable);
+    cldbenv.set_symbol_table(use_symbol_table.clone());
+    if let Some(ArgumentValue::ArgBool(true)) = parsed_args.get("env_diff") {
+        cldbenv.set_env_diff(matches!(
+            parsed_args.get("fold_unchanged"),
+            Some(ArgumentValue::ArgBool(true))
+        ));
+    }
+
+    let watches = string_list_arg(&parsed_args, "watch");
+
+    let interactive = matches!(
+        parsed_args.get("interactive"),
+        Some(ArgumentValue::ArgBool(true))
     );
+    // The debugger keeps the call stack, so with a symbol table it can say where
+    // a failing run was.
+    if interactive || !watches.is_empty() || !use_symbol_table.is_empty() {
+        let mut debugger = CldbDebugger::new(
+            runner,
+            Rc::new(prim_map),
+            use_symbol_table,
+            Box::new(cldbenv),
+            step,
+        );
+        if let Some(ArgumentValue::ArgInt(max_cost)) = parsed_args.get("max_cost") {
+            debugger.set_max_cost(Some(max(0, *max_cost) as u64));
+        }
+        if !is_hex && (interactive || !watches.is_empty()) {
+            if let Err(e) = debugger.set_program_source(opts, &input_program) {
+                let mut source_error = BTreeMap::new();
+                source_error.insert("Error-Location".to_string(), e.0.to_string());
+                source_error.insert("Error".to_string(), e.1);
+                println!("{}", yamlette_string(vec![source_error]));
+                return;
+            }
+        }
+        for w in watches.iter() {
+            if let Err(e) = debugger.add_watch(w) {
+                let mut watch_error = BTreeMap::new();
+                watch_error.insert("Error".to_string(), e);
+                println!("{}", yamlette_string(vec![watch_error]));
+                return;
+            }
+        }
+
+        if interactive {
+            debugger.set_reverse_stepping(true);
+            cldb_interactive(&mut allocator, &mut debugger, format);
+        } else {
+            while !debugger.is_ended() {
+                if let Some(result) = debugger.advance(&mut allocator) {
+                    emit(result, &mut output);
+                }
+            }
+            for frame in debugger.failure_backtrace() {
+                emit(frame, &mut output);
+            }
+            if format != CldbFormat::JsonLines {
+                println!("{}", yamlette_string(output));
+            }
+        }
+        return;
+    }
+
     let mut cldbrun = CldbRun::new(runner, Rc::new(prim_map), Box::new(cldbenv), step);
+    if let Some(ArgumentValue::ArgInt(max_cost)) = parsed_args.get("max_cost") {
+        cldbrun.set_max_cost(Some(max(0, *max_cost) as u64));
+    }
 
     loop {
         if cldbrun.is_ended() {
-            println!("{}", yamlette_string(output));
+            if format != CldbFormat::JsonLines {
+                println!("{}", yamlette_string(output));
+            }
+            return;
+        }
+
+        if let Some(result) = cldbrun.step(&mut allocator) {
+            emit(result, &mut output);
+        }
+    }
+}
+
+pub fn repl(args: &[String]) {
+    let tool_name = "repl".to_string();
+    let props = TArgumentParserProps {
+        description: "Evaluate chialisp forms and expressions interactively.".to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for :load, :include and included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["--run".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help(
+                "compile expressions and run them on clvm, showing their cost, rather than evaluating them"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--replay".to_string()],
+        Argument::new().set_help(
+            "run a transcript of a session, checking each output against the one recorded after its input"
+                .to_string(),
+        ),
+    );
+    parser.add_argument(
+        vec!["--history".to_string()],
+        Argument::new().set_help(
+            "file to keep the history of lines typed in, by default .chialisp_history in the home directory"
+                .to_string(),
+        ),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
+            return;
+        }
+        Ok(pa) => pa,
+    };
+
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new("*program*")).set_search_paths(&search_paths);
+    let mut repl = Repl::new(opts, runner);
+    repl.set_execute(parsed_args.contains_key("run"));
+
+    if let Some(ArgumentValue::ArgString(_, path)) = parsed_args.get("replay") {
+        let transcript = match fs::read_to_string(path) {
+            Ok(transcript) => transcript,
+            Err(e) => {
+                println!("FAIL: could not read {path}: {e}");
+                std::process::exit(1);
+            }
+        };
+        let (inputs, mismatches) = replay_transcript(&mut allocator, &mut repl, &transcript);
+        for m in mismatches.iter() {
+            println!(
+                "{path}:{}: {}\nexpected:\n{}\ngot:\n{}\n",
+                m.line, m.input, m.expected, m.actual
+            );
+        }
+        println!("replayed {inputs} inputs, {} failed", mismatches.len());
+        if !mismatches.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if io::stdin().is_terminal() {
+        let history = if let Some(ArgumentValue::ArgString(_, path)) = parsed_args.get("history") {
+            Some(path.clone())
+        } else {
+            std::env::var("HOME")
+                .ok()
+                .map(|home| format!("{home}/.chialisp_history"))
+        };
+        repl_edit_lines(&mut allocator, &mut repl, history);
+    } else {
+        repl_read_lines(&mut allocator, &mut repl);
+    }
+}
+
+// Read lines for the repl from stdin as they come, such as from a pipe.
+fn repl_read_lines(allocator: &mut Allocator, repl: &mut Repl) {
+    print!(">>> ");
+    io::stdout().flush().unwrap();
+
+    for l in io::stdin().lock().lines() {
+        let line = match l {
+            Err(_) => break,
+            Ok(line) => line,
+        };
+
+        let output = repl.respond(allocator, line);
+        if !output.is_empty() {
+            println!("{output}");
+        }
+        print!("{}", repl_prompt(repl));
+        io::stdout().flush().unwrap();
+    }
+}
+
+fn repl_prompt(repl: &Repl) -> &'static str {
+    if repl.is_incomplete() {
+        "... "
+    } else {
+        ">>> "
+    }
+}
+
+// Read lines for the repl from a terminal with line editing, keeping their
+// history in the given file between sessions.
+#[cfg(not(target_family = "wasm"))]
+fn repl_edit_lines(allocator: &mut Allocator, repl: &mut Repl, history: Option<String>) {
+    let mut editor = match rustyline::Editor::<()>::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            println!("FAIL: could not set up line editing: {e}");
+            return;
+        }
+    };
+
+    if let Some(history) = &history {
+        // There's no history yet the first time.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        match editor.readline(repl_prompt(repl)) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    editor.add_history_entry(line.as_str());
+                }
+                let output = repl.respond(allocator, line);
+                if !output.is_empty() {
+                    println!("{output}");
+                }
+            }
+            Err(rustyline::error::ReadlineError::Interrupted) => {
+                repl.discard_input();
+            }
+            Err(_) => break,
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(e) = editor.save_history(history) {
+            println!("could not save history to {history}: {e}");
+        }
+    }
+}
+
+#[cfg(target_family = "wasm")]
+fn repl_edit_lines(allocator: &mut Allocator, repl: &mut Repl, _history: Option<String>) {
+    repl_read_lines(allocator, repl);
+}
+
+pub fn cltest(args: &[String]) {
+    let tool_name = "cltest".to_string();
+    let props = TArgumentParserProps {
+        description: "Run chialisp unit tests.".to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["--junit".to_string()],
+        Argument::new().set_help("write the results as JUnit XML to this file".to_string()),
+    );
+    parser.add_argument(
+        vec!["paths".to_string()],
+        Argument::new()
+            .set_n_args(NArgsSpec::KleeneStar)
+            .set_help(
+                "test files, or directories to find *_test.clsp files in (by default the current directory)"
+                    .to_string(),
+            ),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
+            return;
+        }
+        Ok(pa) => pa,
+    };
+
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let mut paths = string_list_arg(&parsed_args, "paths");
+    if paths.is_empty() {
+        paths.push(".".to_string());
+    }
+
+    let mut test_files = Vec::new();
+    for path in paths.iter() {
+        let path = std::path::Path::new(path);
+        if path.is_dir() {
+            test_files.append(&mut find_test_files(path));
+        } else {
+            test_files.push(path.to_path_buf());
+        }
+    }
+
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let mut results = Vec::new();
+    for file in test_files.iter() {
+        let filename = file.to_string_lossy().to_string();
+        for result in run_test_file(&mut allocator, runner.clone(), &search_paths, &filename) {
+            println!("{result}");
+            results.push(result);
+        }
+    }
+
+    let passed = results.iter().filter(|r| r.passed()).count();
+    println!(
+        "{passed} passed, {} failed in {} files",
+        results.len() - passed,
+        test_files.len()
+    );
+
+    if let Some(ArgumentValue::ArgString(_, junit)) = parsed_args.get("junit") {
+        if let Err(e) = fs::write(junit, junit_xml(&results)) {
+            println!("FAIL: could not write {junit}: {e}");
+            std::process::exit(1);
+        }
+    }
+
+    if passed != results.len() {
+        std::process::exit(1);
+    }
+}
+
+pub fn clprop(args: &[String]) {
+    let tool_name = "clprop".to_string();
+    let props = TArgumentParserProps {
+        description: "Check a property of a chialisp function with generated arguments."
+            .to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["--function".to_string()],
+        Argument::new().set_help("the function to test".to_string()),
+    );
+    parser.add_argument(
+        vec!["--property".to_string()],
+        Argument::new().set_help(
+            "a chialisp expression of RESULT and the function's arguments that should be true"
+                .to_string(),
+        ),
+    );
+    parser.add_argument(
+        vec!["--gen".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help(
+                "generate an argument with name=generator, one of any, atom, int, bytes<n>, list:<generator> or conditions"
+                    .to_string(),
+            ),
+    );
+    parser.add_argument(
+        vec!["--trials".to_string()],
+        Argument::new()
+            .set_type(Rc::new(IntConversion::new(Rc::new(|| "help".to_string()))))
+            .set_default(ArgumentValue::ArgInt(100))
+            .set_help("how many sets of arguments to try".to_string()),
+    );
+    parser.add_argument(
+        vec!["--seed".to_string()],
+        Argument::new()
+            .set_type(Rc::new(IntConversion::new(Rc::new(|| "help".to_string()))))
+            .set_help("seed for the generators, to repeat a run".to_string()),
+    );
+    parser.add_argument(
+        vec!["--max-shrinks".to_string()],
+        Argument::new()
+            .set_type(Rc::new(IntConversion::new(Rc::new(|| "help".to_string()))))
+            .set_default(ArgumentValue::ArgInt(1000))
+            .set_help("how many smaller arguments to try for a counterexample".to_string()),
+    );
+    parser.add_argument(
+        vec!["path".to_string()],
+        Argument::new().set_help("the chialisp program containing the function".to_string()),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
+            return;
+        }
+        Ok(pa) => pa,
+    };
+
+    let string_arg = |name: &str| {
+        if let Some(ArgumentValue::ArgString(_, s)) = parsed_args.get(name) {
+            Some(s.clone())
+        } else {
+            None
+        }
+    };
+    let int_arg = |name: &str| {
+        if let Some(ArgumentValue::ArgInt(n)) = parsed_args.get(name) {
+            Some(*n)
+        } else {
+            None
+        }
+    };
+    let (path, function, property) = match (
+        string_arg("path"),
+        string_arg("function"),
+        string_arg("property"),
+    ) {
+        (Some(path), Some(function), Some(property)) => (path, function, property),
+        _ => {
+            println!("FAIL: a program, --function and --property are needed");
+            return;
+        }
+    };
+
+    let mut generators = HashMap::new();
+    for gen in string_list_arg(&parsed_args, "gen").iter() {
+        let parsed = gen
+            .split_once('=')
+            .ok_or_else(|| format!("expected name=generator in {gen}"))
+            .and_then(|(name, spec)| ArgGenerator::parse(spec).map(|g| (name, g)));
+        match parsed {
+            Ok((name, g)) => {
+                generators.insert(name.as_bytes().to_vec(), g);
+            }
+            Err(e) => {
+                println!("FAIL: {e}");
+                return;
+            }
+        }
+    }
+
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(&path)).set_search_paths(&search_paths);
+    let mut symbol_table = HashMap::new();
+
+    let checked = fs::read_to_string(&path)
+        .map_err(|e| CompileErr(Srcloc::start(&path), format!("could not read: {e}")))
+        .and_then(|content| {
+            let program = compile_file(
+                &mut allocator,
+                runner.clone(),
+                opts.clone(),
+                &content,
+                &mut symbol_table,
+            )?;
+            let (function_code, function_args) =
+                function_runner(Rc::new(program), &symbol_table, &function)?;
+            let source = parse_sexp(Srcloc::start(&path), content.bytes())?;
+            let helpers = source
+                .first()
+                .map(|s| program_helper_forms(s))
+                .unwrap_or_default();
+            let property_code = compile_property(
+                &mut allocator,
+                runner.clone(),
+                opts.clone(),
+                function_args.clone(),
+                &helpers,
+                &property,
+            )?;
+            Ok(PropertyCheck {
+                function: function_code,
+                args: function_args,
+                property: property_code,
+                generators,
+            })
+        });
+    let check = match checked {
+        Ok(check) => check,
+        Err(CompileErr(l, e)) => {
+            println!("FAIL: {l}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let seed = int_arg("seed")
+        .map(|s| s as u64)
+        .unwrap_or_else(rand::random);
+    let trials = int_arg("trials").unwrap_or(100) as usize;
+    let max_shrinks = int_arg("max_shrinks").unwrap_or(1000) as usize;
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    match check.check(&mut allocator, runner, &mut rng, trials, max_shrinks) {
+        None => {
+            println!("OK, {function} passed {trials} trials (seed {seed})");
+        }
+        Some(counterexample) => {
+            println!("FAIL: {function} (seed {seed})\n{counterexample}");
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn cldiff(args: &[String]) {
+    let tool_name = "cldiff".to_string();
+    let props = TArgumentParserProps {
+        description: "Compare a chialisp program's results from the evaluator and compiled code."
+            .to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["--input".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help("an environment to run the program with, () if none".to_string()),
+    );
+    parser.add_argument(
+        vec!["-O".to_string(), "--optimize".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help("optimize the compiled code".to_string()),
+    );
+    parser.add_argument(
+        vec!["--frontend-opt".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::StoreTrue)
+            .set_help("run the frontend optimizer when compiling".to_string()),
+    );
+    parser.add_argument(
+        vec!["path".to_string()],
+        Argument::new().set_help("the chialisp program".to_string()),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
+            return;
+        }
+        Ok(pa) => pa,
+    };
+
+    let path = if let Some(ArgumentValue::ArgString(_, s)) = parsed_args.get("path") {
+        s.clone()
+    } else {
+        println!("FAIL: a program is needed");
+        return;
+    };
+
+    let mut input_texts = string_list_arg(&parsed_args, "input");
+    if input_texts.is_empty() {
+        input_texts.push("()".to_string());
+    }
+    let mut allocator = Allocator::new();
+    let mut inputs = Vec::new();
+    for text in input_texts.iter() {
+        let input = assemble(&mut allocator, text)
+            .map_err(|e| e.1)
+            .and_then(|node| {
+                convert_from_clvm_rs(&mut allocator, Srcloc::start("*input*"), node)
+                    .map_err(|e| e.to_string())
+            });
+        match input {
+            Ok(input) => inputs.push(input),
+            Err(e) => {
+                println!("FAIL: bad input {text}: {e}");
+                return;
+            }
+        }
+    }
+
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(&path))
+        .set_search_paths(&search_paths)
+        .set_optimize(parsed_args.contains_key("optimize"))
+        .set_frontend_opt(parsed_args.contains_key("frontend_opt"));
+
+    let results = fs::read_to_string(&path)
+        .map_err(|e| CompileErr(Srcloc::start(&path), format!("could not read: {e}")))
+        .and_then(|content| {
+            let program = parse_sexp(Srcloc::start(&path), content.bytes())?;
+            differential_run(&mut allocator, runner, opts, &program, &inputs)
+        });
+    let results = match results {
+        Ok(results) => results,
+        Err(CompileErr(l, e)) => {
+            println!("FAIL: {l}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    for result in results.iter() {
+        println!("{result}");
+    }
+    if results.iter().any(|r| r.verdict == Verdict::Mismatch) {
+        std::process::exit(1);
+    }
+}
+
+pub fn clequiv(args: &[String]) {
+    let tool_name = "clequiv".to_string();
+    let props = TArgumentParserProps {
+        description: "Compare a classic chialisp program with its compilation in a modern dialect."
+            .to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["--solution".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help("a solution to run both programs with, () if none".to_string()),
+    );
+    parser.add_argument(
+        vec!["--dialect".to_string()],
+        Argument::new()
+            .set_default(ArgumentValue::ArgString(
+                None,
+                "*standard-cl-22*".to_string(),
+            ))
+            .set_help("the modern dialect to include, *standard-cl-22* by default".to_string()),
+    );
+    parser.add_argument(
+        vec!["--max-cost".to_string()],
+        Argument::new()
+            .set_type(Rc::new(IntConversion::new(Rc::new(|| "help".to_string()))))
+            .set_default(ArgumentValue::ArgInt(11_000_000_000))
+            .set_help("the most either run may cost".to_string()),
+    );
+    parser.add_argument(
+        vec!["path".to_string()],
+        Argument::new().set_help("the classic chialisp program".to_string()),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
+            return;
+        }
+        Ok(pa) => pa,
+    };
+
+    let string_arg = |name: &str| {
+        if let Some(ArgumentValue::ArgString(_, s)) = parsed_args.get(name) {
+            Some(s.clone())
+        } else {
+            None
+        }
+    };
+    let path = if let Some(path) = string_arg("path") {
+        path
+    } else {
+        println!("FAIL: a program is needed");
+        return;
+    };
+    let dialect = string_arg("dialect").unwrap_or_else(|| "*standard-cl-22*".to_string());
+    let max_cost = if let Some(ArgumentValue::ArgInt(n)) = parsed_args.get("max_cost") {
+        Some(*n as u64)
+    } else {
+        None
+    };
+
+    let mut allocator = Allocator::new();
+    let mut solution_texts = string_list_arg(&parsed_args, "solution");
+    if solution_texts.is_empty() {
+        solution_texts.push("()".to_string());
+    }
+    let mut solutions = Vec::new();
+    for text in solution_texts.iter() {
+        let solution = assemble(&mut allocator, text)
+            .map_err(|e| e.1)
+            .and_then(|node| {
+                convert_from_clvm_rs(&mut allocator, Srcloc::start("*solution*"), node)
+                    .map_err(|e| e.to_string())
+            });
+        match solution {
+            Ok(solution) => solutions.push(solution),
+            Err(e) => {
+                println!("FAIL: bad solution {text}: {e}");
+                return;
+            }
+        }
+    }
+
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let compiled = fs::read_to_string(&path)
+        .map_err(|e| CompileErr(Srcloc::start(&path), format!("could not read: {e}")))
+        .and_then(|content| {
+            let classic = compile_classic(&mut allocator, &search_paths, &path, &content)?;
+            let source = parse_sexp(Srcloc::start(&path), content.bytes())?;
+            let modern_forms = inject_dialect(&source, &dialect)?;
+            let modern = compile_modern(
+                &mut allocator,
+                runner.clone(),
+                &search_paths,
+                &path,
+                &modern_forms,
+            )?;
+            Ok((classic, modern))
+        });
+    let (classic, modern) = match compiled {
+        Ok(compiled) => compiled,
+        Err(CompileErr(l, e)) => {
+            println!("FAIL: {l}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    println!(
+        "tree hash: classic {} modern {}",
+        classic.tree_hash(),
+        modern.tree_hash()
+    );
+    let results = check_equivalence(
+        &mut allocator,
+        runner,
+        &classic,
+        &modern,
+        &solutions,
+        max_cost,
+    );
+    for result in results.iter() {
+        print!("{result}");
+    }
+    if !results.iter().all(|r| r.results_match()) {
+        std::process::exit(1);
+    }
+}
+
+pub fn clsp_migrate(args: &[String]) {
+    let tool_name = "clsp-migrate".to_string();
+    let props = TArgumentParserProps {
+        description: "Rewrite a classic chialisp program in a modern dialect.".to_string(),
+        prog: format!("clvm_tools {tool_name}"),
+    };
+
+    let mut parser = ArgumentParser::new(Some(props));
+    parser.add_argument(
+        vec!["-i".to_string(), "--include".to_string()],
+        Argument::new()
+            .set_type(Rc::new(PathJoin {}))
+            .set_help("add a search path for included files".to_string())
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![])),
+    );
+    parser.add_argument(
+        vec!["-o".to_string(), "--output".to_string()],
+        Argument::new().set_help("where to write the migrated program".to_string()),
+    );
+    parser.add_argument(
+        vec!["--solution".to_string()],
+        Argument::new()
+            .set_action(TArgOptionAction::Append)
+            .set_default(ArgumentValue::ArgArray(vec![]))
+            .set_help("a solution to check both programs give the same result for".to_string()),
+    );
+    parser.add_argument(
+        vec!["--dialect".to_string()],
+        Argument::new()
+            .set_default(ArgumentValue::ArgString(
+                None,
+                "*standard-cl-22*".to_string(),
+            ))
+            .set_help("the modern dialect to migrate to, *standard-cl-22* by default".to_string()),
+    );
+    parser.add_argument(
+        vec!["path".to_string()],
+        Argument::new().set_help("the classic chialisp program".to_string()),
+    );
+
+    let arg_vec = args[1..].to_vec();
+    let parsed_args: HashMap<String, ArgumentValue> = match parser.parse_args(&arg_vec) {
+        Err(e) => {
+            println!("FAIL: {e}");
             return;
         }
+        Ok(pa) => pa,
+    };
 
-        if let Some(result) = cldbrun.step(&mut allocator) {
-            output.push(result);
+    let string_arg = |name: &str| {
+        if let Some(ArgumentValue::ArgString(_, s)) = parsed_args.get(name) {
+            Some(s.clone())
+        } else {
+            None
         }
-    }
-}
+    };
+    let path = if let Some(path) = string_arg("path") {
+        path
+    } else {
+        println!("FAIL: a program is needed");
+        return;
+    };
+    let dialect = string_arg("dialect").unwrap_or_else(|| "*standard-cl-22*".to_string());
 
-struct RunLog<T> {
-    log_entries: RefCell<Vec<T>>,
-}
+    let mut allocator = Allocator::new();
+    let mut solutions = Vec::new();
+    for text in string_list_arg(&parsed_args, "solution").iter() {
+        let solution = assemble(&mut allocator, text)
+            .map_err(|e| e.1)
+            .and_then(|node| {
+                convert_from_clvm_rs(&mut allocator, Srcloc::start("*solution*"), node)
+                    .map_err(|e| e.to_string())
+            });
+        match solution {
+            Ok(solution) => solutions.push(solution),
+            Err(e) => {
+                println!("FAIL: bad solution {text}: {e}");
+                return;
+            }
+        }
+    }
 
-impl<T> RunLog<T> {
-    fn push(&self, new_log: T) {
-        self.log_entries.replace_with(|log| {
-            let mut empty_log = Vec::new();
-            swap(&mut empty_log, &mut *log);
-            empty_log.push(new_log);
-            empty_log
+    let search_paths = string_list_arg(&parsed_args, "include");
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let migrated = fs::read_to_string(&path)
+        .map_err(|e| CompileErr(Srcloc::start(&path), format!("could not read: {e}")))
+        .and_then(|content| {
+            let classic = compile_classic(&mut allocator, &search_paths, &path, &content)?;
+            let source = parse_sexp(Srcloc::start(&path), content.bytes())?;
+            let migration = migrate(&source, &dialect)?;
+            let modern = compile_modern(
+                &mut allocator,
+                runner.clone(),
+                &search_paths,
+                &path,
+                &migration.forms,
+            )?;
+            Ok((migration, classic, modern))
         });
+    let (migration, classic, modern) = match migrated {
+        Ok(migrated) => migrated,
+        Err(CompileErr(l, e)) => {
+            println!("FAIL: {l}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    // The report is written as comments, so the output stays a program.
+    let mut report = String::new();
+    for change in migration.changes.iter() {
+        report += &format!("changed {change}\n");
+    }
+    for warning in migration.warnings.iter() {
+        report += &format!("warning {warning}\n");
+    }
+    let results = check_equivalence(&mut allocator, runner, &classic, &modern, &solutions, None);
+    if results.is_empty() {
+        report += "no solutions given, equivalence not checked\n";
+    }
+    for result in results.iter() {
+        report += &result.to_string();
     }
 
-    fn finish(&self) -> Vec<T> {
-        let mut empty_log = Vec::new();
-        self.log_entries.replace_with(|log| {
-            swap(&mut empty_log, &mut *log);
-            Vec::new()
-        });
-        empty_log
+    if let Some(output) = string_arg("output") {
+        if let Err(e) = fs::write(&output, migration.text()) {
+            println!("FAIL: could not write {output}: {e}");
+            std::process::exit(1);
+        }
+    } else {
+        print!("{}", migration.text());
+    }
+    for line in report.lines() {
+        println!("; {line}");
+    }
+    if !results.iter().all(|r| r.results_match()) {
+        std::process::exit(1);
     }
 }
 
@@ -525,27 +1615,6 @@ fn calculate_cost_offset(
     53 - cost as i64
 }
 
-fn fix_log(
-    allocator: &mut Allocator,
-    log_result: &mut [NodePtr],
-    log_updates: &[(NodePtr, Option<NodePtr>)],
-) {
-    let mut update_map: HashMap<NodePtr, Option<NodePtr>> = HashMap::new();
-    for update in log_updates {
-        update_map.insert(update.0, update.1);
-    }
-
-    for (i, entry) in log_result.to_vec().iter().enumerate() {
-        update_map.get(entry).and_then(|v| *v).map(|v| {
-            proper_list(allocator, *entry, true).map(|list| {
-                let mut updated = list.to_vec();
-                updated.push(v);
-                log_result[i] = enlist(allocator, &updated).unwrap();
-            })
-        });
-    }
-}
-
 fn write_sym_output(compiled_lookup: &HashMap<String, String>, path: &str) -> Result<(), String> {
     m! {
         output <- serde_json::to_string(compiled_lookup).map_err(|_| {
@@ -558,6 +1627,340 @@ fn write_sym_output(compiled_lookup: &HashMap<String, String>, path: &str) -> Re
     }
 }
 
+// Read mocks given as name=expression for functions in symbol_table.  The
+// expressions can use the helpers defined in source when it's given.
+fn read_mocks(
+    runner: Rc<dyn TRunProgram>,
+    opts: Rc<dyn CompilerOpts>,
+    symbol_table: &HashMap<String, String>,
+    source: Option<&str>,
+    specs: &[String],
+) -> Result<HashMap<String, Box<dyn CldbSingleBespokeOverride>>, String> {
+    let helpers = if let Some(source) = source {
+        program_helpers(opts.clone(), source).map_err(|e| format!("{}: {}", e.0, e.1))?
+    } else {
+        Vec::new()
+    };
+    let evaluator = Rc::new(Evaluator::new(opts.clone(), runner, helpers));
+    let mut mocks: HashMap<String, Box<dyn CldbSingleBespokeOverride>> = HashMap::new();
+    for spec in specs.iter() {
+        let mock =
+            CldbMockFunction::from_spec(opts.clone(), evaluator.clone(), symbol_table, spec)?;
+        mocks.insert(mock.name().to_string(), Box::new(mock));
+    }
+    Ok(mocks)
+}
+
+fn string_list_arg(parsed_args: &HashMap<String, ArgumentValue>, name: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    if let Some(ArgumentValue::ArgArray(v)) = parsed_args.get(name) {
+        for item in v {
+            if let ArgumentValue::ArgString(_, s) = item {
+                result.push(s.to_string());
+            }
+        }
+    }
+    result
+}
+
+// Run the program and environment in input in the stepping interpreter with
+// the given functions mocked, writing the result as brun would.  The cost is
+// the stepping interpreter's, in which mocked calls cost nothing.
+#[allow(clippy::too_many_arguments)]
+fn write_mocked_run(
+    allocator: &mut Allocator,
+    stdout: &mut Stream,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: HashMap<String, String>,
+    mocks: &[String],
+    max_cost: Option<u64>,
+    show_cost: bool,
+    keywords: &HashMap<Vec<u8>, String>,
+) {
+    let (program, env) = if let Some(pe) = split_program_and_env(allocator, input_file, input) {
+        pe
+    } else {
+        stdout.write_str("FAIL: could not convert program for mocking\n");
+        return;
+    };
+
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(input_file));
+    let overrides = match read_mocks(runner.clone(), opts, &symbol_table, None, mocks) {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            stdout.write_str(&format!("FAIL: {e}\n"));
+            return;
+        }
+    };
+    let cldbenv = CldbRunEnv::new(
+        None,
+        vec![],
+        Box::new(CldbOverrideBespokeCode::new(symbol_table, overrides)),
+    );
+    let mut cldbrun = CldbRun::new(
+        runner,
+        prims::prim_map(),
+        Box::new(cldbenv),
+        start_step(program, env),
+    );
+    cldbrun.set_max_cost(max_cost);
+
+    let mut last_row = BTreeMap::new();
+    while !cldbrun.is_ended() {
+        if let Some(row) = cldbrun.step(allocator) {
+            last_row = row;
+        }
+    }
+
+    if show_cost {
+        stdout.write_str(&format!("cost = {}\n", cldbrun.cost()));
+    }
+    match cldbrun.final_result() {
+        Some(result) => match convert_to_clvm_rs(allocator, result) {
+            Ok(node) => {
+                stdout.write_str(&format!(
+                    "{}\n",
+                    disassemble_with_kw(allocator, node, keywords)
+                ));
+            }
+            Err(e) => {
+                stdout.write_str(&format!("FAIL: {e}\n"));
+            }
+        },
+        None => {
+            let failure = last_row
+                .get("Throw")
+                .or_else(|| last_row.get("Failure"))
+                .cloned()
+                .unwrap_or_else(|| "the run did not finish".to_string());
+            stdout.write_str(&format!("FAIL: {failure}\n"));
+        }
+    }
+}
+
+fn split_program_and_env(
+    allocator: &mut Allocator,
+    input_file: &str,
+    input: NodePtr,
+) -> Option<(Rc<sexp::SExp>, Rc<sexp::SExp>)> {
+    let loc = Srcloc::start(input_file);
+    let converted = convert_from_clvm_rs(allocator, loc, input).ok()?;
+    if let sexp::SExp::Cons(_, program, env) = converted.borrow() {
+        Some((program.clone(), env.clone()))
+    } else {
+        None
+    }
+}
+
+// Replace the program in input (a cons of program and environment) with one
+// that runs only the named function of it, given the environment as the
+// function's arguments.
+fn compose_function_input(
+    allocator: &mut Allocator,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: &HashMap<String, String>,
+    function: &str,
+) -> Result<NodePtr, String> {
+    let (program, env) = split_program_and_env(allocator, input_file, input)
+        .ok_or_else(|| "could not convert program to run a function".to_string())?;
+    let composed = compose_run_function(program, symbol_table, function)
+        .map_err(|e| format!("{}: {}", e.0, e.1))?;
+    let program_node = convert_to_clvm_rs(allocator, composed).map_err(|e| e.to_string())?;
+    let env_node = convert_to_clvm_rs(allocator, env).map_err(|e| e.to_string())?;
+    allocator.new_pair(program_node, env_node).map_err(|e| e.1)
+}
+
+// Run the failing program and environment in input (a cons of the two) again in
+// the stepping interpreter to write the chialisp functions it was inside of when
+// it failed according to the symbol table, which also gives the source
+// locations of the program's code.
+fn write_backtrace(
+    allocator: &mut Allocator,
+    stdout: &mut Stream,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: HashMap<String, String>,
+    max_cost: Option<u64>,
+) {
+    let (program, env) = if let Some(pe) = split_program_and_env(allocator, input_file, input) {
+        pe
+    } else {
+        return;
+    };
+
+    let program = program_with_source_locations(&symbol_table, Srcloc::start(input_file), program);
+    let runner = Rc::new(DefaultProgramRunner::new());
+    if let Err(e) = backtrace_run(
+        allocator,
+        runner,
+        prims::prim_map(),
+        symbol_table,
+        program,
+        env,
+        max_cost,
+    ) {
+        stdout.write_str(&format_backtrace(&e.frames));
+    }
+}
+
+// Run the program and environment in input (a cons of the two) in the stepping
+// interpreter to attribute its cost to functions, writing cost tables to stdout
+// and folded stacks to profile_output.
+fn write_profile(
+    allocator: &mut Allocator,
+    stdout: &mut Stream,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: HashMap<String, String>,
+    max_cost: Option<u64>,
+    profile_output: &str,
+) {
+    let (program, env) = if let Some(pe) = split_program_and_env(allocator, input_file, input) {
+        pe
+    } else {
+        stdout.write_str("FAIL: could not convert program for profiling\n");
+        return;
+    };
+
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let (profiler, result) = profile_run(
+        allocator,
+        runner,
+        prims::prim_map(),
+        symbol_table,
+        program,
+        env,
+        max_cost,
+    );
+
+    stdout.write_str("\n");
+    if let Err(e) = result {
+        stdout.write_str(&format!("profiled run failed: {e}\n"));
+    }
+    stdout.write_str(&profiler.format_tables());
+    if fs::write(profile_output, profiler.folded_stacks()).is_err() {
+        stdout.write_str(&format!("FAIL: failed to write {profile_output}\n"));
+    }
+}
+
+// Run the program and environment in input in the stepping interpreter,
+// recording which source lines and branches it covers according to the
+// symbol table.  The coverage is merged into any already in coverage_output
+// and is also written as lcov to lcov_output if given.
+#[allow(clippy::too_many_arguments)]
+fn write_coverage(
+    allocator: &mut Allocator,
+    stdout: &mut Stream,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: HashMap<String, String>,
+    max_cost: Option<u64>,
+    coverage_output: &str,
+    lcov_output: Option<String>,
+) {
+    let (program, env) = if let Some(pe) = split_program_and_env(allocator, input_file, input) {
+        pe
+    } else {
+        stdout.write_str("FAIL: could not convert program for coverage\n");
+        return;
+    };
+
+    let mut coverage = if let Ok(existing) = fs::read_to_string(coverage_output) {
+        match Coverage::from_json(&existing) {
+            Ok(c) => c,
+            Err(e) => {
+                stdout.write_str(&format!("FAIL: {coverage_output}: {e}\n"));
+                return;
+            }
+        }
+    } else {
+        Coverage::default()
+    };
+
+    let program = program_with_source_locations(&symbol_table, Srcloc::start(input_file), program);
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let (run_coverage, result) =
+        coverage_run(allocator, runner, prims::prim_map(), program, env, max_cost);
+    if let Err(e) = result {
+        stdout.write_str(&format!("coverage run failed: {e}\n"));
+    }
+    coverage.merge(&run_coverage);
+
+    if fs::write(coverage_output, coverage.to_json()).is_err() {
+        stdout.write_str(&format!("FAIL: failed to write {coverage_output}\n"));
+    }
+    if let Some(lcov_output) = lcov_output {
+        if fs::write(&lcov_output, coverage.to_lcov()).is_err() {
+            stdout.write_str(&format!("FAIL: failed to write {lcov_output}\n"));
+        }
+    }
+}
+
+// Run the program and environment in input in the stepping interpreter,
+// streaming a json object per evaluation to trace_output, or to stdout if it's
+// "-".
+fn write_trace_json(
+    allocator: &mut Allocator,
+    stdout: &mut Stream,
+    input_file: &str,
+    input: NodePtr,
+    symbol_table: HashMap<String, String>,
+    max_cost: Option<u64>,
+    trace_output: &str,
+) {
+    let (program, env) = if let Some(pe) = split_program_and_env(allocator, input_file, input) {
+        pe
+    } else {
+        stdout.write_str("FAIL: could not convert program for tracing\n");
+        return;
+    };
+
+    let mut output: Box<dyn Write> = if trace_output == "-" {
+        // What's in the Stream only reaches stdout once the tool is done, so
+        // hand it over now and let the trace follow it as the run goes.
+        if io::stdout().write_all(stdout.get_value().data()).is_err() {
+            stdout.write_str("FAIL: failed to write to stdout\n");
+            return;
+        }
+        *stdout = Stream::new(None);
+        Box::new(io::BufWriter::new(io::stdout().lock()))
+    } else {
+        match fs::File::create(trace_output) {
+            Ok(f) => Box::new(io::BufWriter::new(f)),
+            Err(_) => {
+                stdout.write_str(&format!("FAIL: failed to write {trace_output}\n"));
+                return;
+            }
+        }
+    };
+
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let (result, failed) = {
+        let mut sink = JsonTraceSink::new(output.as_mut(), &disassemble);
+        let result = trace_run(
+            allocator,
+            runner,
+            prims::prim_map(),
+            symbol_table,
+            program,
+            env,
+            max_cost,
+            &mut sink,
+        );
+        (result, sink.failed)
+    };
+    let failed = output.flush().is_err() || failed;
+    if failed {
+        stdout.write_str(&format!("FAIL: failed to write {trace_output}\n"));
+    }
+    if let Err(e) = result {
+        stdout.write_str(&format!("traced run failed: {e}\n"));
+    }
+}
+
 pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, default_stage: u32) {
     let props = TArgumentParserProps {
         description: "Execute a clvm script.".to_string(),
@@ -697,6 +2100,60 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
             .set_default(ArgumentValue::ArgString(None, "main.sym".to_string())),
     );
 
+    if tool_name == "brun" {
+        parser.add_argument(
+            vec!["--profile".to_string()],
+            Argument::new()
+                .set_action(TArgOptionAction::StoreTrue)
+                .set_help(
+                    "Show the cost spent in each function (use with --symbol-table)".to_string(),
+                ),
+        );
+        parser.add_argument(
+            vec!["--folded-output-file".to_string()],
+            Argument::new()
+                .set_type(Rc::new(PathJoin {}))
+                .set_default(ArgumentValue::ArgString(None, "profile.folded".to_string()))
+                .set_help("Where --profile writes folded stacks for flamegraph tools".to_string()),
+        );
+        parser.add_argument(
+            vec!["--coverage".to_string()],
+            Argument::new().set_type(Rc::new(PathJoin {})).set_help(
+                "Merge source coverage of this run into a json file (use with --symbol-table)"
+                    .to_string(),
+            ),
+        );
+        parser.add_argument(
+            vec!["--lcov".to_string()],
+            Argument::new()
+                .set_type(Rc::new(PathJoin {}))
+                .set_help("Also write the merged --coverage as an lcov file".to_string()),
+        );
+        parser.add_argument(
+            vec!["--mock".to_string()],
+            Argument::new()
+                .set_action(TArgOptionAction::Append)
+                .set_default(ArgumentValue::ArgArray(vec![]))
+                .set_help(
+                    "Replace calls to a function with a chialisp expression of its arguments, given as name=expression (use with --symbol-table); mocked calls cost nothing and -c shows the stepping interpreter's cost"
+                        .to_string(),
+                ),
+        );
+        parser.add_argument(
+            vec!["--function".to_string()],
+            Argument::new().set_help(
+                "Run only the named function, with the environment as its arguments (use with --symbol-table)"
+                    .to_string(),
+            ),
+        );
+        parser.add_argument(
+            vec!["--trace-json".to_string()],
+            Argument::new().set_type(Rc::new(PathJoin {})).set_help(
+                "Stream a json object per evaluation step to a file (- for stdout)".to_string(),
+            ),
+        );
+    }
+
     if tool_name == "run" {
         parser.add_argument(
             vec!["--check-unused-args".to_string()],
@@ -885,6 +2342,28 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
     // This code is confusingly woven due to 'run' and 'brun' serving many roles.
     let mut symbol_table: Option<HashMap<String, String>> = None;
     let mut emit_symbol_output = false;
+    let do_profile = matches!(
+        parsed_args.get("profile"),
+        Some(ArgumentValue::ArgBool(true))
+    );
+    let coverage_output = if let Some(ArgumentValue::ArgString(_, f)) = parsed_args.get("coverage")
+    {
+        Some(f.clone())
+    } else {
+        None
+    };
+    let trace_json_output =
+        if let Some(ArgumentValue::ArgString(_, f)) = parsed_args.get("trace_json") {
+            Some(f.clone())
+        } else {
+            None
+        };
+
+    let function = if let Some(ArgumentValue::ArgString(_, f)) = parsed_args.get("function") {
+        Some(f.clone())
+    } else {
+        None
+    };
 
     let symbol_table_clone = parsed_args
         .get("symbol_table")
@@ -991,75 +2470,14 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
         return;
     }
 
-    let mut pre_eval_f: Option<PreEval> = None;
-
-    // Collections used to generate the run log.
-    let log_entries: Arc<Mutex<RunLog<NodePtr>>> = Arc::new(Mutex::new(RunLog {
-        log_entries: RefCell::new(Vec::new()),
-    }));
-    #[allow(clippy::type_complexity)]
-    let log_updates: Arc<Mutex<RunLog<(NodePtr, Option<NodePtr>)>>> =
-        Arc::new(Mutex::new(RunLog {
-            log_entries: RefCell::new(Vec::new()),
-        }));
-
-    // clvm_rs uses boxed callbacks with unspecified lifetimes so in order to
-    // support logging as intended, we must have values that can be moved so
-    // the callbacks can become immortal.  Our strategy is to use channels
-    // and threads for this.
-    let (pre_eval_req_out, pre_eval_req_in) = channel();
-    let (pre_eval_resp_out, pre_eval_resp_in): (Sender<()>, Receiver<()>) = channel();
-
-    let (post_eval_req_out, post_eval_req_in) = channel();
-    let (post_eval_resp_out, post_eval_resp_in): (Sender<()>, Receiver<()>) = channel();
-
-    let post_eval_fn: Rc<dyn Fn(NodePtr, Option<NodePtr>)> = Rc::new(move |at, n| {
-        post_eval_req_out.send((at, n)).ok();
-        post_eval_resp_in.recv().unwrap();
-    });
-
-    #[allow(clippy::type_complexity)]
-    let pre_eval_fn: Rc<dyn Fn(&mut Allocator, NodePtr)> = Rc::new(move |_allocator, new_log| {
-        pre_eval_req_out.send(new_log).ok();
-        pre_eval_resp_in.recv().unwrap();
-    });
-
-    #[allow(clippy::type_complexity)]
-    let closure: Rc<dyn Fn(NodePtr) -> Box<dyn Fn(Option<NodePtr>)>> = Rc::new(move |v| {
-        let post_eval_fn_clone = post_eval_fn.clone();
-        Box::new(move |n| {
-            let post_eval_fn_clone_2 = post_eval_fn_clone.clone();
-            (*post_eval_fn_clone_2)(v, n)
-        })
-    });
-
-    if emit_symbol_output {
-        #[allow(clippy::type_complexity)]
-        let pre_eval_f_closure: Box<
-            dyn Fn(
-                &mut Allocator,
-                NodePtr,
-                NodePtr,
-            ) -> Result<Option<Box<(dyn Fn(Option<NodePtr>))>>, EvalErr>,
-        > = Box::new(move |allocator, sexp, args| {
-            let pre_eval_clone = pre_eval_fn.clone();
-            trace_pre_eval(
-                allocator,
-                &|allocator, n| (*pre_eval_clone)(allocator, n),
-                symbol_table_clone.clone(),
-                sexp,
-                args,
-            )
-            .map(|t| {
-                t.map(|log_ent| {
-                    let closure_clone = closure.clone();
-                    (*closure_clone)(log_ent)
-                })
-            })
-        });
-
-        pre_eval_f = Some(pre_eval_f_closure);
-    }
+    // Evaluations are recorded as the run goes and given to the trace sinks
+    // once it's over.
+    let recorder = Rc::new(RefCell::new(TraceRecorder::new(symbol_table_clone)));
+    let pre_eval_f: Option<PreEval> = if emit_symbol_output {
+        Some(TraceRecorder::pre_eval(recorder.clone()))
+    } else {
+        None
+    };
 
     let run_script = match parsed_args.get("stage") {
         Some(ArgumentValue::ArgInt(0)) => stages::brun(&mut allocator),
@@ -1076,6 +2494,11 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
         })
         .unwrap_or_else(|| 0);
     let max_cost = max(0, max_cost);
+    let max_cost: Option<Cost> = if max_cost == 0 {
+        None
+    } else {
+        Some(max_cost as Cost)
+    };
 
     if input_sexp.is_none() {
         input_sexp = sexp_from_stream(
@@ -1087,35 +2510,48 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
         .unwrap();
     };
 
-    // Part 2 of doing pre_eval: Have a thing that receives the messages and
-    // performs some action.
-    let log_entries_clone = log_entries.clone();
-    thread::spawn(move || {
-        let pre_in = pre_eval_req_in;
-        let pre_out = pre_eval_resp_out;
-
-        while let Ok(received) = pre_in.recv() {
-            {
-                let locked = log_entries_clone.lock();
-                locked.unwrap().push(received);
+    if let Some(function) = &function {
+        let composed = if let (Some(symbol_table), Some(input)) = (&symbol_table, input_sexp) {
+            compose_function_input(
+                &mut allocator,
+                &reported_input_file,
+                input,
+                symbol_table,
+                function,
+            )
+        } else {
+            Err("--function needs a --symbol-table".to_string())
+        };
+        match composed {
+            Ok(input) => {
+                input_sexp = Some(input);
+            }
+            Err(e) => {
+                stdout.write_str(&format!("FAIL: {e}\n"));
+                return;
             }
-            pre_out.send(()).ok();
         }
-    });
-
-    let log_updates_clone = log_updates.clone();
-    thread::spawn(move || {
-        let post_in = post_eval_req_in;
-        let post_out = post_eval_resp_out;
+    }
 
-        while let Ok(received) = post_in.recv() {
-            {
-                let locked = log_updates_clone.lock();
-                locked.unwrap().push(received);
-            }
-            post_out.send(()).ok();
+    let mocks = string_list_arg(&parsed_args, "mock");
+    if !mocks.is_empty() {
+        if let (Some(symbol_table), Some(input)) = (symbol_table.clone(), input_sexp) {
+            write_mocked_run(
+                &mut allocator,
+                stdout,
+                &reported_input_file,
+                input,
+                symbol_table,
+                &mocks,
+                max_cost,
+                parsed_args.contains_key("cost"),
+                keywords,
+            );
+        } else {
+            stdout.write_str("FAIL: --mock needs a --symbol-table\n");
         }
-    });
+        return;
+    }
 
     let time_parse_input = SystemTime::now();
     let res = run_program
@@ -1124,11 +2560,7 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
             run_script,
             input_sexp.unwrap(),
             Some(RunProgramOption {
-                max_cost: if max_cost == 0 {
-                    None
-                } else {
-                    Some(max_cost as u64)
-                },
+                max_cost,
                 pre_eval_f,
                 strict: parsed_args
                     .get("strict")
@@ -1195,6 +2627,7 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
             run_output
         });
 
+    let failed = res.is_err();
     let output = collapse(res.map_err(|ex| {
         format!(
             "FAIL: {} {}",
@@ -1210,13 +2643,68 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
 
     stdout.write_str(&format!("{output}\n"));
 
-    // Third part of our scheme: now that we have results from the forward pass
-    // and the pass doing the post callbacks, we can integrate them in the main
-    // thread.  We didn't do this in the callbacks because we didn't want to
-    // deal with a possibly escaping mutable allocator &.
-    let mut log_content = log_entries.lock().unwrap().finish();
-    let log_updates = log_updates.lock().unwrap().finish();
-    fix_log(&mut allocator, &mut log_content, &log_updates);
+    if let (true, Some(symbol_table), Some(input)) = (failed, symbol_table.clone(), input_sexp) {
+        write_backtrace(
+            &mut allocator,
+            stdout,
+            &reported_input_file,
+            input,
+            symbol_table,
+            max_cost,
+        );
+    }
+
+    if let (true, Some(input)) = (do_profile, input_sexp) {
+        let profile_output = parsed_args
+            .get("folded_output_file")
+            .and_then(|s| {
+                if let ArgumentValue::ArgString(_, v) = s {
+                    Some(v.clone())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|| "profile.folded".to_string());
+        write_profile(
+            &mut allocator,
+            stdout,
+            &reported_input_file,
+            input,
+            symbol_table.clone().unwrap_or_default(),
+            max_cost,
+            &profile_output,
+        );
+    }
+
+    if let (Some(coverage_output), Some(input)) = (coverage_output, input_sexp) {
+        let lcov_output = if let Some(ArgumentValue::ArgString(_, f)) = parsed_args.get("lcov") {
+            Some(f.clone())
+        } else {
+            None
+        };
+        write_coverage(
+            &mut allocator,
+            stdout,
+            &reported_input_file,
+            input,
+            symbol_table.clone().unwrap_or_default(),
+            max_cost,
+            &coverage_output,
+            lcov_output,
+        );
+    }
+
+    if let (Some(trace_json_output), Some(input)) = (trace_json_output, input_sexp) {
+        write_trace_json(
+            &mut allocator,
+            stdout,
+            &reported_input_file,
+            input,
+            symbol_table.clone().unwrap_or_default(),
+            max_cost,
+            &trace_json_output,
+        );
+    }
 
     let only_exn = parsed_args
         .get("only_exn")
@@ -1225,22 +2713,17 @@ pub fn launch_tool(stdout: &mut Stream, args: &[String], tool_name: &str, defaul
 
     if emit_symbol_output {
         stdout.write_str("\n");
-        trace_to_text(
+        let recorder_ref = RefCell::borrow(&recorder);
+        recorder_ref.replay(
             &mut allocator,
-            stdout,
             only_exn,
-            &log_content,
-            symbol_table.clone(),
-            &disassemble,
+            &mut TextTraceSink::new(stdout, &disassemble),
         );
         if parsed_args.get("table").is_some() {
-            trace_to_table(
+            recorder_ref.replay(
                 &mut allocator,
-                stdout,
                 only_exn,
-                &log_content,
-                symbol_table,
-                &disassemble,
+                &mut TableTraceSink::new(stdout, &disassemble),
             );
         }
     }
@@ -1,10 +1,16 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::Write;
 use std::rc::Rc;
 
 use clvm_rs::allocator::{Allocator, NodePtr, SExp};
+use clvm_rs::cost::Cost;
 use clvm_rs::reduction::EvalErr;
+use clvm_rs::run_program::PreEval;
+use serde::Serialize;
 
 use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType, Stream};
+use crate::classic::clvm::keyword_from_atom;
 use crate::classic::clvm::serialize::sexp_to_stream;
 use crate::classic::clvm::sexp::{enlist, proper_list, rest};
 
@@ -134,108 +140,340 @@ pub fn build_symbol_dump(
     enlist(allocator, &map_result)
 }
 
-fn text_trace(
+/// One evaluation recorded from a run: the form evaluated, the environment it
+/// was evaluated in, its result if it finished and the name of the form in the
+/// symbol table if there is one.
+///
+/// A classic run records forms as they're written, while trace_run records
+/// operators with their evaluated arguments, along with the cost of each.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub form: NodePtr,
+    pub env: NodePtr,
+    pub result: Option<NodePtr>,
+    pub symbol: Option<String>,
+    /// How deeply nested the evaluation is in the run, if known.
+    pub depth: Option<usize>,
+    /// What the evaluation cost, if known.
+    pub cost: Option<Cost>,
+}
+
+/// A destination for the records of a traced run, such as human readable text
+/// or json for other tools.
+pub trait TraceSink {
+    fn record(&mut self, allocator: &mut Allocator, record: &TraceRecord);
+}
+
+/// Discards the trace.
+pub struct NullTraceSink {}
+
+impl TraceSink for NullTraceSink {
+    fn record(&mut self, _allocator: &mut Allocator, _record: &TraceRecord) {}
+}
+
+fn result_text(
     allocator: &mut Allocator,
-    output: &mut Stream,
     disassemble_f: &dyn Fn(&mut Allocator, NodePtr) -> String,
-    form: NodePtr,
-    symbol: Option<String>,
-    env_: NodePtr,
-    result: &str,
-) {
-    let symbol_val;
-    let mut env = env_;
-    match symbol {
-        Some(sym) => {
-            env = rest(allocator, env).unwrap_or_else(|_| allocator.null());
-            let symbol_atom = allocator.new_atom(sym.as_bytes()).unwrap();
-            let symbol_list = allocator.new_pair(symbol_atom, env).unwrap();
-            symbol_val = disassemble_f(allocator, symbol_list);
+    record: &TraceRecord,
+) -> String {
+    record
+        .result
+        .map(|r| disassemble_f(allocator, r))
+        .unwrap_or_else(|| "(didn't finish)".to_string())
+}
+
+/// Writes each record as "form [env] => result", or as the call of the
+/// function when the form is a known one.
+pub struct TextTraceSink<'a> {
+    output: &'a mut Stream,
+    disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String,
+}
+
+impl<'a> TextTraceSink<'a> {
+    pub fn new(
+        output: &'a mut Stream,
+        disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String,
+    ) -> Self {
+        TextTraceSink {
+            output,
+            disassemble_f,
         }
-        _ => {
-            symbol_val = format!(
+    }
+}
+
+impl TraceSink for TextTraceSink<'_> {
+    fn record(&mut self, allocator: &mut Allocator, record: &TraceRecord) {
+        let result = result_text(allocator, self.disassemble_f, record);
+        let symbol_val = match &record.symbol {
+            Some(sym) => {
+                let env = rest(allocator, record.env).unwrap_or_else(|_| allocator.null());
+                let symbol_atom = allocator.new_atom(sym.as_bytes()).unwrap();
+                let symbol_list = allocator.new_pair(symbol_atom, env).unwrap();
+                (self.disassemble_f)(allocator, symbol_list)
+            }
+            _ => format!(
                 "{} [{}]",
-                disassemble_f(allocator, form),
-                disassemble_f(allocator, env)
-            );
+                (self.disassemble_f)(allocator, record.form),
+                (self.disassemble_f)(allocator, record.env)
+            ),
+        };
+
+        self.output
+            .write_str(&format!("{symbol_val} => {result}\n\n"));
+    }
+}
+
+/// Writes each record as a block of expression, arguments, environment and
+/// value, both disassembled and serialized.
+pub struct TableTraceSink<'a> {
+    output: &'a mut Stream,
+    disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String,
+}
+
+impl<'a> TableTraceSink<'a> {
+    pub fn new(
+        output: &'a mut Stream,
+        disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String,
+    ) -> Self {
+        TableTraceSink {
+            output,
+            disassemble_f,
         }
     }
+}
 
-    output.write_str(&format!("{symbol_val} => {result}\n\n"));
+impl TraceSink for TableTraceSink<'_> {
+    fn record(&mut self, allocator: &mut Allocator, record: &TraceRecord) {
+        let result = result_text(allocator, self.disassemble_f, record);
+        let (sexp, args) = match allocator.sexp(record.form) {
+            SExp::Pair(sexp, args) => (sexp, args),
+            SExp::Atom(_) => (record.form, allocator.null()),
+        };
+        let env = record.env;
+        let disassemble_f = self.disassemble_f;
+
+        let stdout = &mut self.output;
+        stdout.write_str(&format!("exp: {}\n", disassemble_f(allocator, sexp)));
+        stdout.write_str(&format!("arg: {}\n", disassemble_f(allocator, args)));
+        stdout.write_str(&format!("env: {}\n", disassemble_f(allocator, env)));
+        stdout.write_str(&format!("val: {result}\n"));
+        let mut sexp_stream = Stream::new(None);
+        sexp_to_stream(allocator, sexp, &mut sexp_stream);
+        let mut args_stream = Stream::new(None);
+        sexp_to_stream(allocator, args, &mut args_stream);
+        let mut benv_stream = Stream::new(None);
+        sexp_to_stream(allocator, env, &mut benv_stream);
+        stdout.write_str(&format!("bexp: {}\n", sexp_stream.get_value().hex()));
+        stdout.write_str(&format!("barg: {}\n", args_stream.get_value().hex()));
+        stdout.write_str(&format!("benv: {}\n", benv_stream.get_value().hex()));
+        stdout.write_str("--\n");
+    }
 }
 
-fn table_trace(
+#[derive(Serialize)]
+struct JsonTraceRecord {
+    operator: String,
+    args: Vec<String>,
+    result: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost: Option<Cost>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    depth: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function: Option<String>,
+}
+
+// The operator of a form and its arguments, or "path" and the path for an
+// environment lookup.  A quoted value has no arguments.
+fn operator_and_args(
     allocator: &mut Allocator,
-    stdout: &mut Stream,
     disassemble_f: &dyn Fn(&mut Allocator, NodePtr) -> String,
     form: NodePtr,
-    _symbol: Option<String>,
-    env: NodePtr,
-    result: &str,
-) {
-    let (sexp, args) = match allocator.sexp(form) {
-        SExp::Pair(sexp, args) => (sexp, args),
-        SExp::Atom(_) => (form, allocator.null()),
+) -> (String, Vec<String>) {
+    let (head, tail) = match allocator.sexp(form) {
+        SExp::Pair(head, tail) => (head, tail),
+        SExp::Atom(_) => {
+            return ("path".to_string(), vec![disassemble_f(allocator, form)]);
+        }
     };
+    let keyword = match allocator.sexp(head) {
+        SExp::Atom(b) => keyword_from_atom().get(allocator.buf(&b)).cloned(),
+        SExp::Pair(_, _) => None,
+    };
+    let operator = keyword.unwrap_or_else(|| disassemble_f(allocator, head));
+    if operator == "q" {
+        return (operator, vec![]);
+    }
+    let args = match proper_list(allocator, tail, true) {
+        Some(args) => args.iter().map(|a| disassemble_f(allocator, *a)).collect(),
+        None => vec![disassemble_f(allocator, tail)],
+    };
+    (operator, args)
+}
 
-    stdout.write_str(&format!("exp: {}\n", disassemble_f(allocator, sexp)));
-    stdout.write_str(&format!("arg: {}\n", disassemble_f(allocator, args)));
-    stdout.write_str(&format!("env: {}\n", disassemble_f(allocator, env)));
-    stdout.write_str(&format!("val: {result}\n"));
-    let mut sexp_stream = Stream::new(None);
-    sexp_to_stream(allocator, sexp, &mut sexp_stream);
-    let mut args_stream = Stream::new(None);
-    sexp_to_stream(allocator, args, &mut args_stream);
-    let mut benv_stream = Stream::new(None);
-    sexp_to_stream(allocator, env, &mut benv_stream);
-    stdout.write_str(&format!("bexp: {}\n", sexp_stream.get_value().hex()));
-    stdout.write_str(&format!("barg: {}\n", args_stream.get_value().hex()));
-    stdout.write_str(&format!("benv: {}\n", benv_stream.get_value().hex()));
-    stdout.write_str("--\n");
+/// Writes each record as a json object on its own line, giving its operator,
+/// arguments, result and, when known, its cost, depth and function.  Write
+/// errors are remembered rather than stopping the trace.
+pub struct JsonTraceSink<'a, W: Write> {
+    output: W,
+    disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String,
+    pub failed: bool,
 }
 
-type DisplayTraceFun = dyn Fn(
-    &mut Allocator,
-    &mut Stream,
-    &dyn Fn(&mut Allocator, NodePtr) -> String,
-    NodePtr,
-    Option<String>,
-    NodePtr,
-    &str,
-);
-
-fn display_trace(
+impl<'a, W: Write> JsonTraceSink<'a, W> {
+    pub fn new(output: W, disassemble_f: &'a dyn Fn(&mut Allocator, NodePtr) -> String) -> Self {
+        JsonTraceSink {
+            output,
+            disassemble_f,
+            failed: false,
+        }
+    }
+}
+
+impl<W: Write> TraceSink for JsonTraceSink<'_, W> {
+    fn record(&mut self, allocator: &mut Allocator, record: &TraceRecord) {
+        let (operator, args) = operator_and_args(allocator, self.disassemble_f, record.form);
+        let json_record = JsonTraceRecord {
+            operator,
+            args,
+            result: record.result.map(|r| (self.disassemble_f)(allocator, r)),
+            cost: record.cost,
+            depth: record.depth,
+            function: record.symbol.clone(),
+        };
+        let written = serde_json::to_string(&json_record)
+            .map_err(|_| ())
+            .and_then(|line| writeln!(self.output, "{line}").map_err(|_| ()));
+        if written.is_err() {
+            self.failed = true;
+        }
+    }
+}
+
+// Whether an evaluation of sexp is traced given the symbol table, which limits
+// the trace to the forms it names, and if so the form's name.
+fn traced_symbol(
     allocator: &mut Allocator,
-    stdout: &mut Stream,
-    only_exn: bool,
-    trace: &[NodePtr],
-    disassemble_f: &dyn Fn(&mut Allocator, NodePtr) -> String,
+    symbol_table: Option<&HashMap<String, String>>,
+    sexp: NodePtr,
+) -> Option<Option<String>> {
+    match symbol_table {
+        Some(st) => {
+            let h = sha256tree(allocator, sexp).hex();
+            st.get(&h).map(|s| Some(s.clone()))
+        }
+        None => Some(None),
+    }
+}
+
+/// Collects the evaluations of a classic run through the pre_eval hook of
+/// clvm_rs.  With a symbol table, only forms named in it are recorded.
+///
+/// The text and table traces list evaluations in the order they began, and
+/// an evaluation's result only arrives once everything it started has
+/// finished, so the records are kept until the run is over and then given to
+/// a TraceSink.  The hook also can't reach the allocator when a result
+/// arrives.  Runs that need their trace as they go can be stepped with
+/// trace_run instead.
+pub struct TraceRecorder {
     symbol_table: Option<HashMap<String, String>>,
-    display_fun: &DisplayTraceFun,
-) {
-    for item in trace {
-        let item_vec = proper_list(allocator, *item, true).unwrap();
-        let form = item_vec[0];
-        let env = item_vec[1];
-        let not_exn = item_vec.len() > 2;
-        let rv = if not_exn {
-            disassemble_f(allocator, item_vec[2])
-        } else {
-            "(didn't finish)".to_string()
-        };
+    records: Vec<TraceRecord>,
+    // The recorded evaluations that haven't finished.
+    open: usize,
+}
 
-        let h = sha256tree(allocator, form).hex();
-        let symbol = symbol_table
-            .as_ref()
-            .and_then(|st| st.get(&h).map(|x| x.to_string()));
+impl TraceRecorder {
+    pub fn new(symbol_table: Option<HashMap<String, String>>) -> Self {
+        TraceRecorder {
+            symbol_table,
+            records: Vec::new(),
+            open: 0,
+        }
+    }
 
-        let display = !only_exn || !not_exn;
-        if display {
-            display_fun(allocator, stdout, disassemble_f, form, symbol, env, &rv);
+    /// Make a recorder holding a log made by trace_pre_eval: lists of a form,
+    /// its environment and, if it finished, its result.
+    pub fn from_log(
+        allocator: &mut Allocator,
+        symbol_table: Option<HashMap<String, String>>,
+        trace: &[NodePtr],
+    ) -> Self {
+        let mut records = Vec::new();
+        for item in trace.iter() {
+            let item = proper_list(allocator, *item, true).unwrap_or_default();
+            if item.len() < 2 {
+                continue;
+            }
+            let symbol = symbol_table.as_ref().and_then(|st| {
+                let h = sha256tree(allocator, item[0]).hex();
+                st.get(&h).cloned()
+            });
+            records.push(TraceRecord {
+                form: item[0],
+                env: item[1],
+                result: item.get(2).copied(),
+                symbol,
+                depth: None,
+                cost: None,
+            });
+        }
+        TraceRecorder {
+            symbol_table,
+            records,
+            open: 0,
+        }
+    }
+
+    /// Make a pre_eval hook for RunProgramOption that records into recorder.
+    pub fn pre_eval(recorder: Rc<RefCell<TraceRecorder>>) -> PreEval {
+        Box::new(move |allocator, sexp, args| {
+            let symbol = if let Some(symbol) =
+                traced_symbol(allocator, recorder.borrow().symbol_table.as_ref(), sexp)
+            {
+                symbol
+            } else {
+                return Ok(None);
+            };
+
+            let index = {
+                let mut r = recorder.borrow_mut();
+                r.open += 1;
+                let depth = Some(r.open);
+                r.records.push(TraceRecord {
+                    form: sexp,
+                    env: args,
+                    result: None,
+                    symbol,
+                    depth,
+                    cost: None,
+                });
+                r.records.len() - 1
+            };
+            let post_recorder = recorder.clone();
+            Ok(Some(Box::new(move |result| {
+                let mut r = post_recorder.borrow_mut();
+                r.open -= 1;
+                r.records[index].result = result;
+            })))
+        })
+    }
+
+    pub fn records(&self) -> &[TraceRecord] {
+        &self.records
+    }
+
+    /// Give the records to sink, only the ones that didn't finish if only_exn
+    /// is set.
+    pub fn replay(&self, allocator: &mut Allocator, only_exn: bool, sink: &mut dyn TraceSink) {
+        for record in self.records.iter() {
+            if !only_exn || record.result.is_none() {
+                sink.record(allocator, record);
+            }
         }
     }
 }
 
+/// Write a log made by trace_pre_eval as text, as brun -v does.
 pub fn trace_to_text(
     allocator: &mut Allocator,
     stdout: &mut Stream,
@@ -244,17 +482,14 @@ pub fn trace_to_text(
     symbol_table: Option<HashMap<String, String>>,
     disassemble_f: &dyn Fn(&mut Allocator, NodePtr) -> String,
 ) {
-    display_trace(
+    TraceRecorder::from_log(allocator, symbol_table, trace).replay(
         allocator,
-        stdout,
         only_exn,
-        trace,
-        disassemble_f,
-        symbol_table,
-        &text_trace,
+        &mut TextTraceSink::new(stdout, disassemble_f),
     );
 }
 
+/// Write a log made by trace_pre_eval as a table, as brun -t does.
 pub fn trace_to_table(
     allocator: &mut Allocator,
     stdout: &mut Stream,
@@ -263,17 +498,16 @@ pub fn trace_to_table(
     symbol_table: Option<HashMap<String, String>>,
     disassemble_f: &dyn Fn(&mut Allocator, NodePtr) -> String,
 ) {
-    display_trace(
+    TraceRecorder::from_log(allocator, symbol_table, trace).replay(
         allocator,
-        stdout,
         only_exn,
-        trace,
-        disassemble_f,
-        symbol_table,
-        &table_trace,
+        &mut TableTraceSink::new(stdout, disassemble_f),
     );
 }
 
+/// A pre_eval hook body that appends a (form env) list to the log for each
+/// traced evaluation and gives it so that the result can be added to it.
+/// TraceRecorder::pre_eval makes a hook that records without a log.
 pub fn trace_pre_eval(
     allocator: &mut Allocator,
     append_log: &dyn Fn(&mut Allocator, NodePtr),
@@ -281,20 +515,13 @@ pub fn trace_pre_eval(
     sexp: NodePtr,
     args: NodePtr,
 ) -> Result<Option<NodePtr>, EvalErr> {
-    let h = sha256tree(allocator, sexp);
-    let recognized = symbol_table
-        .as_ref()
-        .and_then(|symbol_table| symbol_table.get(&h.hex()).map(|x| x.to_string()));
-
-    if recognized.is_none() && symbol_table.is_some() {
-        Ok(None)
-    } else {
-        m! {
-            log_entry <- enlist(allocator, &[sexp, args]);
-            let _ = append_log(allocator, log_entry);
-            Ok(Some(log_entry))
-        }
+    if traced_symbol(allocator, symbol_table.as_ref(), sexp).is_none() {
+        return Ok(None);
     }
+
+    let log_entry = enlist(allocator, &[sexp, args])?;
+    append_log(allocator, log_entry);
+    Ok(Some(log_entry))
 }
 
 pub fn check_unused(
@@ -59,6 +59,7 @@ pub fn compose_paths(path_0_: &Number, path_1_: &Number) -> Number {
     path_1 | (path_0 & mask)
 }
 
+#[derive(Clone, Debug)]
 pub struct NodePath {
     /*
      * Use 1-based paths
@@ -82,6 +83,10 @@ impl NodePath {
         }
     }
 
+    pub fn index(&self) -> Number {
+        self.index.clone()
+    }
+
     pub fn as_path(&self) -> Bytes {
         bigint_to_bytes_unsigned(&self.index)
     }
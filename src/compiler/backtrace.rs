@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+
+use crate::compiler::cldb::format_named_values;
+use crate::compiler::clvm::{check_max_cost, run_step, start_step, RunStep};
+use crate::compiler::debugger::CallStack;
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::SExp;
+use crate::compiler::srcloc::Srcloc;
+
+/// One function in a backtrace: the function, its arguments and where it was
+/// when the run stopped (or, for the functions calling it, where they called
+/// the next one in).
+#[derive(Clone, Debug)]
+pub struct BacktraceFrame {
+    pub name: String,
+    /// The function's hash in the symbol table, or None for the program.
+    pub hash: Option<String>,
+    /// The environment the function's body runs in.
+    pub env: Rc<SExp>,
+    /// The function's arguments by name, when the symbol table records them.
+    pub arguments: Vec<(String, Rc<SExp>)>,
+    pub loc: Option<Srcloc>,
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if self.arguments.is_empty() {
+            write!(f, "{} {}", self.name, self.env)?;
+        } else {
+            write!(
+                f,
+                "{} ({})",
+                self.name,
+                format_named_values(&self.arguments)
+            )?;
+        }
+        if let Some(l) = &self.loc {
+            write!(f, " at {l}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A failed run along with the chialisp functions it was inside of when it
+/// failed, innermost first and ending with the program itself.
+#[derive(Clone, Debug)]
+pub struct RunBacktrace {
+    pub failure: RunFailure,
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl Display for RunBacktrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(f, "{}", self.failure)?;
+        write!(f, "{}", format_backtrace(&self.frames))
+    }
+}
+
+/// A backtrace as text, one numbered frame per line under a heading.
+pub fn format_backtrace(frames: &[BacktraceFrame]) -> String {
+    let mut result = "backtrace:\n".to_string();
+    for (i, frame) in frames.iter().enumerate() {
+        result += &format!("  {i}: {frame}\n");
+    }
+    result
+}
+
+/// Run a program to completion with the stepping interpreter, giving its cost
+/// and result.  Given a symbol table, the functions named in it are tracked so
+/// that a failure can say which chialisp functions raised it, with their
+/// arguments and source locations; without one the failure has no frames.
+/// clvm::run and run_with_cost are this without a symbol table.
+#[allow(clippy::too_many_arguments)]
+pub fn run_with_backtrace(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    symbol_table: Option<HashMap<String, String>>,
+    program: Rc<SExp>,
+    env: Rc<SExp>,
+    iter_limit: Option<usize>,
+    max_cost: Option<Cost>,
+) -> Result<(Cost, Rc<SExp>), RunBacktrace> {
+    let mut call_stack = symbol_table.map(CallStack::new);
+    let mut step = start_step(program, env.clone());
+    let mut iters = 0;
+
+    loop {
+        if let Some(call_stack) = call_stack.as_mut() {
+            call_stack.observe(&step);
+        }
+        let next = if iter_limit.map(|limit| limit <= iters).unwrap_or(false) {
+            Err(RunFailure::RunErr(step.sexp().loc(), "timeout".to_string()))
+        } else {
+            run_step(allocator, runner.clone(), prim_map.clone(), &step)
+                .and_then(|s| check_max_cost(&s, max_cost).map(|_| s))
+        };
+        iters += 1;
+        step = match next {
+            Ok(s) => s,
+            Err(failure) => {
+                return Err(RunBacktrace {
+                    failure,
+                    frames: call_stack
+                        .map(|call_stack| call_stack.backtrace(env))
+                        .unwrap_or_default(),
+                });
+            }
+        };
+
+        if let RunStep::Done(_, x, c) = &step {
+            return Ok((*c, x.clone()));
+        }
+    }
+}
+
+/// Run a program to completion with the stepping interpreter, keeping track of
+/// the functions named in the symbol table so that a failure can say which
+/// chialisp functions raised it, with their arguments and source locations.
+pub fn backtrace_run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    symbol_table: HashMap<String, String>,
+    program: Rc<SExp>,
+    env: Rc<SExp>,
+    max_cost: Option<Cost>,
+) -> Result<Rc<SExp>, RunBacktrace> {
+    run_with_backtrace(
+        allocator,
+        runner,
+        prim_map,
+        Some(symbol_table),
+        program,
+        env,
+        None,
+        max_cost,
+    )
+    .map(|(_, x)| x)
+}
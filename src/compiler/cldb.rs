@@ -1,29 +1,52 @@
 use std::borrow::Borrow;
-use std::collections::{BTreeMap, HashMap};
-use std::mem::swap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem::{swap, take};
 use std::rc::Rc;
 
 use clvm_rs::allocator;
 use clvm_rs::allocator::{Allocator, NodePtr};
+use clvm_rs::cost::Cost;
 use clvm_rs::reduction::EvalErr;
 use num_bigint::ToBigInt;
 
 use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType, Stream};
 use crate::classic::clvm::serialize::{sexp_from_stream, SimpleCreateCLVMObject};
+use crate::classic::clvm_tools::binutils::assemble_from_ir;
+use crate::classic::clvm_tools::clvmc::{compile_clvm_text, detect_modern};
+use crate::classic::clvm_tools::ir::reader::read_ir;
+use crate::classic::clvm_tools::node_path::NodePath;
 use crate::classic::clvm_tools::sha256tree::sha256tree;
 use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
 
 use crate::compiler::clvm;
-use crate::compiler::clvm::{convert_from_clvm_rs, run_step, RunStep};
+use crate::compiler::clvm::{check_max_cost, convert_from_clvm_rs, run_step, RunStep};
+use crate::compiler::compiler::is_at_capture;
+use crate::compiler::comptypes::{BodyForm, CompileErr, CompilerOpts, HelperForm};
+use crate::compiler::debug::function_name_from_symbol_table;
+use crate::compiler::evaluate::{Evaluator, EVAL_STACK_LIMIT};
+use crate::compiler::frontend::{compile_bodyform, frontend};
 use crate::compiler::runtypes::RunFailure;
-use crate::compiler::sexp::SExp;
-use crate::compiler::srcloc::Srcloc;
+use crate::compiler::sexp::{decode_string, parse_sexp, SExp};
+use crate::compiler::srcloc::{parse_srcloc, Srcloc};
 use crate::util::Number;
 
+/// The row of a run that produced a value.
 #[derive(Clone, Debug)]
 pub struct PriorResult {
     reference: usize,
-    // value: Rc<SExp>, // In future, we'll want to know the value produced.
+    value: Rc<SExp>,
+}
+
+impl PriorResult {
+    /// The row number.
+    pub fn reference(&self) -> usize {
+        self.reference
+    }
+
+    pub fn value(&self) -> Rc<SExp> {
+        self.value.clone()
+    }
 }
 
 fn format_arg_inputs(args: &[PriorResult]) -> String {
@@ -54,6 +77,213 @@ fn get_arg_associations(
     }
 }
 
+/// Pair the names in a chialisp argument list with the parts of value they
+/// destructure, in order.  Parts of the list that value doesn't have are left
+/// out.
+pub fn bind_argument_names(
+    spec: Rc<SExp>,
+    value: Rc<SExp>,
+    bindings: &mut Vec<(String, Rc<SExp>)>,
+) {
+    match spec.borrow() {
+        SExp::Atom(_, name) => {
+            bindings.push((decode_string(name), value));
+        }
+        SExp::Cons(_, head, rest) => {
+            if let Some((capture, substructure)) = is_at_capture(head.clone(), rest.clone()) {
+                bindings.push((decode_string(&capture), value.clone()));
+                bind_argument_names(substructure, value, bindings);
+            } else if let SExp::Cons(_, first, others) = value.borrow() {
+                bind_argument_names(head.clone(), first.clone(), bindings);
+                bind_argument_names(rest.clone(), others.clone(), bindings);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Name the parts of an environment using the argument list recorded under
+/// arguments_key in a modern symbol table.  When left_env is set, the
+/// environment holds the program's functions on the left and the arguments on
+/// the right.
+pub fn named_env(
+    symbol_table: &HashMap<String, String>,
+    arguments_key: &str,
+    left_env: bool,
+    env: Rc<SExp>,
+) -> Vec<(String, Rc<SExp>)> {
+    let mut bindings = Vec::new();
+    let spec = if let Some(spec) = symbol_table
+        .get(arguments_key)
+        .and_then(|a| parse_sexp(Srcloc::start(arguments_key), a.bytes()).ok())
+        .and_then(|parsed| parsed.first().cloned())
+    {
+        spec
+    } else {
+        return bindings;
+    };
+
+    let args = if left_env {
+        if let SExp::Cons(_, _, args) = env.borrow() {
+            args.clone()
+        } else {
+            return bindings;
+        }
+    } else {
+        env
+    };
+    bind_argument_names(spec, args, &mut bindings);
+    bindings
+}
+
+/// The names of the functions a program's left environment holds, as found in
+/// a symbol table, in tree order.
+pub fn left_env_helpers(symbol_table: &HashMap<String, String>, env: Rc<SExp>) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut todo = vec![env];
+    while let Some(node) = todo.pop() {
+        let hash = Bytes::new(Some(BytesFromType::Raw(clvm::sha256tree(node.clone())))).hex();
+        if let Some(name) = function_name_from_symbol_table(symbol_table, &hash) {
+            names.push(name);
+        } else if let SExp::Cons(_, l, r) = node.borrow() {
+            todo.push(r.clone());
+            todo.push(l.clone());
+        }
+    }
+    names
+}
+
+/// Show named values as name = value pairs.
+pub fn format_named_values(bindings: &[(String, Rc<SExp>)]) -> String {
+    bindings
+        .iter()
+        .map(|(name, value)| format!("{name} = {value}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// The places where an environment differs from the one before it, as clvm
+/// paths (1 being the whole environment) with the old and new values there.
+/// Pairs are compared part by part, so a change deep inside a large
+/// environment is given where it happened.
+pub fn env_changes(old: Rc<SExp>, new: Rc<SExp>) -> Vec<(Number, Rc<SExp>, Rc<SExp>)> {
+    let mut changes = Vec::new();
+    collect_env_changes(NodePath::new(None), old, new, &mut changes);
+    changes
+}
+
+fn collect_env_changes(
+    path: NodePath,
+    old: Rc<SExp>,
+    new: Rc<SExp>,
+    changes: &mut Vec<(Number, Rc<SExp>, Rc<SExp>)>,
+) {
+    if clvm::sha256tree(old.clone()) == clvm::sha256tree(new.clone()) {
+        return;
+    }
+
+    if let (SExp::Cons(_, old_first, old_rest), SExp::Cons(_, new_first, new_rest)) =
+        (old.borrow(), new.borrow())
+    {
+        let first = path.add(NodePath::new(2_i32.to_bigint()));
+        let rest = path.add(NodePath::new(3_i32.to_bigint()));
+        collect_env_changes(first, old_first.clone(), new_first.clone(), changes);
+        collect_env_changes(rest, old_rest.clone(), new_rest.clone(), changes);
+    } else {
+        changes.push((path.index(), old, new));
+    }
+}
+
+/// Show the changes between two environments as path: old -> new entries.
+pub fn format_env_changes(changes: &[(Number, Rc<SExp>, Rc<SExp>)]) -> String {
+    if changes.is_empty() {
+        return "unchanged".to_string();
+    }
+    changes
+        .iter()
+        .map(|(path, old, new)| format!("{path}: {old} -> {new}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// An environment with the pairs it shares with the one before it folded
+/// behind their tree hash, written #hash, so only what changed is spelled out.
+pub fn fold_unchanged(old: Rc<SExp>, new: Rc<SExp>) -> Rc<SExp> {
+    let hash = clvm::sha256tree(new.clone());
+    match (old.borrow(), new.borrow()) {
+        (_, SExp::Cons(l, _, _)) if clvm::sha256tree(old.clone()) == hash => {
+            let hex = Bytes::new(Some(BytesFromType::Raw(hash))).hex();
+            Rc::new(SExp::Atom(l.clone(), format!("#{hex}").into_bytes()))
+        }
+        (SExp::Cons(_, old_first, old_rest), SExp::Cons(l, new_first, new_rest)) => {
+            Rc::new(SExp::Cons(
+                l.clone(),
+                fold_unchanged(old_first.clone(), new_first.clone()),
+                fold_unchanged(old_rest.clone(), new_rest.clone()),
+            ))
+        }
+        _ => new,
+    }
+}
+
+/// The helpers (functions, macros and constants) defined in a chialisp
+/// program's source.
+pub fn program_helpers(
+    opts: Rc<dyn CompilerOpts>,
+    source: &str,
+) -> Result<Vec<HelperForm>, CompileErr> {
+    let parsed = parse_sexp(Srcloc::start(&opts.filename()), source.bytes())
+        .map_err(|e| CompileErr(e.0, e.1))?;
+    Ok(frontend(opts, &parsed)?.helpers)
+}
+
+/// Compile a single chialisp expression given by a user to evaluate during a
+/// run.
+pub fn compile_expression(opts: Rc<dyn CompilerOpts>, expr: &str) -> Result<Rc<BodyForm>, String> {
+    let parsed = parse_sexp(Srcloc::start("*expression*"), expr.bytes())
+        .map_err(|e| format!("{}: {}", e.0, e.1))?;
+    if parsed.len() != 1 {
+        return Err(format!("expected one expression in {expr}"));
+    }
+    compile_bodyform(opts, parsed[0].clone())
+        .map(Rc::new)
+        .map_err(|e| format!("{}: {}", e.0, e.1))
+}
+
+/// Evaluate a compiled expression with names bound to values, giving its value
+/// when the evaluator can reduce it to a constant.
+pub fn evaluate_with_bindings(
+    allocator: &mut Allocator,
+    evaluator: &Evaluator,
+    bindings: &[(String, Rc<SExp>)],
+    body: Rc<BodyForm>,
+) -> Result<Rc<SExp>, String> {
+    let env: HashMap<Vec<u8>, Rc<BodyForm>> = bindings
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.as_bytes().to_vec(),
+                Rc::new(BodyForm::Quoted(value.as_ref().clone())),
+            )
+        })
+        .collect();
+    let result = evaluator
+        .shrink_bodyform(
+            allocator,
+            Rc::new(SExp::Nil(body.loc())),
+            &env,
+            body,
+            false,
+            Some(EVAL_STACK_LIMIT),
+        )
+        .map_err(|e| format!("{}: {}", e.0, e.1))?;
+    if let BodyForm::Quoted(value) = result.borrow() {
+        Ok(Rc::new(value.clone()))
+    } else {
+        Err(format!("no value for {} here", result.to_sexp()))
+    }
+}
+
 /// An interface which allows consumers to inject their own functionality into
 /// cldb runs, including possibly mocking functions, performing tracing and
 /// other desired things.  The result of the operation can be dictated when
@@ -74,6 +304,28 @@ pub trait CldbEnvironment {
     );
     fn add_function(&self, s: &SExp, context_result: &mut BTreeMap<String, String>);
     fn get_override(&self, s: &RunStep) -> Option<Result<RunStep, RunFailure>>;
+    /// The environment of the last apply, which the next one is compared
+    /// with when showing environment changes.  CldbRun keeps it with each
+    /// step so that it can be put back when the step is taken back.
+    fn previous_env(&self) -> Option<Rc<SExp>> {
+        None
+    }
+    fn set_previous_env(&self, _env: Option<Rc<SExp>>) {}
+}
+
+// What's needed to put a CldbRun back as it was before a step.
+struct CldbRunSnapshot {
+    step: RunStep,
+    ended: bool,
+    final_result: Option<Rc<SExp>>,
+    to_print: BTreeMap<String, String>,
+    in_expr: bool,
+    row: usize,
+    reported_cost: Cost,
+    pending_refs: Vec<PriorResult>,
+    previous_env: Option<Rc<SExp>>,
+    // The association the step replaced, if it recorded one.
+    replaced_output: Option<(Number, Option<PriorResult>)>,
 }
 
 /// CldbRun is the main object used to run CLVM code in a stepwise way.  The main
@@ -87,7 +339,13 @@ pub trait CldbEnvironment {
 /// to ask whether the run ended and what the final result was (if it completed).
 ///
 /// The result is a map of key value pairs indicating various information about
-/// the run.
+/// the run.  Each reported row includes the cost spent since the previous row
+/// and the total cost of the run so far.
+///
+/// When asked to keep its history, every step can be taken back with
+/// step_back, and the rows produced so far are kept so that the origin of a
+/// value can be traced through the operations that computed it.  Otherwise
+/// nothing is kept from one step to the next, so a long run can be streamed.
 pub struct CldbRun {
     runner: Rc<dyn TRunProgram>,
     prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
@@ -100,8 +358,16 @@ pub struct CldbRun {
     to_print: BTreeMap<String, String>,
     in_expr: bool,
     row: usize,
+    max_cost: Option<Cost>,
+    reported_cost: Cost,
 
     outputs_to_step: HashMap<Number, PriorResult>,
+    pending_refs: Vec<PriorResult>,
+
+    rows: Vec<BTreeMap<String, String>>,
+    row_refs: Vec<Vec<PriorResult>>,
+    history: Vec<CldbRunSnapshot>,
+    keep_history: bool,
 }
 
 impl CldbRun {
@@ -125,8 +391,120 @@ impl CldbRun {
             to_print: BTreeMap::new(),
             in_expr: false,
             row: 0,
+            max_cost: None,
+            reported_cost: 0,
             outputs_to_step: HashMap::<Number, PriorResult>::new(),
+            pending_refs: Vec::new(),
+            rows: Vec::new(),
+            row_refs: Vec::new(),
+            history: Vec::new(),
+            keep_history: false,
+        }
+    }
+
+    /// Keep a snapshot of each step and the rows produced, so that steps can
+    /// be taken back and value_origin can find where values came from.
+    pub fn set_keep_history(&mut self, keep_history: bool) {
+        self.keep_history = keep_history;
+    }
+
+    /// Set a cost limit.  When a step goes over it, the run ends with a
+    /// failure as clvmr would.
+    pub fn set_max_cost(&mut self, max_cost: Option<Cost>) {
+        self.max_cost = max_cost;
+    }
+
+    /// The total cost of the run so far.
+    pub fn cost(&self) -> Cost {
+        self.step.cost()
+    }
+
+    fn add_cost(&mut self, cost: Cost) {
+        self.to_print.insert(
+            "Cost".to_string(),
+            cost.saturating_sub(self.reported_cost).to_string(),
+        );
+        self.to_print
+            .insert("Total-Cost".to_string(), cost.to_string());
+        self.reported_cost = cost;
+    }
+
+    /// The number of steps that can be taken back.
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// The number of rows produced so far.
+    pub fn row(&self) -> usize {
+        self.row
+    }
+
+    /// The rows produced so far, indexed by their Row, if history is kept.
+    pub fn rows(&self) -> &[BTreeMap<String, String>] {
+        &self.rows
+    }
+
+    /// The step that will be run next.
+    pub fn current_step(&self) -> &RunStep {
+        &self.step
+    }
+
+    /// Put the run back as it was before the last step, telling whether there
+    /// was a step to take back.  There's none unless history is kept.
+    pub fn step_back(&mut self) -> bool {
+        let snapshot = if let Some(snapshot) = self.history.pop() {
+            snapshot
+        } else {
+            return false;
+        };
+
+        if let Some((n, replaced)) = snapshot.replaced_output {
+            if let Some(prior) = replaced {
+                self.outputs_to_step.insert(n, prior);
+            } else {
+                self.outputs_to_step.remove(&n);
+            }
         }
+        self.step = snapshot.step;
+        self.ended = snapshot.ended;
+        self.final_result = snapshot.final_result;
+        self.to_print = snapshot.to_print;
+        self.in_expr = snapshot.in_expr;
+        self.row = snapshot.row;
+        self.reported_cost = snapshot.reported_cost;
+        self.pending_refs = snapshot.pending_refs;
+        self.env.set_previous_env(snapshot.previous_env);
+        self.rows.truncate(self.row);
+        self.row_refs.truncate(self.row);
+        true
+    }
+
+    /// Where a value came from: the row that most recently produced it,
+    /// followed by the rows that produced its arguments and so on.  Only atoms
+    /// are tracked, and only rows kept with the history are given.
+    pub fn value_origin(&self, value: &Number) -> Vec<BTreeMap<String, String>> {
+        let mut result = Vec::new();
+        let mut seen = HashSet::new();
+        let mut to_visit: Vec<usize> = self
+            .outputs_to_step
+            .get(value)
+            .map(|p| vec![p.reference])
+            .unwrap_or_default();
+        while !to_visit.is_empty() {
+            let row = to_visit.remove(0);
+            if !seen.insert(row) || row >= self.rows.len() {
+                continue;
+            }
+
+            let mut origin = self.rows[row].clone();
+            let refs = &self.row_refs[row];
+            if !refs.is_empty() {
+                origin.insert("Argument-Refs".to_string(), format_arg_inputs(refs));
+            }
+            result.push(origin);
+            to_visit.extend(refs.iter().map(|r| r.reference));
+        }
+        result
     }
 
     pub fn is_ended(&self) -> bool {
@@ -140,6 +518,20 @@ impl CldbRun {
     pub fn step(&mut self, allocator: &mut Allocator) -> Option<BTreeMap<String, String>> {
         let mut produce_result = false;
         let mut result = BTreeMap::new();
+        if self.keep_history {
+            self.history.push(CldbRunSnapshot {
+                step: self.step.clone(),
+                ended: self.ended,
+                final_result: self.final_result.clone(),
+                to_print: self.to_print.clone(),
+                in_expr: self.in_expr,
+                row: self.row,
+                reported_cost: self.reported_cost,
+                pending_refs: self.pending_refs.clone(),
+                previous_env: self.env.previous_env(),
+                replaced_output: None,
+            });
+        }
         let new_step = match self.env.get_override(&self.step) {
             Some(v) => v,
             _ => run_step(
@@ -148,33 +540,42 @@ impl CldbRun {
                 self.prim_map.clone(),
                 &self.step,
             ),
-        };
+        }
+        .and_then(|new_step| {
+            check_max_cost(&new_step, self.max_cost)?;
+            Ok(new_step)
+        });
 
         // Allow overrides by consumers.
 
         match &new_step {
-            Ok(RunStep::OpResult(l, x, _p)) => {
+            Ok(RunStep::OpResult(l, x, c, _p, _)) => {
                 if self.in_expr {
+                    self.add_cost(*c);
                     self.to_print
                         .insert("Result-Location".to_string(), l.to_string());
                     self.to_print.insert("Value".to_string(), x.to_string());
                     self.to_print
                         .insert("Row".to_string(), self.row.to_string());
                     if let Ok(n) = x.get_number() {
-                        self.outputs_to_step.insert(
-                            n,
+                        let replaced = self.outputs_to_step.insert(
+                            n.clone(),
                             PriorResult {
                                 reference: self.row,
-                                // value: x.clone(), // for future
+                                value: x.clone(),
                             },
                         );
+                        if let Some(snapshot) = self.history.last_mut() {
+                            snapshot.replaced_output = Some((n, replaced));
+                        }
                     }
                     self.in_expr = false;
                     swap(&mut self.to_print, &mut result);
                     produce_result = true;
                 }
             }
-            Ok(RunStep::Done(l, x)) => {
+            Ok(RunStep::Done(l, x, c)) => {
+                self.add_cost(*c);
                 self.to_print
                     .insert("Final-Location".to_string(), l.to_string());
                 self.to_print.insert("Final".to_string(), x.to_string());
@@ -184,21 +585,21 @@ impl CldbRun {
                 swap(&mut self.to_print, &mut result);
                 produce_result = true;
             }
-            Ok(RunStep::Step(_sexp, _c, _p)) => {}
-            Ok(RunStep::Op(sexp, c, a, None, _p)) => {
+            Ok(RunStep::Step(_sexp, _c, _cost, _p, _)) => {}
+            Ok(RunStep::Op(sexp, c, a, None, _cost, _p, _)) => {
                 self.to_print
                     .insert("Operator-Location".to_string(), a.loc().to_string());
                 self.to_print
                     .insert("Operator".to_string(), sexp.to_string());
+                let arg_associations = get_arg_associations(&self.outputs_to_step, a.clone());
                 if let Ok(v) = sexp.get_number() {
                     if v == 11_u32.to_bigint().unwrap() {
                         // Build source tree for hashes.
-                        let arg_associations =
-                            get_arg_associations(&self.outputs_to_step, a.clone());
                         let args = format_arg_inputs(&arg_associations);
                         self.to_print.insert("Argument-Refs".to_string(), args);
                     }
                 }
+                self.pending_refs = arg_associations;
                 self.env.add_context(
                     sexp.borrow(),
                     c.borrow(),
@@ -208,8 +609,9 @@ impl CldbRun {
                 self.env.add_function(sexp, &mut self.to_print);
                 self.in_expr = true;
             }
-            Ok(RunStep::Op(_sexp, _c, _a, Some(_v), _p)) => {}
+            Ok(RunStep::Op(_sexp, _c, _a, Some(_v), _cost, _p, _)) => {}
             Err(RunFailure::RunExn(l, s)) => {
+                self.add_cost(self.step.cost());
                 self.to_print
                     .insert("Throw-Location".to_string(), l.to_string());
                 self.to_print.insert("Throw".to_string(), s.to_string());
@@ -219,6 +621,7 @@ impl CldbRun {
                 produce_result = true;
             }
             Err(RunFailure::RunErr(l, s)) => {
+                self.add_cost(self.step.cost());
                 self.to_print
                     .insert("Failure-Location".to_string(), l.to_string());
                 self.to_print.insert("Failure".to_string(), s.to_string());
@@ -232,6 +635,11 @@ impl CldbRun {
         self.step = new_step.unwrap_or_else(|_| self.step.clone());
 
         if produce_result {
+            let refs = take(&mut self.pending_refs);
+            if self.keep_history {
+                self.rows.push(result.clone());
+                self.row_refs.push(refs);
+            }
             self.row += 1;
             Some(result)
         } else {
@@ -304,6 +712,7 @@ impl CldbOverrideBespokeCode {
         _c: Rc<SExp>,
         f: Rc<SExp>,
         args: Rc<SExp>,
+        cost: Cost,
         p: Rc<RunStep>,
     ) -> Option<Result<RunStep, RunFailure>> {
         let fun_hash = clvm::sha256tree(f);
@@ -313,9 +722,9 @@ impl CldbOverrideBespokeCode {
             .get(&fun_hash_str)
             .and_then(|funname| self.overrides.get(funname))
             .map(|override_fn| {
-                override_fn
-                    .get_override(args.clone())
-                    .map(|new_exp| RunStep::OpResult(sexp.loc(), new_exp, p.clone()))
+                override_fn.get_override(args.clone()).map(|new_exp| {
+                    RunStep::OpResult(sexp.loc(), new_exp, cost, p.clone(), p.depth() + 1)
+                })
             })
     }
 }
@@ -323,7 +732,7 @@ impl CldbOverrideBespokeCode {
 impl CldbRunnable for CldbOverrideBespokeCode {
     fn replace_step(&self, step: &RunStep) -> Option<Result<RunStep, RunFailure>> {
         match step {
-            RunStep::Op(sexp, context, arguments, None, parent) => match sexp.borrow() {
+            RunStep::Op(sexp, context, arguments, None, cost, parent, _) => match sexp.borrow() {
                 SExp::Integer(_, i) => {
                     if *i == 2_u32.to_bigint().unwrap() {
                         match arguments.borrow() {
@@ -333,6 +742,7 @@ impl CldbRunnable for CldbOverrideBespokeCode {
                                     context.clone(),
                                     first.clone(),
                                     args.clone(),
+                                    *cost,
                                     parent.clone(),
                                 ),
                             _ => None,
@@ -348,6 +758,100 @@ impl CldbRunnable for CldbOverrideBespokeCode {
     }
 }
 
+/// A CldbSingleBespokeOverride which replaces calls to a function with the
+/// value of a chialisp expression of the call's arguments, so a program can be
+/// run without some of its dependencies.
+///
+/// A mocked call costs nothing: the run's cost is that of the rest of the
+/// program, as the stepping interpreter counts it.
+pub struct CldbMockFunction {
+    name: String,
+    evaluator: Rc<Evaluator>,
+    arguments: Rc<SExp>,
+    left_env: bool,
+    body: Rc<BodyForm>,
+    allocator: RefCell<Allocator>,
+}
+
+impl CldbMockFunction {
+    /// Mock the function called name in a modern symbol table with expr, which
+    /// can use the function's arguments by name along with the helpers the
+    /// evaluator has.
+    pub fn new(
+        opts: Rc<dyn CompilerOpts>,
+        evaluator: Rc<Evaluator>,
+        symbol_table: &HashMap<String, String>,
+        name: &str,
+        expr: &str,
+    ) -> Result<Self, String> {
+        let hash = symbol_table
+            .iter()
+            .find(|(hash, v)| {
+                *v == name && function_name_from_symbol_table(symbol_table, hash).is_some()
+            })
+            .map(|(hash, _)| hash.clone())
+            .ok_or_else(|| format!("no function named {name} in the symbol table"))?;
+        let arguments_key = format!("{hash}_arguments");
+        let arguments = symbol_table
+            .get(&arguments_key)
+            .and_then(|a| parse_sexp(Srcloc::start(&arguments_key), a.bytes()).ok())
+            .and_then(|parsed| parsed.first().cloned())
+            .ok_or_else(|| format!("the symbol table has no arguments for {name}"))?;
+        Ok(CldbMockFunction {
+            name: name.to_string(),
+            evaluator,
+            arguments,
+            left_env: symbol_table.contains_key(&format!("{hash}_left_env")),
+            body: compile_expression(opts, expr)?,
+            allocator: RefCell::new(Allocator::new()),
+        })
+    }
+
+    /// Read a mock given as name=expr.
+    pub fn from_spec(
+        opts: Rc<dyn CompilerOpts>,
+        evaluator: Rc<Evaluator>,
+        symbol_table: &HashMap<String, String>,
+        spec: &str,
+    ) -> Result<Self, String> {
+        let (name, expr) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("mock {spec} should be given as name=expression"))?;
+        CldbMockFunction::new(opts, evaluator, symbol_table, name.trim(), expr)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl CldbSingleBespokeOverride for CldbMockFunction {
+    fn get_override(&self, env: Rc<SExp>) -> Result<Rc<SExp>, RunFailure> {
+        // The override is given the rest of the apply operator's arguments,
+        // whose first is the environment the function would run in.
+        let mut function_env = if let SExp::Cons(_, first, _) = env.borrow() {
+            first.clone()
+        } else {
+            env.clone()
+        };
+        if self.left_env {
+            if let SExp::Cons(_, _, args) = function_env.borrow() {
+                function_env = args.clone();
+            }
+        }
+
+        let mut bindings = Vec::new();
+        bind_argument_names(self.arguments.clone(), function_env, &mut bindings);
+        evaluate_with_bindings(
+            &mut self.allocator.borrow_mut(),
+            &self.evaluator,
+            &bindings,
+            self.body.clone(),
+        )
+        .map_err(|e| RunFailure::RunErr(env.loc(), format!("mock of {}: {e}", self.name)))
+    }
+}
+
 /// A small collection of information about the running program, including the
 /// name of the source file and the lines of the program.  When present, this
 /// allows names to be picked out of the source base and locations to be accurate.
@@ -357,6 +861,11 @@ pub struct CldbRunEnv {
     input_file: Option<String>,
     program_lines: Vec<String>,
     overrides: Box<dyn CldbRunnable>,
+    symbol_table: HashMap<String, String>,
+    // When set, applies show how the environment changed since the last one,
+    // with unchanged pairs folded if the flag is true.
+    env_diff: Option<bool>,
+    previous_env: RefCell<Option<Rc<SExp>>>,
 }
 
 impl CldbRunEnv {
@@ -371,6 +880,92 @@ impl CldbRunEnv {
             input_file,
             program_lines,
             overrides: runnable,
+            symbol_table: HashMap::new(),
+            env_diff: None,
+            previous_env: RefCell::new(None),
+        }
+    }
+
+    /// Show each apply's environment as its changes from the environment of
+    /// the apply before it (as Env-Diff) rather than in full.  With
+    /// fold_unchanged, the environment is also shown with the pairs it shares
+    /// with the one before folded behind their tree hash.
+    pub fn set_env_diff(&mut self, fold_unchanged: bool) {
+        self.env_diff = Some(fold_unchanged);
+    }
+
+    /// Use a symbol table from the modern compiler to name the functions
+    /// applied in the run and the arguments they're given.
+    pub fn set_symbol_table(&mut self, symbol_table: HashMap<String, String>) {
+        self.symbol_table = symbol_table;
+    }
+
+    // Describe the environment an apply runs its program in.
+    fn add_env(&self, env: &SExp, context_result: &mut BTreeMap<String, String>) {
+        let env = if let Some(fold) = self.env_diff {
+            let env = Rc::new(env.clone());
+            let previous = self
+                .previous_env
+                .replace(Some(env.clone()))
+                .unwrap_or_else(|| Rc::new(SExp::Nil(env.loc())));
+            let changes = env_changes(previous.clone(), env.clone());
+            context_result.insert("Env-Diff".to_string(), format_env_changes(&changes));
+            if !fold {
+                return;
+            }
+            fold_unchanged(previous, env)
+        } else {
+            Rc::new(env.clone())
+        };
+
+        match env.borrow() {
+            SExp::Cons(_, a, b) => {
+                context_result.insert("Env".to_string(), a.to_string());
+                context_result.insert("Env-Args".to_string(), b.to_string());
+            }
+            _ => {
+                context_result.insert("Function-Context".to_string(), env.to_string());
+            }
+        }
+    }
+
+    // Describe the application of a function the symbol table knows, given
+    // the arguments of the apply operator.
+    fn add_applied_function(&self, args: Rc<SExp>, context_result: &mut BTreeMap<String, String>) {
+        let (program, env) = if let SExp::Cons(_, program, rest) = args.borrow() {
+            if let SExp::Cons(_, env, _) = rest.borrow() {
+                (program.clone(), env.clone())
+            } else {
+                return;
+            }
+        } else {
+            return;
+        };
+
+        let hash = Bytes::new(Some(BytesFromType::Raw(clvm::sha256tree(program)))).hex();
+        let name = if let Some(name) = function_name_from_symbol_table(&self.symbol_table, &hash) {
+            name
+        } else {
+            return;
+        };
+        context_result.insert("Function".to_string(), name);
+
+        let left_env = self.symbol_table.contains_key(&format!("{hash}_left_env"));
+        let arguments = named_env(
+            &self.symbol_table,
+            &format!("{hash}_arguments"),
+            left_env,
+            env.clone(),
+        );
+        if !arguments.is_empty() {
+            context_result.insert(
+                "Function-Arguments".to_string(),
+                format_named_values(&arguments),
+            );
+        }
+        if let (true, SExp::Cons(_, left, _)) = (left_env, env.borrow()) {
+            let helpers = left_env_helpers(&self.symbol_table, left.clone());
+            context_result.insert("Left-Env".to_string(), helpers.join(" "));
         }
     }
 
@@ -432,13 +1027,10 @@ impl CldbEnvironment for CldbRunEnv {
         self.whether_is_apply(
             s,
             context_result,
-            &|context_result| match c {
-                SExp::Cons(_, a, b) => {
-                    context_result.insert("Env".to_string(), a.to_string());
-                    context_result.insert("Env-Args".to_string(), b.to_string());
-                }
-                _ => {
-                    context_result.insert("Function-Context".to_string(), c.to_string());
+            &|context_result| {
+                self.add_env(c, context_result);
+                if let Some(a) = &args {
+                    self.add_applied_function(a.clone(), context_result);
                 }
             },
             &|context_result| {
@@ -467,6 +1059,14 @@ impl CldbEnvironment for CldbRunEnv {
     fn get_override(&self, s: &RunStep) -> Option<Result<RunStep, RunFailure>> {
         self.overrides.replace_step(s)
     }
+
+    fn previous_env(&self) -> Option<Rc<SExp>> {
+        self.previous_env.borrow().clone()
+    }
+
+    fn set_previous_env(&self, env: Option<Rc<SExp>>) {
+        self.previous_env.replace(env);
+    }
 }
 
 fn hex_to_modern_sexp_inner(
@@ -479,7 +1079,7 @@ fn hex_to_modern_sexp_inner(
     let hash_str = hash.hex();
     let srcloc = symbol_table
         .get(&hash_str)
-        .map(|f| Srcloc::start(f))
+        .map(|f| parse_srcloc(f).unwrap_or_else(|| Srcloc::start(f)))
         .unwrap_or_else(|| loc.clone());
 
     match allocator.sexp(program) {
@@ -497,6 +1097,105 @@ fn hex_to_modern_sexp_inner(
     }
 }
 
+// Where an expression is, as the modern compiler places code: at the
+// operator of a form.
+fn operator_loc(sexp: &SExp) -> Srcloc {
+    sexp.proper_list()
+        .and_then(|l| l.first().map(|head| head.loc()))
+        .unwrap_or_else(|| sexp.loc())
+}
+
+fn is_atom_named(sexp: Option<&SExp>, name: &[u8]) -> bool {
+    matches!(sexp, Some(SExp::Atom(_, a)) if a == name)
+}
+
+/// Whether chialisp source is a classic (mod ...) program, one which doesn't
+/// include a modern dialect.
+pub fn is_classic_mod(allocator: &mut Allocator, source: &str) -> bool {
+    let is_mod = parse_sexp(Srcloc::start("*classic*"), source.bytes())
+        .ok()
+        .and_then(|parsed| parsed.first().and_then(|p| p.proper_list()))
+        .map(|l| is_atom_named(l.first(), b"mod"))
+        .unwrap_or(false);
+    if !is_mod {
+        return false;
+    }
+
+    read_ir(source)
+        .ok()
+        .and_then(|ir| assemble_from_ir(allocator, Rc::new(ir)).ok())
+        .map(|program| detect_modern(allocator, program).is_none())
+        .unwrap_or(false)
+}
+
+/// Compile a classic (mod ...) program with the classic compiler for stepping.
+///
+/// The classic compiler's symbols only name functions, so the argument lists
+/// of the source's defuns are added to symbol_table as the modern compiler
+/// records them, and each part of the compiled program is given the location
+/// of the body of the defun it comes from (or of the program's main expression)
+/// since classic compilation keeps no finer locations.
+pub fn compile_classic_program(
+    allocator: &mut Allocator,
+    search_paths: &[String],
+    filename: &str,
+    source: &str,
+    symbol_table: &mut HashMap<String, String>,
+) -> Result<Rc<SExp>, CompileErr> {
+    let start = Srcloc::start(filename);
+    let program = compile_clvm_text(allocator, search_paths, symbol_table, source, filename)
+        .map_err(|e| CompileErr(start.clone(), e.1))?;
+
+    let parsed = parse_sexp(start.clone(), source.bytes()).map_err(|e| CompileErr(e.0, e.1))?;
+    let mod_form = parsed
+        .first()
+        .and_then(|p| p.proper_list())
+        .unwrap_or_default();
+    let mut main_loc = start;
+    let mut defuns = HashMap::new();
+    if mod_form.len() > 2 {
+        symbol_table.insert(
+            "__chia__main_arguments".to_string(),
+            mod_form[1].to_string(),
+        );
+        main_loc = operator_loc(&mod_form[mod_form.len() - 1]);
+        for form in mod_form[2..mod_form.len() - 1].iter() {
+            if let Some(defun) = form.proper_list() {
+                if let (true, Some(SExp::Atom(_, name))) =
+                    (is_atom_named(defun.first(), b"defun"), defun.get(1))
+                {
+                    if let (Some(arguments), Some(body)) = (defun.get(2), defun.get(3)) {
+                        defuns.insert(
+                            decode_string(name),
+                            (arguments.to_string(), operator_loc(body)),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    let functions: Vec<(String, String)> = symbol_table
+        .iter()
+        .map(|(hash, name)| (hash.clone(), name.clone()))
+        .collect();
+    let mut locations = HashMap::new();
+    for (hash, name) in functions.iter() {
+        if let Some((arguments, loc)) = defuns.get(name) {
+            symbol_table.insert(format!("{hash}_arguments"), arguments.clone());
+            symbol_table.insert(format!("{hash}_left_env"), "1".to_string());
+            locations.insert(hash.clone(), loc.to_string());
+        }
+    }
+
+    hex_to_modern_sexp_inner(allocator, &locations, main_loc.clone(), program).map_err(|_| {
+        CompileErr(
+            main_loc,
+            "Failed to convert from classic to modern".to_string(),
+        )
+    })
+}
+
 /// A function which, given hex input, produces equivalent SExp.
 /// All produced SExp have the location given in loc.
 pub fn hex_to_modern_sexp(
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+use crate::compiler::clvm::run_on_clvmr;
+use crate::compiler::compiler::{compile_file, compile_pre_forms, DefaultCompilerOpts};
+use crate::compiler::comptypes::{CompileErr, CompilerOpts};
+use crate::compiler::sexp::{enlist, parse_sexp, SExp};
+use crate::compiler::srcloc::Srcloc;
+
+/// How a chialisp unit test went.
+#[derive(Debug, Clone)]
+pub enum TestOutcome {
+    Pass,
+    /// The test ran, giving a different value than expected.
+    Fail {
+        expected: Rc<SExp>,
+        actual: Rc<SExp>,
+    },
+    /// The test couldn't be compiled or raised when run.
+    Error(Srcloc, String),
+}
+
+/// The result of one chialisp unit test: a deftest form, or a whole test file
+/// that has none.
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub file: String,
+    pub name: String,
+    pub loc: Srcloc,
+    /// The cost of running the test's expression, when it ran.
+    pub cost: Option<Cost>,
+    pub outcome: TestOutcome,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        matches!(self.outcome, TestOutcome::Pass)
+    }
+}
+
+fn short_loc(loc: &Srcloc) -> String {
+    format!("{}({}):{}", loc.file, loc.line, loc.col)
+}
+
+impl Display for TestResult {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let cost = self
+            .cost
+            .map(|c| format!(" (cost {c})"))
+            .unwrap_or_default();
+        match &self.outcome {
+            TestOutcome::Pass => write!(
+                formatter,
+                "PASS {}{cost} at {}",
+                self.name,
+                short_loc(&self.loc)
+            ),
+            TestOutcome::Fail { expected, actual } => write!(
+                formatter,
+                "FAIL {}{cost} at {}\n  expected: {expected}\n  actual:   {actual}",
+                self.name,
+                short_loc(&self.loc)
+            ),
+            TestOutcome::Error(l, e) => write!(
+                formatter,
+                "ERROR {} at {}\n  {}: {e}",
+                self.name,
+                short_loc(&self.loc),
+                short_loc(l)
+            ),
+        }
+    }
+}
+
+/// The chialisp test files, named *_test.clsp, in a directory and the
+/// directories below it, in order of their paths.
+pub fn find_test_files(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|rd| rd.filter_map(|e| e.ok().map(|e| e.path())).collect())
+        .unwrap_or_default();
+    entries.sort();
+
+    for path in entries.into_iter() {
+        if path.is_dir() {
+            found.append(&mut find_test_files(&path));
+        } else if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.ends_with("_test.clsp"))
+            .unwrap_or(false)
+        {
+            found.push(path);
+        }
+    }
+
+    found
+}
+
+// Compile (mod () helpers... exp) from forms keeping their source locations
+// and run it.
+fn run_expression(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    opts: Rc<dyn CompilerOpts>,
+    helpers: &[Rc<SExp>],
+    exp: Rc<SExp>,
+) -> Result<(Cost, Rc<SExp>), CompileErr> {
+    let l = exp.loc();
+    let mut program = vec![
+        Rc::new(SExp::atom_from_string(l.clone(), "mod")),
+        Rc::new(SExp::Nil(l.clone())),
+    ];
+    program.extend(helpers.iter().cloned());
+    program.push(exp);
+    let code = compile_pre_forms(
+        allocator,
+        runner.clone(),
+        opts,
+        &[Rc::new(enlist(l.clone(), program))],
+        &mut HashMap::new(),
+    )?;
+    let env = Rc::new(SExp::Nil(l));
+    Ok(run_on_clvmr(allocator, runner, Rc::new(code), env)?)
+}
+
+fn is_deftest(form: &SExp) -> bool {
+    if let SExp::Cons(_, head, _) = form {
+        if let SExp::Atom(_, name) = head.as_ref() {
+            return name == b"deftest";
+        }
+    }
+    false
+}
+
+fn run_deftest(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    opts: Rc<dyn CompilerOpts>,
+    filename: &str,
+    helpers: &[Rc<SExp>],
+    form: Rc<SExp>,
+) -> TestResult {
+    let loc = form.loc();
+    let parts = form.proper_list().unwrap_or_default();
+    if parts.len() != 4 {
+        return TestResult {
+            file: filename.to_string(),
+            name: form.to_string(),
+            loc: loc.clone(),
+            cost: None,
+            outcome: TestOutcome::Error(
+                loc,
+                "expected (deftest name expression expected)".to_string(),
+            ),
+        };
+    }
+
+    let name = parts[1].to_string();
+    let ran = run_expression(
+        allocator,
+        runner.clone(),
+        opts.clone(),
+        helpers,
+        Rc::new(parts[2].clone()),
+    )
+    .and_then(|(cost, actual)| {
+        run_expression(allocator, runner, opts, helpers, Rc::new(parts[3].clone()))
+            .map(|(_, expected)| (cost, actual, expected))
+    });
+
+    match ran {
+        Ok((cost, actual, expected)) => TestResult {
+            file: filename.to_string(),
+            name,
+            loc,
+            cost: Some(cost),
+            outcome: if actual.to_string() == expected.to_string() {
+                TestOutcome::Pass
+            } else {
+                TestOutcome::Fail { expected, actual }
+            },
+        },
+        Err(CompileErr(l, e)) => TestResult {
+            file: filename.to_string(),
+            name,
+            loc,
+            cost: None,
+            outcome: TestOutcome::Error(l, e),
+        },
+    }
+}
+
+/// Run the chialisp unit tests in a file, compiling them with the modern
+/// compiler, with includes found in the search paths, and running them on
+/// clvmr.
+///
+/// Each (deftest name expression expected) form at the top level of the file
+/// is a test that passes when expression gives the same value as expected.
+/// Both can use the other forms of the file, such as defuns, defconstants and
+/// includes.  A file without deftest forms is a single test, compiled with
+/// compile_file, that passes when its program runs with a nil environment
+/// without raising.
+pub fn run_test_file(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    search_paths: &[String],
+    filename: &str,
+) -> Vec<TestResult> {
+    let mut opts: Rc<dyn CompilerOpts> = Rc::new(DefaultCompilerOpts::new(filename));
+    if !search_paths.is_empty() {
+        opts = opts.set_search_paths(search_paths);
+    }
+    let file_loc = Srcloc::start(filename);
+    let error = |loc: Srcloc, e: String| {
+        vec![TestResult {
+            file: filename.to_string(),
+            name: filename.to_string(),
+            loc: file_loc.clone(),
+            cost: None,
+            outcome: TestOutcome::Error(loc, e),
+        }]
+    };
+
+    let content = match fs::read_to_string(filename) {
+        Ok(content) => content,
+        Err(e) => {
+            return error(file_loc.clone(), format!("could not read: {e}"));
+        }
+    };
+    let forms = match parse_sexp(file_loc.clone(), content.bytes()) {
+        Ok(forms) => forms,
+        Err((l, e)) => {
+            return error(l, e);
+        }
+    };
+
+    if !forms.iter().any(|f| is_deftest(f)) {
+        let ran = compile_file(
+            allocator,
+            runner.clone(),
+            opts,
+            &content,
+            &mut HashMap::new(),
+        )
+        .and_then(|code| {
+            let env = Rc::new(SExp::Nil(file_loc.clone()));
+            Ok(run_on_clvmr(allocator, runner, Rc::new(code), env)?)
+        });
+        return match ran {
+            Ok((cost, _)) => vec![TestResult {
+                file: filename.to_string(),
+                name: filename.to_string(),
+                loc: file_loc.clone(),
+                cost: Some(cost),
+                outcome: TestOutcome::Pass,
+            }],
+            Err(CompileErr(l, e)) => error(l, e),
+        };
+    }
+
+    let helpers: Vec<Rc<SExp>> = forms.iter().filter(|f| !is_deftest(f)).cloned().collect();
+    forms
+        .iter()
+        .filter(|f| is_deftest(f))
+        .map(|f| {
+            run_deftest(
+                allocator,
+                runner.clone(),
+                opts.clone(),
+                filename,
+                &helpers,
+                f.clone(),
+            )
+        })
+        .collect()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// JUnit XML describing test results, with a testsuite for each file.
+pub fn junit_xml(results: &[TestResult]) -> String {
+    let mut files: Vec<String> = Vec::new();
+    for r in results.iter() {
+        if !files.contains(&r.file) {
+            files.push(r.file.clone());
+        }
+    }
+
+    let count = |rs: &[&TestResult], f: &dyn Fn(&TestOutcome) -> bool| {
+        rs.iter().filter(|r| f(&r.outcome)).count()
+    };
+    let is_failure = |o: &TestOutcome| matches!(o, TestOutcome::Fail { .. });
+    let is_error = |o: &TestOutcome| matches!(o, TestOutcome::Error(_, _));
+    let all: Vec<&TestResult> = results.iter().collect();
+
+    let mut xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n".to_string();
+    xml += &format!(
+        "<testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+        all.len(),
+        count(&all, &is_failure),
+        count(&all, &is_error)
+    );
+    for file in files.iter() {
+        let in_file: Vec<&TestResult> = results.iter().filter(|r| &r.file == file).collect();
+        xml += &format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+            xml_escape(file),
+            in_file.len(),
+            count(&in_file, &is_failure),
+            count(&in_file, &is_error)
+        );
+        for r in in_file.iter() {
+            let testcase = format!(
+                "    <testcase name=\"{}\" classname=\"{}\" file=\"{}\" line=\"{}\"",
+                xml_escape(&r.name),
+                xml_escape(file),
+                xml_escape(&r.loc.file),
+                r.loc.line
+            );
+            match &r.outcome {
+                TestOutcome::Pass => {
+                    xml += &format!("{testcase}/>\n");
+                }
+                TestOutcome::Fail { expected, actual } => {
+                    xml += &format!(
+                        "{testcase}>\n      <failure message=\"expected {} but got {}\">{}</failure>\n    </testcase>\n",
+                        xml_escape(&expected.to_string()),
+                        xml_escape(&actual.to_string()),
+                        xml_escape(&r.to_string())
+                    );
+                }
+                TestOutcome::Error(l, e) => {
+                    xml += &format!(
+                        "{testcase}>\n      <error message=\"{}\">{}</error>\n    </testcase>\n",
+                        xml_escape(&format!("{}: {e}", short_loc(l))),
+                        xml_escape(&r.to_string())
+                    );
+                }
+            }
+        }
+        xml += "  </testsuite>\n";
+    }
+    xml + "</testsuites>\n"
+}
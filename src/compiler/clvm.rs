@@ -4,6 +4,7 @@ use std::rc::Rc;
 
 use clvm_rs::allocator;
 use clvm_rs::allocator::{Allocator, NodePtr};
+use clvm_rs::cost::Cost;
 
 use num_bigint::ToBigInt;
 
@@ -11,8 +12,13 @@ use sha2::Digest;
 use sha2::Sha256;
 
 use crate::classic::clvm::__type_compatibility__::{bi_one, bi_zero};
+use crate::classic::clvm::costs::{
+    APPLY_COST, CONS_COST, FIRST_COST, IF_COST, OP_COST, PATH_LOOKUP_BASE_COST,
+    PATH_LOOKUP_COST_PER_LEG, PATH_LOOKUP_COST_PER_ZERO_BYTE, QUOTE_COST, REST_COST,
+};
 use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
 
+use crate::compiler::backtrace::run_with_backtrace;
 use crate::compiler::prims;
 use crate::compiler::runtypes::RunFailure;
 use crate::compiler::sexp::{parse_sexp, SExp};
@@ -26,51 +32,98 @@ use crate::util::{number_from_u8, u8_from_number, Number};
 /// Being immutable, it can be preserved, examined and compared as desired.  The
 /// whole record of CLVM execution can be observed by collecting these until the
 /// program is in the Done state.
+///
+/// Each step carries the cumulative CLVM cost of the run up to that point,
+/// computed the same way clvmr computes it.
 #[derive(Clone, Debug)]
 pub enum RunStep {
     /// The state of a program (or subprogram) that completed.
-    /// Contains a location taken from the operator that completed and the result
-    /// value.
-    Done(Srcloc, Rc<SExp>),
+    /// Contains a location taken from the operator that completed, the result
+    /// value and the cost.
+    Done(Srcloc, Rc<SExp>, Cost),
     /// An operator producing a result.  The operator has run and the result is
-    /// given.  The final argument is a refcounted pointer to a step that the
-    /// operator's result will be returned to if it stepped again.
-    OpResult(Srcloc, Rc<SExp>, Rc<RunStep>),
+    /// given.  The fourth argument is a refcounted pointer to a step that the
+    /// operator's result will be returned to if it stepped again, and the last
+    /// is the step's depth.
+    OpResult(Srcloc, Rc<SExp>, Cost, Rc<RunStep>, usize),
     /// An operator in flight.  The arguments are
     /// - An operator
     /// - The environment
     /// - The tail of the expression if an operator, in progress.
     /// - When present, a list of arguments remaining to evaluate, otherwise
     ///   the expression is ready to run the operator on.
+    /// - The cost so far.
     /// - The RunStep to which this step returns a value when complete.
+    /// - The step's depth.
     Op(
         Rc<SExp>,
         Rc<SExp>,
         Rc<SExp>,
         Option<Vec<Rc<SExp>>>,
+        Cost,
         Rc<RunStep>,
+        usize,
     ),
-    /// A step about to be taken.  Indicates a clvm expression and env, plus the
-    /// parent to which its value is returned.
-    Step(Rc<SExp>, Rc<SExp>, Rc<RunStep>),
+    /// A step about to be taken.  Indicates a clvm expression and env, the cost
+    /// so far, the parent to which its value is returned and the step's depth.
+    Step(Rc<SExp>, Rc<SExp>, Cost, Rc<RunStep>, usize),
 }
 
 impl RunStep {
     pub fn parent(&self) -> Option<Rc<RunStep>> {
         match self {
-            RunStep::Done(_, _) => None,
-            RunStep::OpResult(_, _, p) => Some(p.clone()),
-            RunStep::Op(_, _, _, _, p) => Some(p.clone()),
-            RunStep::Step(_, _, p) => Some(p.clone()),
+            RunStep::Done(_, _, _) => None,
+            RunStep::OpResult(_, _, _, p, _) => Some(p.clone()),
+            RunStep::Op(_, _, _, _, _, p, _) => Some(p.clone()),
+            RunStep::Step(_, _, _, p, _) => Some(p.clone()),
         }
     }
 
     pub fn sexp(&self) -> Rc<SExp> {
         match self {
-            RunStep::Done(_, s) => s.clone(),
-            RunStep::OpResult(_, s, _) => s.clone(),
-            RunStep::Op(s, _, _, _, _) => s.clone(),
-            RunStep::Step(s, _, _) => s.clone(),
+            RunStep::Done(_, s, _) => s.clone(),
+            RunStep::OpResult(_, s, _, _, _) => s.clone(),
+            RunStep::Op(s, _, _, _, _, _, _) => s.clone(),
+            RunStep::Step(s, _, _, _, _) => s.clone(),
+        }
+    }
+
+    /// If this step is about to apply a program to an environment (the clvm
+    /// apply operator with its arguments evaluated), give the program and the
+    /// environment.
+    pub fn applied_program(&self) -> Option<(Rc<SExp>, Rc<SExp>)> {
+        if let RunStep::Op(head, _, tail, None, _, _, _) = self {
+            if head.get_number().ok() == 2_i32.to_bigint() {
+                if let SExp::Cons(_, program, rest) = tail.borrow() {
+                    if let SExp::Cons(_, env, _) = rest.borrow() {
+                        return Some((program.clone(), env.clone()));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The number of steps in this step's parent chain, including itself,
+    /// which tells how deeply nested in the evaluation it is.  Each step
+    /// records it when made, as one more than its parent's.
+    pub fn depth(&self) -> usize {
+        match self {
+            RunStep::Done(_, _, _) => 1,
+            RunStep::OpResult(_, _, _, _, d) => *d,
+            RunStep::Op(_, _, _, _, _, _, d) => *d,
+            RunStep::Step(_, _, _, _, d) => *d,
+        }
+    }
+
+    /// The cumulative cost of the run when this step was reached.
+    pub fn cost(&self) -> Cost {
+        match self {
+            RunStep::Done(_, _, c) => *c,
+            RunStep::OpResult(_, _, c, _, _) => *c,
+            RunStep::Op(_, _, _, _, c, _, _) => *c,
+            RunStep::Step(_, _, c, _, _) => *c,
         }
     }
 }
@@ -154,16 +207,15 @@ fn translate_head(
 }
 
 fn eval_args(
-    _allocator: &mut Allocator,
-    _runner: Rc<dyn TRunProgram>,
-    _prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
     head: Rc<SExp>,
     sexp_: Rc<SExp>,
     context_: Rc<SExp>,
+    cost: Cost,
     parent: Rc<RunStep>,
 ) -> Result<RunStep, RunFailure> {
     let mut sexp = sexp_.clone();
     let mut eval_list: Vec<Rc<SExp>> = Vec::new();
+    let depth = parent.depth() + 1;
 
     loop {
         match sexp.borrow() {
@@ -173,7 +225,9 @@ fn eval_args(
                     context_,
                     sexp.clone(),
                     Some(eval_list),
+                    cost,
                     parent,
+                    depth,
                 ));
             }
             SExp::Cons(_l, a, b) => {
@@ -255,6 +309,29 @@ pub fn convert_from_clvm_rs(
     }
 }
 
+/// Run code on env with clvmr through the given runner, giving the cost and
+/// result.  When clvmr fails, the error names the value it failed at.
+pub fn run_on_clvmr(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    code: Rc<SExp>,
+    env: Rc<SExp>,
+) -> Result<(Cost, Rc<SExp>), RunFailure> {
+    let loc = code.loc();
+    let code_node = convert_to_clvm_rs(allocator, code)?;
+    let env_node = convert_to_clvm_rs(allocator, env)?;
+    let reduction = runner
+        .run_program(allocator, code_node, env_node, None)
+        .map_err(|e| {
+            let at = convert_from_clvm_rs(allocator, loc.clone(), e.0)
+                .map(|at| at.to_string())
+                .unwrap_or_default();
+            RunFailure::RunErr(loc.clone(), format!("{} in {at}", e.1))
+        })?;
+    let result = convert_from_clvm_rs(allocator, loc, reduction.1)?;
+    Ok((reduction.0, result))
+}
+
 fn generate_argument_refs(start: Number, sexp: Rc<SExp>) -> Rc<SExp> {
     match sexp.borrow() {
         SExp::Cons(l, a, b) => {
@@ -270,23 +347,51 @@ fn generate_argument_refs(start: Number, sexp: Rc<SExp>) -> Rc<SExp> {
     }
 }
 
+/// The cost clvmr charges for looking up an environment path given as the
+/// bytes of an atom.
+pub fn path_lookup_cost(path: &[u8]) -> Cost {
+    let first_bit_byte_index = path.iter().take_while(|b| **b == 0).count();
+    let cost = PATH_LOOKUP_BASE_COST
+        + (first_bit_byte_index as Cost) * PATH_LOOKUP_COST_PER_ZERO_BYTE
+        + PATH_LOOKUP_COST_PER_LEG;
+
+    if first_bit_byte_index >= path.len() {
+        return cost;
+    }
+
+    // Every bit below the most significant set bit (the sentinel) is a leg.
+    let sentinel_bit = 7 - path[first_bit_byte_index].leading_zeros() as usize;
+    let legs = (path.len() - first_bit_byte_index - 1) * 8 + sentinel_bit;
+    cost + (legs as Cost) * PATH_LOOKUP_COST_PER_LEG
+}
+
+// Run an operator in clvmr on already evaluated arguments.  The result is
+// returned along with the cost of the operator alone; the cost of the argument
+// lookups we used to feed clvmr is removed since the arguments were paid for
+// when they were evaluated.
 fn apply_op(
     allocator: &mut Allocator,
     runner: Rc<dyn TRunProgram>,
     l: Srcloc,
     head: Rc<SExp>,
     args: Rc<SExp>,
-) -> Result<Rc<SExp>, RunFailure> {
+) -> Result<(Cost, Rc<SExp>), RunFailure> {
     let wrapped_args = Rc::new(SExp::Cons(
         l.clone(),
         Rc::new(SExp::Nil(l.clone())),
         args.clone(),
     ));
-    let application = Rc::new(SExp::Cons(
-        l,
-        head.clone(),
-        generate_argument_refs(5_i32.to_bigint().unwrap(), args),
-    ));
+    let argument_refs = generate_argument_refs(5_i32.to_bigint().unwrap(), args);
+    let lookup_cost: Cost = argument_refs
+        .proper_list()
+        .unwrap_or_default()
+        .iter()
+        .map(|r| match r {
+            SExp::Integer(_, i) => path_lookup_cost(&u8_from_number(i.clone())),
+            _ => 0,
+        })
+        .sum();
+    let application = Rc::new(SExp::Cons(l, head.clone(), argument_refs));
     let converted_app = convert_to_clvm_rs(allocator, application.clone())?;
     let converted_args = convert_to_clvm_rs(allocator, wrapped_args.clone())?;
 
@@ -298,7 +403,10 @@ fn apply_op(
                 format!("{} in {application} {wrapped_args}", e.1),
             )
         })
-        .and_then(|v| convert_from_clvm_rs(allocator, head.loc(), v.1))
+        .and_then(|v| {
+            let op_cost = v.0.saturating_sub(OP_COST + lookup_cost);
+            convert_from_clvm_rs(allocator, head.loc(), v.1).map(|r| (op_cost, r))
+        })
 }
 
 fn atom_value(head: Rc<SExp>) -> Result<Number, RunFailure> {
@@ -316,12 +424,7 @@ fn atom_value(head: Rc<SExp>) -> Result<Number, RunFailure> {
 
 /// Tell how many parents are in the parent step chain until completion.
 pub fn get_history_len(step: Rc<RunStep>) -> usize {
-    match step.borrow() {
-        RunStep::Done(_, _) => 1,
-        RunStep::OpResult(_, _, p) => 1 + get_history_len(p.clone()),
-        RunStep::Op(_, _, _, _, p) => 1 + get_history_len(p.clone()),
-        RunStep::Step(_, _, p) => 1 + get_history_len(p.clone()),
-    }
+    step.depth()
 }
 
 /// Generically determine whether a value is truthy.
@@ -336,22 +439,27 @@ pub fn truthy(sexp: Rc<SExp>) -> bool {
 /// For example, when a finished operation is combined with a parent that needs
 /// more arguments for its operator, one needed argument evaluation is removed
 /// and the step becomes closer to evaluation.
+///
+/// The cost of the combined step is always the cost of the completed step,
+/// since the parent was recorded before the work below it was done.
 pub fn combine(a: &RunStep, b: &RunStep) -> RunStep {
     match (a, b.borrow()) {
-        (RunStep::Done(l, x), RunStep::Done(_, _)) => RunStep::Done(l.clone(), x.clone()),
-        (RunStep::Done(l, x), RunStep::Op(head, context, args, Some(remain), parent)) => {
+        (RunStep::Done(l, x, c), RunStep::Done(_, _, _)) => RunStep::Done(l.clone(), x.clone(), *c),
+        (RunStep::Done(l, x, c), RunStep::Op(head, context, args, Some(remain), _, parent, _)) => {
             RunStep::Op(
                 head.clone(),
                 context.clone(),
                 Rc::new(SExp::Cons(l.clone(), x.clone(), args.clone())),
                 Some(remain.clone()),
+                *c,
                 parent.clone(),
+                parent.depth() + 1,
             )
         }
-        (RunStep::Done(_l, _x), RunStep::Op(_head, _context, _args, None, parent)) => {
+        (RunStep::Done(_l, _x, _c), RunStep::Op(_head, _context, _args, None, _, parent, _)) => {
             combine(a, parent.borrow())
         }
-        (RunStep::Done(_l, _x), RunStep::Step(_sexp, _context, parent)) => {
+        (RunStep::Done(_l, _x, _c), RunStep::Step(_sexp, _context, _, parent, _)) => {
             combine(a, parent.borrow())
         }
         _ => a.clone(),
@@ -364,6 +472,24 @@ pub fn flatten_signed_int(v: Number) -> Number {
     Number::from_signed_bytes_le(&sign_digits)
 }
 
+// Look up an environment path given by the bytes of an atom, producing the
+// result of the step.
+fn path_lookup_step(
+    l: &Srcloc,
+    path: &[u8],
+    context: Rc<SExp>,
+    step_: &RunStep,
+) -> Result<RunStep, RunFailure> {
+    let flat_v = flatten_signed_int(number_from_u8(path));
+    Ok(RunStep::OpResult(
+        l.clone(),
+        choose_path(l.clone(), flat_v.clone(), flat_v, context.clone(), context)?,
+        step_.cost() + path_lookup_cost(path),
+        Rc::new(step_.clone()),
+        step_.depth() + 1,
+    ))
+}
+
 /// The main operation to step the machine.  Given a RunStep, produce a new RunStep
 /// which is one step farther toward a result.  When complete, the result is a
 /// Done value.
@@ -374,49 +500,33 @@ pub fn run_step(
     step_: &RunStep,
 ) -> Result<RunStep, RunFailure> {
     let mut step = step_.clone();
+    let cost = step_.cost();
 
     match &step {
-        RunStep::OpResult(l, x, p) => {
+        RunStep::OpResult(l, x, c, p, _) => {
             let parent: &RunStep = p.borrow();
-            return Ok(combine(&RunStep::Done(l.clone(), x.clone()), parent));
+            return Ok(combine(&RunStep::Done(l.clone(), x.clone(), *c), parent));
         }
-        RunStep::Done(_l, _x) => {}
-        RunStep::Step(sexp, context, parent) => {
+        RunStep::Done(_l, _x, _c) => {}
+        RunStep::Step(sexp, context, _c, parent, _) => {
             match sexp.borrow() {
                 SExp::Integer(l, v) => {
                     /* An integer picks a value from the context */
-                    let flat_v = flatten_signed_int(v.clone());
-                    return Ok(RunStep::OpResult(
-                        l.clone(),
-                        choose_path(
-                            l.clone(),
-                            flat_v.clone(),
-                            flat_v,
-                            context.clone(),
-                            context.clone(),
-                        )?,
-                        Rc::new(step_.clone()),
-                    ));
+                    return path_lookup_step(l, &u8_from_number(v.clone()), context.clone(), step_);
                 }
                 SExp::QuotedString(l, _, v) => {
-                    step = RunStep::Step(
-                        Rc::new(SExp::Integer(l.clone(), number_from_u8(v))),
-                        context.clone(),
-                        parent.clone(),
-                    );
+                    return path_lookup_step(l, v, context.clone(), step_);
                 }
                 SExp::Atom(l, v) => {
-                    step = RunStep::Step(
-                        Rc::new(SExp::Integer(l.clone(), number_from_u8(v))),
-                        context.clone(),
-                        parent.clone(),
-                    );
+                    return path_lookup_step(l, v, context.clone(), step_);
                 }
                 SExp::Nil(l) => {
                     return Ok(RunStep::OpResult(
                         l.clone(),
                         sexp.clone(),
+                        cost + path_lookup_cost(&[]),
                         Rc::new(step_.clone()),
+                        step_.depth() + 1,
                     ));
                 }
                 SExp::Cons(l, a, b) => {
@@ -433,35 +543,37 @@ pub fn run_step(
                     );
 
                     if atom_value(head.clone())? == bi_one() {
-                        step = RunStep::Done(l.clone(), b.clone());
+                        step = RunStep::Done(l.clone(), b.clone(), cost + QUOTE_COST);
                     } else {
                         step = eval_args(
-                            allocator,
-                            runner.clone(),
-                            prim_map,
                             head,
                             b.clone(),
                             context.clone(),
+                            cost + OP_COST,
                             parent.clone(),
                         )?;
                     }
                 }
             }
         }
-        RunStep::Op(head, context, tail, Some(rest), parent) => {
+        RunStep::Op(head, context, tail, Some(rest), _c, parent, _) => {
             let mut rest_mut = rest.clone();
             match rest_mut.pop() {
                 Some(x) => {
                     step = RunStep::Step(
                         x,
                         context.clone(),
+                        cost,
                         Rc::new(RunStep::Op(
                             head.clone(),
                             context.clone(),
                             tail.clone(),
                             Some(rest_mut),
+                            cost,
                             parent.clone(),
+                            parent.depth() + 1,
                         )),
+                        parent.depth() + 2,
                     );
                 }
                 None => {
@@ -470,12 +582,14 @@ pub fn run_step(
                         context.clone(),
                         tail.clone(),
                         None,
+                        cost,
                         parent.clone(),
+                        parent.depth() + 1,
                     );
                 }
             }
         }
-        RunStep::Op(head, _context, tail, None, parent) => {
+        RunStep::Op(head, _context, tail, None, _c, parent, _) => {
             let aval = atom_value(head.clone())?;
             let apply_atom = 2_i32.to_bigint().unwrap();
             let if_atom = 3_i32.to_bigint().unwrap();
@@ -529,7 +643,7 @@ pub fn run_step(
                             l[2].clone()
                         };
 
-                        step = RunStep::Done(outcome.loc(), Rc::new(outcome));
+                        step = RunStep::Done(outcome.loc(), Rc::new(outcome), cost + IF_COST);
                     } else if aval == cons_atom {
                         return Ok(RunStep::OpResult(
                             head.loc(),
@@ -538,7 +652,9 @@ pub fn run_step(
                                 Rc::new(l[0].clone()),
                                 Rc::new(l[1].clone()),
                             )),
+                            cost + CONS_COST,
                             Rc::new(step_.clone()),
+                            step_.depth() + 1,
                         ));
                     } else if aval == first_atom || aval == rest_atom {
                         match &l[0] {
@@ -547,13 +663,17 @@ pub fn run_step(
                                     return Ok(RunStep::OpResult(
                                         a.loc(),
                                         a.clone(),
+                                        cost + FIRST_COST,
                                         Rc::new(step_.clone()),
+                                        step_.depth() + 1,
                                     ));
                                 } else {
                                     return Ok(RunStep::OpResult(
                                         b.loc(),
                                         b.clone(),
+                                        cost + REST_COST,
                                         Rc::new(step_.clone()),
+                                        step_.depth() + 1,
                                     ));
                                 }
                             }
@@ -568,10 +688,12 @@ pub fn run_step(
                         step = RunStep::Step(
                             Rc::new(l[0].clone()),
                             Rc::new(l[1].clone()),
+                            cost + APPLY_COST,
                             parent.clone(),
+                            parent.depth() + 1,
                         );
                     } else {
-                        let result = apply_op(
+                        let (op_cost, result) = apply_op(
                             allocator,
                             runner.clone(),
                             head.loc(),
@@ -582,7 +704,9 @@ pub fn run_step(
                         return Ok(RunStep::OpResult(
                             head.loc(),
                             result,
+                            cost + op_cost,
                             Rc::new(step_.clone()),
+                            step_.depth() + 1,
                         ));
                     }
                 }
@@ -597,11 +721,27 @@ pub fn start_step(sexp_: Rc<SExp>, context_: Rc<SExp>) -> RunStep {
     RunStep::Step(
         sexp_.clone(),
         context_,
-        Rc::new(RunStep::Done(sexp_.loc(), sexp_.clone())),
+        0,
+        Rc::new(RunStep::Done(sexp_.loc(), sexp_.clone(), 0)),
+        2,
     )
 }
 
-/// Use the RunStep object to evaluate some clvm to completion.
+/// Produce the failure reported when a step's cost goes over max_cost, if it
+/// did.
+pub fn check_max_cost(step: &RunStep, max_cost: Option<Cost>) -> Result<(), RunFailure> {
+    match max_cost {
+        Some(max_cost) if step.cost() > max_cost => Err(RunFailure::RunErr(
+            step.sexp().loc(),
+            "cost exceeded".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Use the RunStep object to evaluate some clvm to completion.  This is
+/// backtrace::run_with_backtrace without a symbol table, which given one also
+/// says which chialisp functions a failure happened in.
 pub fn run(
     allocator: &mut Allocator,
     runner: Rc<dyn TRunProgram>,
@@ -610,21 +750,27 @@ pub fn run(
     context_: Rc<SExp>,
     iter_limit: Option<usize>,
 ) -> Result<Rc<SExp>, RunFailure> {
-    let mut step = start_step(sexp_, context_);
-    let mut iters = 0;
+    run_with_cost(
+        allocator, runner, prim_map, sexp_, context_, iter_limit, None,
+    )
+    .map(|x| x.1)
+}
 
-    loop {
-        if let Some(limit) = &iter_limit {
-            if *limit <= iters {
-                return Err(RunFailure::RunErr(step.sexp().loc(), "timeout".to_string()));
-            }
-        }
-        iters += 1;
-        step = run_step(allocator, runner.clone(), prim_map.clone(), &step)?;
-        if let RunStep::Done(_, x) = step {
-            return Ok(x);
-        }
-    }
+/// Evaluate some clvm to completion as run does, also producing the total cost
+/// of the run.  If max_cost is given, the run fails as soon as it is exceeded.
+pub fn run_with_cost(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    sexp_: Rc<SExp>,
+    context_: Rc<SExp>,
+    iter_limit: Option<usize>,
+    max_cost: Option<Cost>,
+) -> Result<(Cost, Rc<SExp>), RunFailure> {
+    run_with_backtrace(
+        allocator, runner, prim_map, None, sexp_, context_, iter_limit, max_cost,
+    )
+    .map_err(|e| e.failure)
 }
 
 /// A convenience function which, givne a text program, its arguments and filename,
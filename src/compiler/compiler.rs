@@ -7,7 +7,7 @@ use std::rc::Rc;
 
 use clvm_rs::allocator::Allocator;
 
-use crate::classic::clvm::__type_compatibility__::{bi_one, bi_zero};
+use crate::classic::clvm::__type_compatibility__::{bi_one, bi_zero, Bytes, BytesFromType};
 use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
 use crate::classic::clvm_tools::stages::stage_2::optimize::optimize_sexp;
 
@@ -19,7 +19,6 @@ use crate::compiler::comptypes::{
 use crate::compiler::evaluate::{build_reflex_captures, Evaluator, EVAL_STACK_LIMIT};
 use crate::compiler::frontend::frontend;
 use crate::compiler::prims;
-use crate::compiler::runtypes::RunFailure;
 use crate::compiler::sexp::{parse_sexp, SExp};
 use crate::compiler::srcloc::Srcloc;
 use crate::util::Number;
@@ -209,19 +208,13 @@ pub fn run_optimizer(
 ) -> Result<Rc<SExp>, CompileErr> {
     let to_clvm_rs = convert_to_clvm_rs(allocator, r.clone())
         .map(|x| (r.loc(), x))
-        .map_err(|e| match e {
-            RunFailure::RunErr(l, e) => CompileErr(l, e),
-            RunFailure::RunExn(s, e) => CompileErr(s, format!("exception {e}\n")),
-        })?;
+        .map_err(CompileErr::from)?;
 
     let optimized = optimize_sexp(allocator, to_clvm_rs.1, runner)
         .map_err(|e| CompileErr(to_clvm_rs.0.clone(), e.1))
         .map(|x| (to_clvm_rs.0, x))?;
 
-    convert_from_clvm_rs(allocator, optimized.0, optimized.1).map_err(|e| match e {
-        RunFailure::RunErr(l, e) => CompileErr(l, e),
-        RunFailure::RunExn(s, e) => CompileErr(s, format!("exception {e}\n")),
-    })
+    convert_from_clvm_rs(allocator, optimized.0, optimized.1).map_err(CompileErr::from)
 }
 
 impl CompilerOpts for DefaultCompilerOpts {
@@ -490,6 +483,46 @@ pub fn extract_program_and_env(program: Rc<SExp>) -> Option<(Rc<SExp>, Rc<SExp>)
     }
 }
 
+/// Find the hash of the named function in a symbol table, skipping entries
+/// like <hash>_arguments which hold information about a function.
+pub fn find_function_hash(symbol_table: &HashMap<String, String>, name: &str) -> Option<String> {
+    symbol_table
+        .iter()
+        .find(|(hash, n)| *n == name && !hash.contains('_'))
+        .map(|(hash, _)| hash.clone())
+}
+
+/// Given a compiled program and its symbol table, compose the program that
+/// runs the named function by itself, as though the program's main expression
+/// had been a call to it with the whole environment as its arguments.  This
+/// lets a program's helpers be tested in isolation.
+pub fn compose_run_function(
+    program: Rc<SExp>,
+    symbol_table: &HashMap<String, String>,
+    function_name: &str,
+) -> Result<Rc<SExp>, CompileErr> {
+    let function_hash = find_function_hash(symbol_table, function_name).ok_or_else(|| {
+        CompileErr(
+            program.loc(),
+            format!("function not found in symbols: {function_name}"),
+        )
+    })?;
+    let (_, env) = extract_program_and_env(program.clone()).ok_or_else(|| {
+        CompileErr(
+            program.loc(),
+            "could not extract env from program".to_string(),
+        )
+    })?;
+    let hash_bytes = Bytes::new(Some(BytesFromType::Hex(function_hash)));
+    let function_path = path_to_function(env.clone(), hash_bytes.data()).ok_or_else(|| {
+        CompileErr(
+            program.loc(),
+            format!("could not find function with hash from symbols: {function_name}"),
+        )
+    })?;
+    Ok(rewrite_in_program(function_path, env))
+}
+
 pub fn is_at_capture(head: Rc<SExp>, rest: Rc<SExp>) -> Option<(Vec<u8>, Rc<SExp>)> {
     rest.proper_list().and_then(|l| {
         if l.len() != 2 {
@@ -0,0 +1,315 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+use num_bigint::ToBigInt;
+use serde::{Deserialize, Serialize};
+
+use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType};
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+
+use crate::compiler::clvm::{check_max_cost, run_step, sha256tree, start_step, truthy, RunStep};
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::SExp;
+use crate::compiler::srcloc::{parse_srcloc, Srcloc};
+
+/// How many times each way out of an (i ...) form was taken.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BranchCoverage {
+    pub then_taken: u64,
+    pub else_taken: u64,
+}
+
+/// Coverage of one source file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileCoverage {
+    /// Hit counts by line.  Lines with code that never ran are present with 0.
+    pub lines: BTreeMap<usize, u64>,
+    /// Branch counts of the i operator by line, then by column.
+    pub branches: BTreeMap<usize, BTreeMap<usize, BranchCoverage>>,
+}
+
+/// Line and branch coverage by source file, accumulated over any number of
+/// runs.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coverage {
+    pub files: BTreeMap<String, FileCoverage>,
+}
+
+// Synthetic files such as *macros* and *prims* aren't worth reporting on.
+fn is_source_loc(loc: &Srcloc) -> bool {
+    !loc.file.starts_with('*')
+}
+
+fn form_operator(sexp: &SExp) -> Option<u8> {
+    if let SExp::Cons(_, head, _) = sexp {
+        let op = head.get_number().ok()?;
+        if op == 1_i32.to_bigint().unwrap() {
+            return Some(1);
+        } else if op == 3_i32.to_bigint().unwrap() {
+            return Some(3);
+        }
+        return Some(0);
+    }
+    None
+}
+
+// Where a branch is reported.  When the (i ...) form came from a macro, the
+// location of its condition is used instead.
+fn branch_location(sexp: &SExp) -> Option<Srcloc> {
+    if form_operator(sexp) != Some(3) {
+        return None;
+    }
+    let args = if let SExp::Cons(_, _, args) = sexp {
+        args.proper_list()?
+    } else {
+        return None;
+    };
+    if args.len() != 3 {
+        return None;
+    }
+
+    let loc = sexp.loc();
+    if is_source_loc(&loc) {
+        return Some(loc);
+    }
+    let cond_loc = args[0].loc();
+    if is_source_loc(&cond_loc) {
+        return Some(cond_loc);
+    }
+    None
+}
+
+impl Coverage {
+    fn file(&mut self, loc: &Srcloc) -> &mut FileCoverage {
+        let file: &String = loc.file.borrow();
+        self.files.entry(file.clone()).or_default()
+    }
+
+    /// Record the lines and branches in the program as coverable, so that ones
+    /// which are never run are reported.
+    ///
+    /// Code in compiled chialisp lives in quoted data, so everything quoted is
+    /// treated as possibly being code.
+    pub fn add_program(&mut self, program: &SExp) {
+        if let SExp::Cons(l, _, rest) = program {
+            if form_operator(program) == Some(1) {
+                self.add_program(rest);
+                return;
+            }
+
+            if is_source_loc(l) {
+                self.file(l).lines.entry(l.line).or_insert(0);
+            }
+            if let Some(bl) = branch_location(program) {
+                self.file(&bl)
+                    .branches
+                    .entry(bl.line)
+                    .or_default()
+                    .entry(bl.col)
+                    .or_default();
+            }
+            if let Some(args) = rest.proper_list() {
+                for a in args.iter() {
+                    self.add_program(a);
+                }
+            }
+        }
+    }
+
+    fn hit_line(&mut self, loc: &Srcloc) {
+        *self.file(loc).lines.entry(loc.line).or_insert(0) += 1;
+    }
+
+    fn hit_branch(&mut self, loc: &Srcloc, taken: bool) {
+        let branch = self
+            .file(loc)
+            .branches
+            .entry(loc.line)
+            .or_default()
+            .entry(loc.col)
+            .or_default();
+        if taken {
+            branch.then_taken += 1;
+        } else {
+            branch.else_taken += 1;
+        }
+    }
+
+    /// Add the counts from other coverage into this one.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (file, other_file) in other.files.iter() {
+            let this_file = self.files.entry(file.clone()).or_default();
+            for (line, hits) in other_file.lines.iter() {
+                *this_file.lines.entry(*line).or_insert(0) += hits;
+            }
+            for (line, cols) in other_file.branches.iter() {
+                let this_line = this_file.branches.entry(*line).or_default();
+                for (col, b) in cols.iter() {
+                    let this_branch = this_line.entry(*col).or_default();
+                    this_branch.then_taken += b.then_taken;
+                    this_branch.else_taken += b.else_taken;
+                }
+            }
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Coverage, String> {
+        serde_json::from_str(json).map_err(|e| format!("bad coverage json: {e}"))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap_or_default()
+    }
+
+    /// The coverage in the lcov tracefile format read by genhtml and most
+    /// coverage services.  Each (i ...) form is a block with the then branch
+    /// numbered 0 and the else branch numbered 1.
+    pub fn to_lcov(&self) -> String {
+        let mut result = String::new();
+        for (file, fc) in self.files.iter() {
+            result.push_str(&format!("TN:\nSF:{file}\n"));
+
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (line, cols) in fc.branches.iter() {
+                for (block, b) in cols.values().enumerate() {
+                    let never_run = b.then_taken == 0 && b.else_taken == 0;
+                    for (branch, taken) in [b.then_taken, b.else_taken].iter().enumerate() {
+                        let taken_str = if never_run {
+                            "-".to_string()
+                        } else {
+                            taken.to_string()
+                        };
+                        result.push_str(&format!("BRDA:{line},{block},{branch},{taken_str}\n"));
+                        branches_found += 1;
+                        if *taken > 0 {
+                            branches_hit += 1;
+                        }
+                    }
+                }
+            }
+            result.push_str(&format!("BRF:{branches_found}\nBRH:{branches_hit}\n"));
+
+            for (line, hits) in fc.lines.iter() {
+                result.push_str(&format!("DA:{line},{hits}\n"));
+            }
+            let lines_hit = fc.lines.values().filter(|h| **h > 0).count();
+            result.push_str(&format!(
+                "LF:{}\nLH:{lines_hit}\nend_of_record\n",
+                fc.lines.len()
+            ));
+        }
+        result
+    }
+}
+
+/// Give a program whose subtrees carry the source locations recorded for them
+/// in a symbol table, so that a program read from hex or clvm text can be
+/// related to its chialisp source.  Subtrees not in the table take the location
+/// of their parent.
+pub fn program_with_source_locations(
+    symbol_table: &HashMap<String, String>,
+    loc: Srcloc,
+    program: Rc<SExp>,
+) -> Rc<SExp> {
+    let hash = Bytes::new(Some(BytesFromType::Raw(sha256tree(program.clone())))).hex();
+    let srcloc = symbol_table
+        .get(&hash)
+        .and_then(|l| parse_srcloc(l))
+        .unwrap_or(loc);
+
+    match program.borrow() {
+        SExp::Cons(_, a, b) => Rc::new(SExp::Cons(
+            srcloc.clone(),
+            program_with_source_locations(symbol_table, srcloc.clone(), a.clone()),
+            program_with_source_locations(symbol_table, srcloc, b.clone()),
+        )),
+        _ => Rc::new(program.with_loc(srcloc)),
+    }
+}
+
+/// Records the coverage of a run by observing each step before it's taken.
+pub struct CoverageRecorder {
+    coverage: Coverage,
+    // Locations of (i ...) forms whose arguments are being evaluated, by the
+    // depth of the step that will apply them.
+    pending_branches: HashMap<usize, Srcloc>,
+}
+
+impl CoverageRecorder {
+    pub fn new(program: &SExp) -> Self {
+        let mut coverage = Coverage::default();
+        coverage.add_program(program);
+        CoverageRecorder {
+            coverage,
+            pending_branches: HashMap::new(),
+        }
+    }
+
+    /// Account for a step the run is about to take.
+    pub fn observe(&mut self, step: &RunStep) {
+        match step {
+            RunStep::Step(sexp, _, _, _, _) => {
+                let op = form_operator(sexp);
+                let loc = sexp.loc();
+                if op.is_some() && op != Some(1) && is_source_loc(&loc) {
+                    self.coverage.hit_line(&loc);
+                }
+                if let Some(bl) = branch_location(sexp) {
+                    // The step applying the operator takes the place of this one.
+                    self.pending_branches.insert(step.depth(), bl);
+                }
+            }
+            RunStep::Op(head, _, tail, None, _, _, _) => {
+                if head.get_number().ok() != 3_i32.to_bigint() {
+                    return;
+                }
+                if let Some(bl) = self.pending_branches.remove(&step.depth()) {
+                    if let SExp::Cons(_, cond, _) = tail.borrow() {
+                        self.coverage.hit_branch(&bl, truthy(cond.clone()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn coverage(&self) -> &Coverage {
+        &self.coverage
+    }
+}
+
+/// Run a program to completion with the stepping interpreter, recording the
+/// coverage of the source locations its subtrees carry.  The coverage is given
+/// even when the run fails.
+pub fn coverage_run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    program: Rc<SExp>,
+    env: Rc<SExp>,
+    max_cost: Option<Cost>,
+) -> (Coverage, Result<Rc<SExp>, RunFailure>) {
+    let mut recorder = CoverageRecorder::new(program.borrow());
+    let mut step = start_step(program, env);
+
+    loop {
+        recorder.observe(&step);
+        let new_step = match run_step(allocator, runner.clone(), prim_map.clone(), &step)
+            .and_then(|s| check_max_cost(&s, max_cost).map(|_| s))
+        {
+            Ok(s) => s,
+            Err(e) => {
+                return (recorder.coverage, Err(e));
+            }
+        };
+
+        if let RunStep::Done(_, x, _) = &new_step {
+            return (recorder.coverage, Ok(x.clone()));
+        }
+        step = new_step;
+    }
+}
@@ -0,0 +1,588 @@
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use serde_json::{json, Value};
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+
+use crate::compiler::cldb::{CldbNoOverride, CldbRunEnv};
+use crate::compiler::clvm::start_step;
+use crate::compiler::compiler::{compile_file, DefaultCompilerOpts};
+use crate::compiler::comptypes::CompilerOpts;
+use crate::compiler::debug::TOPLEVEL_NAME;
+use crate::compiler::debugger::{CldbBreakpoint, CldbDebugger};
+use crate::compiler::prims;
+use crate::compiler::sexp::{parse_sexp, SExp};
+use crate::compiler::srcloc::Srcloc;
+
+// There's one thread of execution in a clvm run.
+const THREAD_ID: i64 = 1;
+
+/// Read one Debug Adapter Protocol message, framed by a Content-Length
+/// header.  Gives None at the end of input.
+pub fn read_dap_message(input: &mut dyn BufRead) -> Result<Option<Value>, String> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        let read = input
+            .read_line(&mut line)
+            .map_err(|e| format!("reading dap header: {e}"))?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length.unwrap_or(0)];
+    input
+        .read_exact(&mut body)
+        .map_err(|e| format!("reading dap message: {e}"))?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| format!("bad dap message: {e}"))
+}
+
+/// Write a Debug Adapter Protocol message with its Content-Length header.
+pub fn write_dap_message(output: &mut dyn Write, message: &Value) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())
+        .and_then(|_| output.flush())
+        .map_err(|e| format!("writing dap message: {e}"))
+}
+
+fn dap_source(loc: &Srcloc) -> Option<Value> {
+    let file: &String = loc.file.borrow();
+    if file.starts_with('*') {
+        return None;
+    }
+    let path = fs::canonicalize(file)
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_else(|_| file.clone());
+    let name = Path::new(file)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| file.clone());
+    Some(json!({ "name": name, "path": path }))
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// A chialisp function as shown to the editor.
+struct DapFrame {
+    name: String,
+    loc: Option<Srcloc>,
+    arguments: Vec<(String, Rc<SExp>)>,
+}
+
+struct DapSession {
+    allocator: Allocator,
+    debugger: CldbDebugger,
+    program_file: String,
+    stop_on_entry: bool,
+    line_breakpoints: HashMap<String, Vec<usize>>,
+    function_breakpoints: Vec<usize>,
+    frames: Vec<DapFrame>,
+}
+
+impl DapSession {
+    fn launch(arguments: &Value) -> Result<Self, String> {
+        let program_file = arguments
+            .get("program")
+            .and_then(|p| p.as_str())
+            .ok_or_else(|| "launch needs a program".to_string())?
+            .to_string();
+        let env_text = arguments
+            .get("args")
+            .and_then(|a| a.as_str())
+            .unwrap_or("()")
+            .to_string();
+        let mut search_paths: Vec<String> = arguments
+            .get("includePaths")
+            .and_then(|i| i.as_array())
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|p| p.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(dir) = Path::new(&program_file).parent() {
+            search_paths.push(dir.to_string_lossy().to_string());
+        }
+
+        let content = fs::read_to_string(&program_file)
+            .map_err(|e| format!("could not read {program_file}: {e}"))?;
+
+        let mut allocator = Allocator::new();
+        let runner = Rc::new(DefaultProgramRunner::new());
+        let opts = Rc::new(DefaultCompilerOpts::new(&program_file)).set_search_paths(&search_paths);
+        let mut symbol_table = HashMap::new();
+        let program = compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts.clone(),
+            &content,
+            &mut symbol_table,
+        )
+        .map_err(|e| format!("{}: {}", e.0, e.1))?;
+        let env = parse_sexp(Srcloc::start("*args*"), env_text.bytes())
+            .map_err(|e| format!("{}: {}", e.0, e.1))?
+            .first()
+            .cloned()
+            .unwrap_or_else(|| Rc::new(SExp::Nil(Srcloc::start("*args*"))));
+
+        let mut cldbenv = CldbRunEnv::new(
+            Some(program_file.clone()),
+            content.lines().map(|l| l.to_string()).collect(),
+            Box::new(CldbNoOverride::new()),
+        );
+        cldbenv.set_symbol_table(symbol_table.clone());
+        let mut debugger = CldbDebugger::new(
+            runner,
+            prims::prim_map(),
+            symbol_table,
+            Box::new(cldbenv),
+            start_step(Rc::new(program), env),
+        );
+        debugger.set_reverse_stepping(true);
+        debugger
+            .set_program_source(opts, &content)
+            .map_err(|e| format!("{}: {}", e.0, e.1))?;
+
+        Ok(DapSession {
+            allocator,
+            debugger,
+            program_file,
+            stop_on_entry: arguments
+                .get("stopOnEntry")
+                .and_then(|s| s.as_bool())
+                .unwrap_or(false),
+            line_breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    fn set_line_breakpoints(&mut self, path: &str, lines: &[usize]) -> Vec<Value> {
+        for id in self.line_breakpoints.remove(path).unwrap_or_default() {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        // Compiled code carries the program's file as it was named at launch.
+        let file = if same_file(path, &self.program_file) {
+            self.program_file.clone()
+        } else {
+            path.to_string()
+        };
+        let mut ids = Vec::new();
+        let mut result = Vec::new();
+        for line in lines.iter() {
+            let breakpoint = CldbBreakpoint::Line {
+                file: file.clone(),
+                line: *line,
+            };
+            if let Ok(id) = self.debugger.add_breakpoint(breakpoint) {
+                ids.push(id);
+                result.push(json!({ "id": id, "verified": true, "line": line }));
+            }
+        }
+        self.line_breakpoints.insert(path.to_string(), ids);
+        result
+    }
+
+    fn set_function_breakpoints(&mut self, names: &[String]) -> Vec<Value> {
+        for id in self.function_breakpoints.drain(..) {
+            self.debugger.remove_breakpoint(id);
+        }
+
+        let mut result = Vec::new();
+        for name in names.iter() {
+            match self
+                .debugger
+                .add_breakpoint(CldbBreakpoint::Function(name.clone()))
+            {
+                Ok(id) => {
+                    self.function_breakpoints.push(id);
+                    result.push(json!({ "id": id, "verified": true }));
+                }
+                Err(e) => {
+                    result.push(json!({ "verified": false, "message": e }));
+                }
+            }
+        }
+        result
+    }
+
+    // Gather the frames to show, innermost first, at the run's current stop.
+    fn collect_frames(&mut self) {
+        let frames = self.debugger.frames();
+        let mut result = Vec::new();
+        let mut loc = self.debugger.location();
+        for frame in frames.iter().rev() {
+            result.push(DapFrame {
+                name: frame.name.clone(),
+                loc: loc.clone(),
+                arguments: self.debugger.frame_arguments(frame),
+            });
+            loc = frame.call_loc.clone();
+        }
+
+        result.push(DapFrame {
+            name: TOPLEVEL_NAME.to_string(),
+            loc,
+            arguments: self.debugger.toplevel_arguments(),
+        });
+        self.frames = result;
+    }
+
+    fn stack_trace(&self) -> Value {
+        let frames: Vec<Value> = self
+            .frames
+            .iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let mut frame = json!({
+                    "id": i,
+                    "name": f.name,
+                    "line": f.loc.as_ref().map(|l| l.line).unwrap_or(0),
+                    "column": f.loc.as_ref().map(|l| l.col).unwrap_or(0),
+                });
+                if let Some(source) = f.loc.as_ref().and_then(dap_source) {
+                    frame["source"] = source;
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": self.frames.len() })
+    }
+
+    fn variables(&self, reference: usize) -> Value {
+        let variables: Vec<Value> = self
+            .frames
+            .get(reference.saturating_sub(1))
+            .map(|f| {
+                f.arguments
+                    .iter()
+                    .map(|(name, value)| {
+                        json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        json!({ "variables": variables })
+    }
+}
+
+/// A Debug Adapter Protocol server for chialisp programs, driving a
+/// CldbDebugger on behalf of an editor.
+///
+/// The launch request takes the path of a chialisp program as "program" and
+/// optionally its environment as clvm text in "args", "includePaths" and
+/// "stopOnEntry".
+pub struct DapServer<'a> {
+    output: &'a mut dyn Write,
+    seq: i64,
+    session: Option<DapSession>,
+}
+
+impl<'a> DapServer<'a> {
+    pub fn new(output: &'a mut dyn Write) -> Self {
+        DapServer {
+            output,
+            seq: 1,
+            session: None,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), String> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_dap_message(self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> Result<(), String> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request.get("seq").cloned().unwrap_or(Value::Null),
+            "command": request.get("command").cloned().unwrap_or(Value::Null),
+        });
+        match result {
+            Ok(body) => {
+                response["success"] = json!(true);
+                response["body"] = body;
+            }
+            Err(e) => {
+                response["success"] = json!(false);
+                response["message"] = json!(e);
+            }
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), String> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn session(&mut self) -> Result<&mut DapSession, String> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    // Tell the editor where the run stopped, or how it ended.
+    fn report(&mut self, rows: Vec<BTreeMap<String, String>>) -> Result<(), String> {
+        let ended = self.session()?.debugger.is_ended();
+        let last = rows.last().cloned().unwrap_or_default();
+        if !ended {
+            let stopped = last.get("Stopped").cloned().unwrap_or_default();
+            let mut body = json!({ "threadId": THREAD_ID, "allThreadsStopped": true });
+            if let Some(id) = stopped.strip_prefix("breakpoint ") {
+                body["reason"] = json!("breakpoint");
+                body["hitBreakpointIds"] = json!([id.parse::<usize>().unwrap_or(0)]);
+            } else {
+                body["reason"] = json!("step");
+            }
+            self.session()?.collect_frames();
+            return self.event("stopped", body);
+        }
+
+        let (category, text, exit_code) = if let Some(value) = last.get("Final") {
+            ("stdout", format!("{value}\n"), 0)
+        } else {
+            let location = last
+                .get("Failure-Location")
+                .or_else(|| last.get("Throw-Location"))
+                .cloned()
+                .unwrap_or_default();
+            let error = last
+                .get("Failure")
+                .or_else(|| last.get("Throw"))
+                .cloned()
+                .unwrap_or_default();
+            ("stderr", format!("{location}: {error}\n"), 1)
+        };
+        self.event("output", json!({ "category": category, "output": text }))?;
+        self.event("exited", json!({ "exitCode": exit_code }))?;
+        self.event("terminated", json!({}))
+    }
+
+    fn motion(&mut self, request: &Value, command: &str) -> Result<(), String> {
+        let session = match self.session() {
+            Ok(s) => s,
+            Err(e) => {
+                return self.respond(request, Err(e));
+            }
+        };
+        let allocator = &mut session.allocator;
+        let rows = match command {
+            "next" => session.debugger.next(allocator),
+            "stepIn" => session.debugger.step(allocator),
+            "stepOut" => session.debugger.finish(allocator),
+            "stepBack" => session.debugger.reverse_step(allocator),
+            "reverseContinue" => session.debugger.reverse_continue(allocator),
+            _ => session.debugger.cont(allocator),
+        };
+        if let Some(e) = rows.first().and_then(|r| r.get("Error")) {
+            let e = e.clone();
+            return self.respond(request, Err(e));
+        }
+
+        let body = if request.get("command") == Some(&json!("continue")) {
+            json!({ "allThreadsContinued": true })
+        } else {
+            json!({})
+        };
+        self.respond(request, Ok(body))?;
+        self.report(rows)
+    }
+
+    /// Handle one request, telling whether the editor has asked to end the
+    /// session.
+    pub fn handle(&mut self, request: &Value) -> Result<bool, String> {
+        let command = request
+            .get("command")
+            .and_then(|c| c.as_str())
+            .unwrap_or("")
+            .to_string();
+        let arguments = request.get("arguments").cloned().unwrap_or(Value::Null);
+
+        match command.as_str() {
+            "initialize" => {
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsFunctionBreakpoints": true,
+                        "supportsStepBack": true,
+                    })),
+                )?;
+            }
+            "launch" => match DapSession::launch(&arguments) {
+                Ok(session) => {
+                    self.session = Some(session);
+                    self.respond(request, Ok(json!({})))?;
+                    self.event("initialized", json!({}))?;
+                }
+                Err(e) => {
+                    self.respond(request, Err(e))?;
+                }
+            },
+            "setBreakpoints" => {
+                let path = arguments
+                    .get("source")
+                    .and_then(|s| s.get("path"))
+                    .and_then(|p| p.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                let lines: Vec<usize> = arguments
+                    .get("breakpoints")
+                    .and_then(|b| b.as_array())
+                    .map(|bps| {
+                        bps.iter()
+                            .filter_map(|b| b.get("line").and_then(|l| l.as_u64()))
+                            .map(|l| l as usize)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let result = self
+                    .session()
+                    .map(|s| json!({ "breakpoints": s.set_line_breakpoints(&path, &lines) }));
+                self.respond(request, result)?;
+            }
+            "setFunctionBreakpoints" => {
+                let names: Vec<String> = arguments
+                    .get("breakpoints")
+                    .and_then(|b| b.as_array())
+                    .map(|bps| {
+                        bps.iter()
+                            .filter_map(|b| b.get("name").and_then(|n| n.as_str()))
+                            .map(|n| n.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let result = self
+                    .session()
+                    .map(|s| json!({ "breakpoints": s.set_function_breakpoints(&names) }));
+                self.respond(request, result)?;
+            }
+            "configurationDone" => {
+                let stop_on_entry = match self.session() {
+                    Ok(s) => s.stop_on_entry,
+                    Err(e) => {
+                        self.respond(request, Err(e))?;
+                        return Ok(false);
+                    }
+                };
+                if stop_on_entry {
+                    self.respond(request, Ok(json!({})))?;
+                    self.session()?.collect_frames();
+                    self.event(
+                        "stopped",
+                        json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+                    )?;
+                } else {
+                    self.motion(request, "continue")?;
+                }
+            }
+            "threads" => {
+                self.respond(
+                    request,
+                    Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+                )?;
+            }
+            "stackTrace" => {
+                let result = self.session().map(|s| s.stack_trace());
+                self.respond(request, result)?;
+            }
+            "scopes" => {
+                let frame = arguments
+                    .get("frameId")
+                    .and_then(|f| f.as_u64())
+                    .unwrap_or(0) as usize;
+                // There is no Locals scope: let forms are compiled inline into
+                // the function they're written in, so the symbol table has no
+                // entries for their bindings and arguments are all there is
+                // to show.
+                self.respond(
+                    request,
+                    Ok(json!({
+                        "scopes": [
+                            { "name": "Arguments", "variablesReference": frame + 1, "expensive": false },
+                        ]
+                    })),
+                )?;
+            }
+            "variables" => {
+                let reference = arguments
+                    .get("variablesReference")
+                    .and_then(|f| f.as_u64())
+                    .unwrap_or(0) as usize;
+                let result = self.session().map(|s| s.variables(reference));
+                self.respond(request, result)?;
+            }
+            "evaluate" => {
+                let expression = arguments
+                    .get("expression")
+                    .and_then(|e| e.as_str())
+                    .unwrap_or("")
+                    .to_string();
+                let result = self.session().and_then(|s| {
+                    s.debugger
+                        .evaluate(&mut s.allocator, &expression)
+                        .map(|v| json!({ "result": v.to_string(), "variablesReference": 0 }))
+                });
+                self.respond(request, result)?;
+            }
+            "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                self.motion(request, &command)?;
+            }
+            "pause" => {
+                // Runs only proceed while a request is being handled.
+                self.respond(request, Ok(json!({})))?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(true);
+            }
+            _ => {
+                self.respond(request, Err(format!("unsupported request {command}")))?;
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Serve the Debug Adapter Protocol over the given input and output until the
+/// editor disconnects or the input ends.
+pub fn serve_dap(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), String> {
+    let mut server = DapServer::new(output);
+    while let Some(request) = read_dap_message(input)? {
+        if server.handle(&request)? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
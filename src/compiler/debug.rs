@@ -170,3 +170,24 @@ pub fn relabel(code_map: &HashMap<String, SExp>, code: &SExp) -> SExp {
     }
     relabel_inner_(code_map, &swap_table, code)
 }
+
+/// The name given to the program itself where a run is described in terms of
+/// the chialisp functions it's inside of.
+pub const TOPLEVEL_NAME: &str = "(main)";
+
+/// Given a symbol table produced by either compiler and the hex treehash of a
+/// program being applied, give the name of the chialisp function it is, if
+/// known.
+///
+/// Symbol tables written for modern programs also map subtree hashes to source
+/// locations, so in those only entries with argument information are functions.
+pub fn function_name_from_symbol_table(
+    symbol_table: &HashMap<String, String>,
+    hash: &str,
+) -> Option<String> {
+    let is_modern = symbol_table.contains_key("__chia__main_arguments");
+    symbol_table
+        .get(hash)
+        .filter(|_| !is_modern || symbol_table.contains_key(&format!("{hash}_arguments")))
+        .cloned()
+}
@@ -0,0 +1,1019 @@
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+use num_bigint::ToBigInt;
+
+use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType};
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+
+use crate::compiler::backtrace::BacktraceFrame;
+use crate::compiler::cldb::{
+    compile_expression, evaluate_with_bindings, format_named_values, left_env_helpers, named_env,
+    program_helpers, CldbEnvironment, CldbRun,
+};
+use crate::compiler::clvm::{run, sha256tree, RunStep};
+use crate::compiler::comptypes::{BodyForm, CompileErr, CompilerOpts};
+use crate::compiler::debug::{function_name_from_symbol_table, TOPLEVEL_NAME};
+use crate::compiler::evaluate::Evaluator;
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::SExp;
+use crate::compiler::srcloc::Srcloc;
+use crate::util::Number;
+
+/// A place where an interactive cldb run stops.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CldbBreakpoint {
+    /// Stop when the named chialisp function is applied.
+    Function(String),
+    /// Stop when the run reaches code from this line of a source file.  The
+    /// file matches when it's the same as the one in a Srcloc or either names
+    /// the last components of the other's path.
+    Line { file: String, line: usize },
+    /// Stop when this chialisp expression, evaluated as a watch is, goes from
+    /// nil (or not having a value) to non-nil.
+    Condition(String),
+}
+
+impl Display for CldbBreakpoint {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CldbBreakpoint::Function(name) => formatter.write_str(name),
+            CldbBreakpoint::Line { file, line } => write!(formatter, "{file}:{line}"),
+            CldbBreakpoint::Condition(expr) => write!(formatter, "if {expr}"),
+        }
+    }
+}
+
+impl CldbBreakpoint {
+    /// Read a breakpoint as given to the break command, either a function name,
+    /// file:line or "if" followed by a condition.
+    pub fn parse(spec: &str) -> Self {
+        if let Some(expr) = spec.strip_prefix("if ") {
+            return CldbBreakpoint::Condition(expr.trim().to_string());
+        }
+        if let Some((file, line)) = spec.rsplit_once(':') {
+            if let Ok(line) = line.parse::<usize>() {
+                return CldbBreakpoint::Line {
+                    file: file.to_string(),
+                    line,
+                };
+            }
+        }
+        CldbBreakpoint::Function(spec.to_string())
+    }
+
+    fn matches_line(&self, loc: &Srcloc) -> bool {
+        if let CldbBreakpoint::Line { file, line } = self {
+            let loc_file: &String = loc.file.borrow();
+            *line == loc.line
+                && (loc_file == file
+                    || loc_file.ends_with(&format!("/{file}"))
+                    || file.ends_with(&format!("/{loc_file}")))
+        } else {
+            false
+        }
+    }
+}
+
+/// A chialisp function application the run is inside of.
+#[derive(Clone, Debug)]
+pub struct CallFrame {
+    pub name: String,
+    pub hash: String,
+    /// The environment the function's body runs in.
+    pub env: Rc<SExp>,
+    /// The last source location reached by the caller before the function
+    /// was applied.
+    pub call_loc: Option<Srcloc>,
+    // The depth of the step this function's result returns to.
+    base: usize,
+}
+
+/// Tracks the chialisp functions a run is inside of, using a symbol table to
+/// recognize when a function is applied, along with the last source location
+/// the run reached.
+pub struct CallStack {
+    symbol_table: HashMap<String, String>,
+    frames: Vec<CallFrame>,
+    location: Option<Srcloc>,
+}
+
+impl CallStack {
+    pub fn new(symbol_table: HashMap<String, String>) -> Self {
+        CallStack {
+            symbol_table,
+            frames: Vec::new(),
+            location: None,
+        }
+    }
+
+    /// Account for a step the run is about to take, giving the frame pushed if
+    /// the step applies a known function.
+    pub fn observe(&mut self, step: &RunStep) -> Option<&CallFrame> {
+        let depth = step.depth();
+        while self.frames.last().map(|f| f.base >= depth).unwrap_or(false) {
+            self.frames.pop();
+        }
+
+        let call_loc = self.location.clone();
+        if let Some(loc) = source_location(step) {
+            self.location = Some(loc);
+        }
+
+        let (program, env) = step.applied_program()?;
+        let parent = step.parent()?;
+        let hash = Bytes::new(Some(BytesFromType::Raw(sha256tree(program)))).hex();
+        let name = function_name_from_symbol_table(&self.symbol_table, &hash)?;
+        self.frames.push(CallFrame {
+            name,
+            hash,
+            env,
+            call_loc,
+            base: parent.depth(),
+        });
+        self.frames.last()
+    }
+
+    /// The frames, outermost first.
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    /// The last source location the run reached.
+    pub fn location(&self) -> Option<Srcloc> {
+        self.location.clone()
+    }
+
+    /// The arguments of a function frame by name, when the symbol table records
+    /// its argument list.
+    pub fn frame_arguments(&self, frame: &CallFrame) -> Vec<(String, Rc<SExp>)> {
+        let left_env = self
+            .symbol_table
+            .contains_key(&format!("{}_left_env", frame.hash));
+        named_env(
+            &self.symbol_table,
+            &format!("{}_arguments", frame.hash),
+            left_env,
+            frame.env.clone(),
+        )
+    }
+
+    /// The functions the run is inside of, innermost first, ending with the
+    /// program itself, which was run with toplevel_env.
+    pub fn backtrace(&self, toplevel_env: Rc<SExp>) -> Vec<BacktraceFrame> {
+        let mut location = self.location.clone();
+        let mut result = Vec::new();
+        for frame in self.frames.iter().rev() {
+            result.push(BacktraceFrame {
+                name: frame.name.clone(),
+                hash: Some(frame.hash.clone()),
+                env: frame.env.clone(),
+                arguments: self.frame_arguments(frame),
+                loc: location,
+            });
+            location = frame.call_loc.clone();
+        }
+        result.push(BacktraceFrame {
+            name: TOPLEVEL_NAME.to_string(),
+            hash: None,
+            env: toplevel_env.clone(),
+            arguments: named_env(
+                &self.symbol_table,
+                "__chia__main_arguments",
+                false,
+                toplevel_env,
+            ),
+            loc: location,
+        });
+        result
+    }
+}
+
+// The source location of a step evaluating an expression from a source file,
+// leaving out quoted values and code the compiler made up.
+fn source_location(step: &RunStep) -> Option<Srcloc> {
+    if let RunStep::Step(sexp, _, _, _, _) = step {
+        let loc = sexp.loc();
+        if matches!(sexp.borrow(), SExp::Cons(_, _, _))
+            && !is_quote_form(sexp)
+            && !loc.file.starts_with('*')
+        {
+            return Some(loc);
+        }
+    }
+    None
+}
+
+fn is_quote_form(sexp: &SExp) -> bool {
+    if let SExp::Cons(_, head, _) = sexp {
+        head.get_number().ok() == 1_i32.to_bigint()
+    } else {
+        false
+    }
+}
+
+// What a step did that breakpoints can be hit by.
+#[derive(Clone, Debug, Default)]
+struct StepEvents {
+    entered: Option<String>,
+    new_line: Option<Srcloc>,
+    condition: Option<usize>,
+}
+
+struct DebuggerState {
+    call_stack: CallStack,
+    breakpoints: BTreeMap<usize, CldbBreakpoint>,
+    next_breakpoint: usize,
+    last_line: Option<(String, usize)>,
+    events: StepEvents,
+    // Whether each conditional breakpoint's condition held at the last row,
+    // once it has been evaluated.
+    conditions_held: BTreeMap<usize, bool>,
+}
+
+// The debugger's view of the run before a step, so it can be taken back.
+struct DebuggerSnapshot {
+    frames: Vec<CallFrame>,
+    location: Option<Srcloc>,
+    last_line: Option<(String, usize)>,
+    events: StepEvents,
+    conditions_held: BTreeMap<usize, bool>,
+}
+
+impl DebuggerState {
+    fn observe(&mut self, step: &RunStep) {
+        self.events = StepEvents::default();
+        if let Some(name) = self.call_stack.observe(step).map(|f| f.name.clone()) {
+            // Re-entering code on the same line in a new call is a new visit.
+            self.last_line = None;
+            self.events.entered = Some(name);
+        }
+
+        if let Some(loc) = source_location(step) {
+            let line = (loc.file.to_string(), loc.line);
+            if self.last_line.as_ref() == Some(&line) {
+                return;
+            }
+            self.last_line = Some(line);
+            self.events.new_line = Some(loc);
+        }
+    }
+
+    fn breakpoint_hit(&self, events: &StepEvents) -> Option<usize> {
+        self.breakpoints.iter().find_map(|(id, bp)| {
+            let function_hit = events
+                .entered
+                .as_ref()
+                .map(|name| *bp == CldbBreakpoint::Function(name.clone()))
+                .unwrap_or(false);
+            let line_hit = events
+                .new_line
+                .as_ref()
+                .map(|loc| bp.matches_line(loc))
+                .unwrap_or(false);
+            let condition_hit = events.condition == Some(*id);
+            if function_hit || line_hit || condition_hit {
+                Some(*id)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn snapshot(&self) -> DebuggerSnapshot {
+        DebuggerSnapshot {
+            frames: self.call_stack.frames.clone(),
+            location: self.call_stack.location.clone(),
+            last_line: self.last_line.clone(),
+            events: StepEvents::default(),
+            conditions_held: self.conditions_held.clone(),
+        }
+    }
+
+    fn restore(&mut self, snapshot: DebuggerSnapshot) {
+        self.call_stack.frames = snapshot.frames;
+        self.call_stack.location = snapshot.location;
+        self.last_line = snapshot.last_line;
+        self.events = StepEvents::default();
+        self.conditions_held = snapshot.conditions_held;
+    }
+}
+
+/// A CldbEnvironment which watches each step before it's run to keep the
+/// debugger's call stack and notice breakpoints, and otherwise defers to the
+/// environment it wraps.
+pub struct CldbDebuggerEnv {
+    inner: Box<dyn CldbEnvironment>,
+    state: Rc<RefCell<DebuggerState>>,
+}
+
+impl CldbEnvironment for CldbDebuggerEnv {
+    fn add_context(
+        &self,
+        s: &SExp,
+        c: &SExp,
+        args: Option<Rc<SExp>>,
+        context_result: &mut BTreeMap<String, String>,
+    ) {
+        self.inner.add_context(s, c, args, context_result);
+    }
+
+    fn add_function(&self, s: &SExp, context_result: &mut BTreeMap<String, String>) {
+        self.inner.add_function(s, context_result);
+    }
+
+    fn get_override(&self, s: &RunStep) -> Option<Result<RunStep, RunFailure>> {
+        self.state.borrow_mut().observe(s);
+        self.inner.get_override(s)
+    }
+
+    fn previous_env(&self) -> Option<Rc<SExp>> {
+        self.inner.previous_env()
+    }
+
+    fn set_previous_env(&self, env: Option<Rc<SExp>>) {
+        self.inner.set_previous_env(env);
+    }
+}
+
+fn message_row(key: &str, value: String) -> BTreeMap<String, String> {
+    let mut row = BTreeMap::new();
+    row.insert(key.to_string(), value);
+    row
+}
+
+fn reverse_stepping_off() -> BTreeMap<String, String> {
+    message_row("Error", "reverse stepping isn't turned on".to_string())
+}
+
+/// Drives a CldbRun on behalf of a user, with commands to step into and over
+/// functions, run to breakpoints and examine the environment and call stack.
+///
+/// Each command gives rows in the same form as CldbRun::step, so that they
+/// can be shown the same way.  Motion commands give the last row the run
+/// produced, if any, followed by a row saying where and why the run stopped.
+///
+/// When reverse stepping is turned on, the run can also be moved backwards,
+/// since every step taken through the debugger can then be taken back.
+///
+/// Given the program's chialisp source, the debugger can also evaluate
+/// expressions where the run is, using the program's functions and the
+/// arguments in scope.  Watched expressions are shown with every row.
+pub struct CldbDebugger {
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    symbol_table: HashMap<String, String>,
+    toplevel_env: Rc<SExp>,
+    state: Rc<RefCell<DebuggerState>>,
+    history: Vec<DebuggerSnapshot>,
+    reverse_stepping: bool,
+    run: CldbRun,
+    source: Option<(Rc<dyn CompilerOpts>, Evaluator)>,
+    watches: BTreeMap<usize, (String, Rc<BodyForm>)>,
+    next_watch: usize,
+    conditions: BTreeMap<usize, Rc<BodyForm>>,
+}
+
+impl CldbDebugger {
+    /// Create a debugger for a program starting at step, whose function names
+    /// are in symbol_table.  The given environment is consulted as CldbRun
+    /// would.
+    pub fn new(
+        runner: Rc<dyn TRunProgram>,
+        prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+        symbol_table: HashMap<String, String>,
+        env: Box<dyn CldbEnvironment>,
+        step: RunStep,
+    ) -> Self {
+        let toplevel_env = if let RunStep::Step(_, context, _, _, _) = &step {
+            context.clone()
+        } else {
+            Rc::new(SExp::Nil(step.sexp().loc()))
+        };
+        let state = Rc::new(RefCell::new(DebuggerState {
+            call_stack: CallStack::new(symbol_table.clone()),
+            breakpoints: BTreeMap::new(),
+            next_breakpoint: 1,
+            last_line: None,
+            events: StepEvents::default(),
+            conditions_held: BTreeMap::new(),
+        }));
+        let debugger_env = CldbDebuggerEnv {
+            inner: env,
+            state: state.clone(),
+        };
+        let run = CldbRun::new(
+            runner.clone(),
+            prim_map.clone(),
+            Box::new(debugger_env),
+            step,
+        );
+        CldbDebugger {
+            runner,
+            prim_map,
+            symbol_table,
+            toplevel_env,
+            state,
+            history: Vec::new(),
+            reverse_stepping: false,
+            run,
+            source: None,
+            watches: BTreeMap::new(),
+            next_watch: 1,
+            conditions: BTreeMap::new(),
+        }
+    }
+
+    /// Give the chialisp source the program was compiled from, so expressions
+    /// can use the functions, macros and constants it defines.
+    pub fn set_program_source(
+        &mut self,
+        opts: Rc<dyn CompilerOpts>,
+        source: &str,
+    ) -> Result<(), CompileErr> {
+        let helpers = program_helpers(opts.clone(), source)?;
+        let evaluator = Evaluator::new(opts.clone(), self.runner.clone(), helpers);
+        self.source = Some((opts, evaluator));
+        Ok(())
+    }
+
+    /// The underlying run, for reading its rows and result.  Steps must be
+    /// taken through the debugger so they can be taken back.
+    pub fn run(&self) -> &CldbRun {
+        &self.run
+    }
+
+    pub fn set_max_cost(&mut self, max_cost: Option<Cost>) {
+        self.run.set_max_cost(max_cost);
+    }
+
+    /// Keep what's needed to move the run backwards and to find the origin
+    /// of values.  It's kept for every step taken after this is turned on.
+    pub fn set_reverse_stepping(&mut self, reverse_stepping: bool) {
+        self.reverse_stepping = reverse_stepping;
+        self.run.set_keep_history(reverse_stepping);
+    }
+
+    /// Take one step of the run as CldbRun::step does, adding the value of each
+    /// watch to the row produced.
+    pub fn advance(&mut self, allocator: &mut Allocator) -> Option<BTreeMap<String, String>> {
+        self.start_conditions(allocator);
+        let snapshot = self
+            .reverse_stepping
+            .then(|| RefCell::borrow(&self.state).snapshot());
+        let mut row = self.run.step(allocator);
+        if self.run.is_ended() && self.run.final_result().is_some() {
+            // The program has returned from every function it was in.
+            self.state.borrow_mut().call_stack.frames.clear();
+        }
+        if let Some(r) = row.as_mut() {
+            self.add_watch_values(allocator, r);
+            let condition = self.check_conditions(allocator);
+            self.state.borrow_mut().events.condition = condition;
+        }
+        if let Some(mut snapshot) = snapshot {
+            snapshot.events = RefCell::borrow(&self.state).events.clone();
+            self.history.push(snapshot);
+        }
+        row
+    }
+
+    // Take back one step, giving what it did if there was one.
+    fn retreat(&mut self) -> Option<StepEvents> {
+        if !self.run.step_back() {
+            return None;
+        }
+        let snapshot = self.history.pop()?;
+        let events = snapshot.events.clone();
+        self.state.borrow_mut().restore(snapshot);
+        Some(events)
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.run.is_ended()
+    }
+
+    /// Add a breakpoint, giving its number.  Function breakpoints must name a
+    /// function in the symbol table, and conditions must compile.
+    pub fn add_breakpoint(&mut self, breakpoint: CldbBreakpoint) -> Result<usize, String> {
+        let mut condition = None;
+        match &breakpoint {
+            CldbBreakpoint::Function(name) => {
+                let known = self.symbol_table.iter().any(|(hash, v)| {
+                    v == name && function_name_from_symbol_table(&self.symbol_table, hash).is_some()
+                });
+                if !known {
+                    return Err(format!("no function named {name} in the symbol table"));
+                }
+            }
+            CldbBreakpoint::Condition(expr) => {
+                condition = Some(self.compile_expression(expr)?);
+            }
+            _ => {}
+        }
+
+        let mut state = self.state.borrow_mut();
+        let id = state.next_breakpoint;
+        state.next_breakpoint += 1;
+        state.breakpoints.insert(id, breakpoint);
+        if let Some(body) = condition {
+            self.conditions.insert(id, body);
+        }
+        Ok(id)
+    }
+
+    /// Remove a breakpoint by number, telling whether it existed.
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        self.conditions.remove(&id);
+        let mut state = self.state.borrow_mut();
+        state.conditions_held.remove(&id);
+        state.breakpoints.remove(&id).is_some()
+    }
+
+    fn compile_expression(&self, expr: &str) -> Result<Rc<BodyForm>, String> {
+        let opts = if let Some((opts, _)) = &self.source {
+            opts.clone()
+        } else {
+            return Err("expressions need the program's chialisp source".to_string());
+        };
+        compile_expression(opts, expr)
+    }
+
+    fn evaluate_body(
+        &self,
+        allocator: &mut Allocator,
+        body: Rc<BodyForm>,
+    ) -> Result<Rc<SExp>, String> {
+        let evaluator = if let Some((_, evaluator)) = &self.source {
+            evaluator
+        } else {
+            return Err("expressions need the program's chialisp source".to_string());
+        };
+        let scope = if let Some(frame) = self.frames().last() {
+            self.frame_arguments(frame)
+        } else {
+            self.toplevel_arguments()
+        };
+        evaluate_with_bindings(allocator, evaluator, &scope, body)
+    }
+
+    /// Evaluate a chialisp expression where the run is, with the arguments of
+    /// the innermost function (or of the program, outside of any) in scope.
+    pub fn evaluate(&self, allocator: &mut Allocator, expr: &str) -> Result<Rc<SExp>, String> {
+        let body = self.compile_expression(expr)?;
+        self.evaluate_body(allocator, body)
+    }
+
+    /// Watch an expression, giving its number.
+    pub fn add_watch(&mut self, expr: &str) -> Result<usize, String> {
+        let body = self.compile_expression(expr)?;
+        let id = self.next_watch;
+        self.next_watch += 1;
+        self.watches.insert(id, (expr.to_string(), body));
+        Ok(id)
+    }
+
+    /// Stop watching an expression by number, telling whether it was watched.
+    pub fn remove_watch(&mut self, id: usize) -> bool {
+        self.watches.remove(&id).is_some()
+    }
+
+    pub fn watches(&self) -> Vec<(usize, String)> {
+        self.watches
+            .iter()
+            .map(|(id, (expr, _))| (*id, expr.clone()))
+            .collect()
+    }
+
+    // Add a "Watch <expression>" key with each watch's value to a row.
+    fn add_watch_values(&self, allocator: &mut Allocator, row: &mut BTreeMap<String, String>) {
+        for (expr, body) in self.watches.values() {
+            let value = match self.evaluate_body(allocator, body.clone()) {
+                Ok(v) => v.to_string(),
+                Err(e) => format!("error: {e}"),
+            };
+            row.insert(format!("Watch {expr}"), value);
+        }
+    }
+
+    fn condition_holds(&self, allocator: &mut Allocator, body: Rc<BodyForm>) -> bool {
+        self.evaluate_body(allocator, body)
+            .map(|v| !v.nilp())
+            .unwrap_or(false)
+    }
+
+    // Record whether new conditions hold before the run moves, so they are hit
+    // only once they change.
+    fn start_conditions(&mut self, allocator: &mut Allocator) {
+        for (id, body) in self.conditions.iter() {
+            if RefCell::borrow(&self.state)
+                .conditions_held
+                .contains_key(id)
+            {
+                continue;
+            }
+            let held = self.condition_holds(allocator, body.clone());
+            self.state.borrow_mut().conditions_held.insert(*id, held);
+        }
+    }
+
+    // Give the first conditional breakpoint whose condition has begun to hold.
+    fn check_conditions(&mut self, allocator: &mut Allocator) -> Option<usize> {
+        let mut hit = None;
+        for (id, body) in self.conditions.iter() {
+            let held = self.condition_holds(allocator, body.clone());
+            let before = self.state.borrow_mut().conditions_held.insert(*id, held);
+            if held && before == Some(false) && hit.is_none() {
+                hit = Some(*id);
+            }
+        }
+        hit
+    }
+
+    pub fn breakpoints(&self) -> Vec<(usize, CldbBreakpoint)> {
+        RefCell::borrow(&self.state)
+            .breakpoints
+            .iter()
+            .map(|(id, bp)| (*id, bp.clone()))
+            .collect()
+    }
+
+    /// The last source location the run reached.
+    pub fn location(&self) -> Option<Srcloc> {
+        RefCell::borrow(&self.state).call_stack.location()
+    }
+
+    /// The functions the run is inside of, outermost first.
+    pub fn frames(&self) -> Vec<CallFrame> {
+        RefCell::borrow(&self.state).call_stack.frames().to_vec()
+    }
+
+    /// The arguments of a function frame by name, when the symbol table records
+    /// its argument list.
+    pub fn frame_arguments(&self, frame: &CallFrame) -> Vec<(String, Rc<SExp>)> {
+        RefCell::borrow(&self.state)
+            .call_stack
+            .frame_arguments(frame)
+    }
+
+    /// The arguments of the program itself by name.
+    pub fn toplevel_arguments(&self) -> Vec<(String, Rc<SExp>)> {
+        named_env(
+            &self.symbol_table,
+            "__chia__main_arguments",
+            false,
+            self.toplevel_env.clone(),
+        )
+    }
+
+    /// The environment code is running in: the innermost function's or, outside
+    /// of any function, the program's.
+    pub fn current_env(&self) -> Rc<SExp> {
+        RefCell::borrow(&self.state)
+            .call_stack
+            .frames()
+            .last()
+            .map(|f| f.env.clone())
+            .unwrap_or_else(|| self.toplevel_env.clone())
+    }
+
+    /// Look up an environment path (as in clvm, 1 being the whole environment)
+    /// in the current environment.
+    pub fn lookup(&self, allocator: &mut Allocator, path: Number) -> Result<Rc<SExp>, RunFailure> {
+        let env = self.current_env();
+        run(
+            allocator,
+            self.runner.clone(),
+            self.prim_map.clone(),
+            Rc::new(SExp::Integer(env.loc(), path)),
+            env,
+            None,
+        )
+    }
+
+    /// The functions the run is inside of, innermost first, ending with the
+    /// program itself.
+    pub fn backtrace_frames(&self) -> Vec<BacktraceFrame> {
+        RefCell::borrow(&self.state)
+            .call_stack
+            .backtrace(self.toplevel_env.clone())
+    }
+
+    /// One row per function the run is inside of, innermost first, ending with
+    /// the program itself.  Function arguments are shown by name when they're
+    /// known, with the functions in the left environment apart from them.
+    pub fn backtrace(&self) -> Vec<BTreeMap<String, String>> {
+        let mut result = Vec::new();
+        for (i, frame) in self.backtrace_frames().iter().enumerate() {
+            let mut row = BTreeMap::new();
+            row.insert("Frame".to_string(), i.to_string());
+            row.insert("Function".to_string(), frame.name.clone());
+            if frame.arguments.is_empty() {
+                row.insert("Arguments".to_string(), frame.env.to_string());
+            } else {
+                row.insert(
+                    "Arguments".to_string(),
+                    format_named_values(&frame.arguments),
+                );
+                if let (Some(hash), SExp::Cons(_, left, _)) = (&frame.hash, frame.env.borrow()) {
+                    if self.symbol_table.contains_key(&format!("{hash}_left_env")) {
+                        let helpers = left_env_helpers(&self.symbol_table, left.clone());
+                        row.insert("Left-Env".to_string(), helpers.join(" "));
+                    }
+                }
+            }
+            if let Some(l) = &frame.loc {
+                row.insert("Location".to_string(), l.to_string());
+            }
+            result.push(row);
+        }
+        result
+    }
+
+    /// The backtrace at the point the run failed, or nothing if it hasn't.
+    pub fn failure_backtrace(&self) -> Vec<BTreeMap<String, String>> {
+        if self.run.is_ended() && self.run.final_result().is_none() {
+            self.backtrace()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn frame_count(&self) -> usize {
+        RefCell::borrow(&self.state).call_stack.frames().len()
+    }
+
+    // Step the run until stop says to given the frame count and whether a row
+    // was produced, a breakpoint is hit or the run ends.
+    fn run_until(
+        &mut self,
+        allocator: &mut Allocator,
+        reason: &str,
+        stop: &dyn Fn(usize, bool) -> bool,
+    ) -> Vec<BTreeMap<String, String>> {
+        if self.run.is_ended() {
+            return vec![message_row("Error", "the run has ended".to_string())];
+        }
+
+        let mut last_row = None;
+        let mut stop_reason = reason.to_string();
+        while !self.run.is_ended() {
+            let row = self.advance(allocator);
+            let produced = row.is_some();
+            if row.is_some() {
+                last_row = row;
+            }
+            let hit = {
+                let state = RefCell::borrow(&self.state);
+                state.breakpoint_hit(&state.events)
+            };
+            if let Some(id) = hit {
+                stop_reason = format!("breakpoint {id}");
+                break;
+            }
+            if stop(self.frame_count(), produced) {
+                break;
+            }
+        }
+
+        let mut result: Vec<BTreeMap<String, String>> = last_row.into_iter().collect();
+        if !self.run.is_ended() {
+            result.push(self.stopped_row(stop_reason));
+        }
+        result.append(&mut self.failure_backtrace());
+        result
+    }
+
+    fn stopped_row(&self, reason: String) -> BTreeMap<String, String> {
+        let mut stopped = message_row("Stopped", reason);
+        if let Some(l) = self.location() {
+            stopped.insert("Location".to_string(), l.to_string());
+        }
+        let function = self
+            .frames()
+            .last()
+            .map(|f| f.name.clone())
+            .unwrap_or_else(|| TOPLEVEL_NAME.to_string());
+        stopped.insert("Function".to_string(), function);
+        stopped
+    }
+
+    // Give the last row produced and where the run is after moving backwards.
+    fn reverse_result(
+        &self,
+        allocator: &mut Allocator,
+        reason: String,
+    ) -> Vec<BTreeMap<String, String>> {
+        let mut result = Vec::new();
+        if let Some(mut row) = self.run.rows().last().cloned() {
+            self.add_watch_values(allocator, &mut row);
+            result.push(row);
+        }
+        let reason = if self.history.is_empty() {
+            "start".to_string()
+        } else {
+            reason
+        };
+        result.push(self.stopped_row(reason));
+        result
+    }
+
+    /// Move back to where step would have stopped before the last row was
+    /// produced.
+    pub fn reverse_step(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        if !self.reverse_stepping {
+            return vec![reverse_stepping_off()];
+        }
+        if self.history.is_empty() {
+            return vec![message_row("Error", "at the start of the run".to_string())];
+        }
+
+        self.retreat();
+        let row = self.run.row();
+        while self.retreat().is_some() {
+            if self.run.row() < row {
+                self.advance(allocator);
+                break;
+            }
+        }
+        self.reverse_result(allocator, "reverse-step".to_string())
+    }
+
+    /// Move back to the last place a breakpoint was hit, or the start.
+    pub fn reverse_continue(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        if !self.reverse_stepping {
+            return vec![reverse_stepping_off()];
+        }
+        if self.history.is_empty() {
+            return vec![message_row("Error", "at the start of the run".to_string())];
+        }
+
+        // The step the run stopped after doesn't count, so that repeating
+        // this keeps going back.
+        self.retreat();
+        while let Some(events) = self.retreat() {
+            let hit = RefCell::borrow(&self.state).breakpoint_hit(&events);
+            if let Some(id) = hit {
+                // Stop where going forward would have.
+                self.advance(allocator);
+                return self.reverse_result(allocator, format!("breakpoint {id}"));
+            }
+        }
+        self.reverse_result(allocator, "start".to_string())
+    }
+
+    /// Where a value came from, as rows starting with the one that produced it
+    /// and going back through the rows that produced its inputs.
+    pub fn origin(&self, value: &Number) -> Vec<BTreeMap<String, String>> {
+        if !self.reverse_stepping {
+            return vec![reverse_stepping_off()];
+        }
+        let rows = self.run.value_origin(value);
+        if rows.is_empty() {
+            return vec![message_row("Error", format!("no row produced {value}"))];
+        }
+        rows
+    }
+
+    /// Run until the next row is produced, going into functions.
+    pub fn step(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        self.run_until(allocator, "step", &|_, produced| produced)
+    }
+
+    /// Run until the next row produced outside of any function applied in the
+    /// meantime.
+    pub fn next(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        let frames = self.frame_count();
+        self.run_until(allocator, "next", &|now, produced| {
+            produced && now <= frames
+        })
+    }
+
+    /// Run until the current function returns.
+    pub fn finish(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        let frames = self.frame_count();
+        self.run_until(allocator, "finish", &|now, _| now < frames)
+    }
+
+    /// Run until a breakpoint is hit or the run ends.
+    pub fn cont(&mut self, allocator: &mut Allocator) -> Vec<BTreeMap<String, String>> {
+        self.run_until(allocator, "continue", &|_, _| false)
+    }
+
+    /// Perform a command as typed by the user, such as "break fact" or
+    /// "print 5".
+    pub fn command(
+        &mut self,
+        allocator: &mut Allocator,
+        line: &str,
+    ) -> Vec<BTreeMap<String, String>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            return vec![];
+        }
+        // Everything after the command, for commands taking an expression.
+        let rest = line.trim_start()[words[0].len()..].trim();
+
+        match (words[0], words.len()) {
+            ("s", 1) | ("step", 1) => self.step(allocator),
+            ("n", 1) | ("next", 1) => self.next(allocator),
+            ("f", 1) | ("finish", 1) => self.finish(allocator),
+            ("c", 1) | ("continue", 1) => self.cont(allocator),
+            ("rs", 1) | ("reverse-step", 1) => self.reverse_step(allocator),
+            ("rc", 1) | ("reverse-continue", 1) => self.reverse_continue(allocator),
+            ("o", 2) | ("origin", 2) => match words[1].parse::<Number>() {
+                Ok(n) => self.origin(&n),
+                Err(_) => vec![message_row(
+                    "Error",
+                    format!("origin needs a number, not {}", words[1]),
+                )],
+            },
+            ("b", _) | ("break", _) if words.len() > 1 => {
+                let breakpoint = CldbBreakpoint::parse(rest);
+                match self.add_breakpoint(breakpoint.clone()) {
+                    Ok(id) => {
+                        let mut row = message_row("Breakpoint", id.to_string());
+                        row.insert("Location".to_string(), breakpoint.to_string());
+                        vec![row]
+                    }
+                    Err(e) => vec![message_row("Error", e)],
+                }
+            }
+            ("b", 1) | ("break", 1) => self
+                .breakpoints()
+                .iter()
+                .map(|(id, bp)| {
+                    let mut row = message_row("Breakpoint", id.to_string());
+                    row.insert("Location".to_string(), bp.to_string());
+                    row
+                })
+                .collect(),
+            ("d", 2) | ("delete", 2) => match words[1].parse::<usize>() {
+                Ok(id) if self.remove_breakpoint(id) => {
+                    vec![message_row("Deleted", id.to_string())]
+                }
+                _ => vec![message_row("Error", format!("no breakpoint {}", words[1]))],
+            },
+            ("p", _) | ("print", _) if words.len() <= 2 => {
+                let path = if words.len() == 2 {
+                    match words[1].parse::<Number>() {
+                        Ok(p) => p,
+                        Err(_) => {
+                            return vec![message_row(
+                                "Error",
+                                format!("bad environment path {}", words[1]),
+                            )];
+                        }
+                    }
+                } else {
+                    1_i32.to_bigint().unwrap()
+                };
+                match self.lookup(allocator, path.clone()) {
+                    Ok(v) => {
+                        let mut row = message_row("Path", path.to_string());
+                        row.insert("Value".to_string(), v.to_string());
+                        vec![row]
+                    }
+                    Err(e) => vec![message_row("Error", e.to_string())],
+                }
+            }
+            ("bt", 1) | ("backtrace", 1) => self.backtrace(),
+            ("w", 1) | ("watch", 1) => self
+                .watches()
+                .iter()
+                .map(|(id, expr)| {
+                    let mut row = message_row("Watch", id.to_string());
+                    row.insert("Expression".to_string(), expr.clone());
+                    row
+                })
+                .collect(),
+            ("w", _) | ("watch", _) => match self.add_watch(rest) {
+                Ok(id) => {
+                    let mut row = message_row("Watch", id.to_string());
+                    row.insert("Expression".to_string(), rest.to_string());
+                    match self.evaluate(allocator, rest) {
+                        Ok(v) => row.insert("Value".to_string(), v.to_string()),
+                        Err(e) => row.insert("Error".to_string(), e),
+                    };
+                    vec![row]
+                }
+                Err(e) => vec![message_row("Error", e)],
+            },
+            ("uw", 2) | ("unwatch", 2) => match words[1].parse::<usize>() {
+                Ok(id) if self.remove_watch(id) => {
+                    vec![message_row("Unwatched", id.to_string())]
+                }
+                _ => vec![message_row("Error", format!("no watch {}", words[1]))],
+            },
+            _ => vec![message_row(
+                "Error",
+                format!("unknown command {}", line.trim()),
+            )],
+        }
+    }
+}
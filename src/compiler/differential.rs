@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+use crate::compiler::clvm::{run_on_clvmr, sha256tree};
+use crate::compiler::compiler::compile_pre_forms;
+use crate::compiler::comptypes::{BodyForm, CompileErr, CompilerOpts};
+use crate::compiler::evaluate::{argument_captures_for_env, Evaluator, EVAL_STACK_LIMIT};
+use crate::compiler::frontend::frontend;
+use crate::compiler::sexp::SExp;
+
+/// Whether the Evaluator and the compiled code agreed on a program's result
+/// for an input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Both gave the same value, or both failed.
+    Agree,
+    /// They gave different values, or only one of them failed.
+    Mismatch,
+    /// The Evaluator couldn't reduce the program to a constant, so there's
+    /// nothing to compare.
+    Inconclusive,
+}
+
+/// The outcomes of the Evaluator and of running the compiled code for one
+/// input, and whether they agree.
+#[derive(Debug, Clone)]
+pub struct DifferentialResult {
+    pub input: Rc<SExp>,
+    /// The constant the Evaluator reduced the program to, or why it couldn't.
+    pub evaluated: Result<Rc<SExp>, String>,
+    /// The result and cost of running the compiled code, or its failure.
+    pub compiled: Result<(Cost, Rc<SExp>), String>,
+    pub verdict: Verdict,
+}
+
+impl Display for DifferentialResult {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let evaluated = match &self.evaluated {
+            Ok(v) => v.to_string(),
+            Err(e) => format!("failed: {e}"),
+        };
+        let compiled = match &self.compiled {
+            Ok((cost, v)) => format!("{v} (cost {cost})"),
+            Err(e) => format!("failed: {e}"),
+        };
+        match self.verdict {
+            Verdict::Agree => write!(formatter, "AGREE {}: {compiled}", self.input),
+            Verdict::Mismatch => write!(
+                formatter,
+                "MISMATCH {}\n  evaluator: {evaluated}\n  compiled:  {compiled}",
+                self.input
+            ),
+            Verdict::Inconclusive => write!(
+                formatter,
+                "INCONCLUSIVE {}\n  evaluator: {evaluated}\n  compiled:  {compiled}",
+                self.input
+            ),
+        }
+    }
+}
+
+fn run_compiled(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    code: Rc<SExp>,
+    input: Rc<SExp>,
+) -> Result<(Cost, Rc<SExp>), String> {
+    run_on_clvmr(allocator, runner, code, input).map_err(|e| CompileErr::from(e).1)
+}
+
+/// Run a program for each input both ways chialisp can be given meaning
+/// here: by simplifying it with the frontend's Evaluator, shrink_bodyform,
+/// down to a constant, and by compiling it and running the code on clvmr.
+/// Differences point to a bug in one of them, such as in the frontend
+/// optimizer when opts enables it.
+pub fn differential_run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    opts: Rc<dyn CompilerOpts>,
+    program: &[Rc<SExp>],
+    inputs: &[Rc<SExp>],
+) -> Result<Vec<DifferentialResult>, CompileErr> {
+    let code = Rc::new(compile_pre_forms(
+        allocator,
+        runner.clone(),
+        opts.clone(),
+        program,
+        &mut HashMap::new(),
+    )?);
+    let form = frontend(opts.clone(), program)?;
+    let evaluator = Evaluator::new(opts, runner.clone(), form.helpers.clone());
+
+    let mut results = Vec::new();
+    for input in inputs.iter() {
+        let evaluated = argument_captures_for_env(form.args.clone(), input)
+            .and_then(|captures| {
+                evaluator.shrink_bodyform(
+                    allocator,
+                    form.args.clone(),
+                    &captures,
+                    form.exp.clone(),
+                    false,
+                    Some(EVAL_STACK_LIMIT),
+                )
+            })
+            .map_err(|CompileErr(l, e)| format!("{l}: {e}"));
+        let compiled = run_compiled(allocator, runner.clone(), code.clone(), input.clone());
+
+        let (evaluated, verdict) = match evaluated {
+            Ok(bf) => {
+                if let BodyForm::Quoted(v) = bf.as_ref() {
+                    let v = Rc::new(v.clone());
+                    let verdict = match &compiled {
+                        Ok((_, r)) if sha256tree(r.clone()) == sha256tree(v.clone()) => {
+                            Verdict::Agree
+                        }
+                        _ => Verdict::Mismatch,
+                    };
+                    (Ok(v), verdict)
+                } else {
+                    (
+                        Err(format!("not a constant: {}", bf.to_sexp())),
+                        Verdict::Inconclusive,
+                    )
+                }
+            }
+            Err(e) => {
+                let verdict = if compiled.is_err() {
+                    Verdict::Agree
+                } else {
+                    Verdict::Mismatch
+                };
+                (Err(e), verdict)
+            }
+        };
+
+        results.push(DifferentialResult {
+            input: input.clone(),
+            evaluated,
+            compiled,
+            verdict,
+        });
+    }
+
+    Ok(results)
+}
@@ -0,0 +1,327 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+
+use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType};
+use crate::classic::clvm_tools::binutils::assemble_from_ir;
+use crate::classic::clvm_tools::clvmc::{compile_clvm_text, detect_modern};
+use crate::classic::clvm_tools::ir::reader::read_ir;
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+use crate::compiler::clvm::{convert_from_clvm_rs, convert_to_clvm_rs, sha256tree};
+use crate::compiler::compiler::{compile_pre_forms, run_optimizer, DefaultCompilerOpts};
+use crate::compiler::comptypes::{CompileErr, CompilerOpts};
+use crate::compiler::debug::TOPLEVEL_NAME;
+use crate::compiler::prims;
+use crate::compiler::profile::{profile_run, FunctionCost};
+use crate::compiler::sexp::{enlist, SExp};
+use crate::compiler::srcloc::Srcloc;
+
+/// Give the forms of a classic (mod args body...) program with an include of
+/// the named dialect, such as *standard-cl-22*, added after its arguments so
+/// that the modern compiler takes it.
+pub fn inject_dialect(source: &[Rc<SExp>], dialect: &str) -> Result<Vec<Rc<SExp>>, CompileErr> {
+    let loc = source
+        .first()
+        .map(|s| s.loc())
+        .unwrap_or_else(|| Srcloc::start("*source*"));
+    let not_a_mod = || {
+        CompileErr(
+            loc.clone(),
+            "expected a single (mod args ...) form".to_string(),
+        )
+    };
+    if source.len() != 1 {
+        return Err(not_a_mod());
+    }
+    let mut elements: Vec<Rc<SExp>> = source[0]
+        .proper_list()
+        .ok_or_else(not_a_mod)?
+        .into_iter()
+        .map(Rc::new)
+        .collect();
+    if elements.len() < 3 || !matches!(elements[0].as_ref(), SExp::Atom(_, m) if m == b"mod") {
+        return Err(not_a_mod());
+    }
+
+    let include = Rc::new(enlist(
+        loc.clone(),
+        vec![
+            Rc::new(SExp::atom_from_string(loc.clone(), "include")),
+            Rc::new(SExp::atom_from_string(loc.clone(), dialect)),
+        ],
+    ));
+    elements.insert(2, include);
+    Ok(vec![Rc::new(enlist(source[0].loc(), elements))])
+}
+
+/// A compiled program with the symbol table its compiler gave.
+#[derive(Clone, Debug)]
+pub struct CompiledProgram {
+    pub program: Rc<SExp>,
+    pub symbol_table: HashMap<String, String>,
+}
+
+impl CompiledProgram {
+    /// The program's tree hash in hex, which changes when a deployed puzzle
+    /// is recompiled differently.
+    pub fn tree_hash(&self) -> String {
+        Bytes::new(Some(BytesFromType::Raw(sha256tree(self.program.clone())))).hex()
+    }
+}
+
+/// Compile classic chialisp text as the classic compiler (stage_2) does,
+/// refusing text that asks for a modern dialect.
+pub fn compile_classic(
+    allocator: &mut Allocator,
+    search_paths: &[String],
+    filename: &str,
+    text: &str,
+) -> Result<CompiledProgram, CompileErr> {
+    let loc = Srcloc::start(filename);
+    let ir = read_ir(text).map_err(|e| CompileErr(loc.clone(), e))?;
+    let assembled =
+        assemble_from_ir(allocator, Rc::new(ir)).map_err(|e| CompileErr(loc.clone(), e.1))?;
+    if detect_modern(allocator, assembled).is_some() {
+        return Err(CompileErr(
+            loc,
+            "source already includes a modern dialect".to_string(),
+        ));
+    }
+
+    let mut symbol_table = HashMap::new();
+    let node = compile_clvm_text(allocator, search_paths, &mut symbol_table, text, filename)
+        .map_err(|e| CompileErr(loc.clone(), e.1))?;
+    let program = convert_from_clvm_rs(allocator, loc, node).map_err(CompileErr::from)?;
+    Ok(CompiledProgram {
+        program,
+        symbol_table,
+    })
+}
+
+/// Compile the forms of a modern program with the options compiling its text
+/// with run or the compile_clvm API would use for its dialect, so the code is
+/// what would be deployed.
+pub fn compile_modern(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    search_paths: &[String],
+    filename: &str,
+    forms: &[Rc<SExp>],
+) -> Result<CompiledProgram, CompileErr> {
+    let loc = Srcloc::start(filename);
+    let mut dialect = None;
+    for form in forms.iter() {
+        let node = convert_to_clvm_rs(allocator, form.clone()).map_err(CompileErr::from)?;
+        dialect = dialect.or_else(|| detect_modern(allocator, node));
+    }
+    let dialect = dialect.ok_or_else(|| {
+        CompileErr(
+            loc,
+            "source doesn't include a modern dialect such as *standard-cl-22*".to_string(),
+        )
+    })?;
+
+    let opts = Rc::new(DefaultCompilerOpts::new(filename))
+        .set_optimize(true)
+        .set_frontend_opt(dialect > 21)
+        .set_search_paths(search_paths);
+    let mut symbol_table = HashMap::new();
+    let unoptimized = compile_pre_forms(allocator, runner.clone(), opts, forms, &mut symbol_table)?;
+    let program = run_optimizer(allocator, runner, Rc::new(unoptimized))?;
+    Ok(CompiledProgram {
+        program,
+        symbol_table,
+    })
+}
+
+/// The outcome of running one compiled program on a solution, with its cost
+/// attributed to the functions in its symbol table.
+#[derive(Clone, Debug)]
+pub struct ProfiledRun {
+    pub result: Result<Rc<SExp>, String>,
+    pub cost: Cost,
+    pub functions: HashMap<String, FunctionCost>,
+}
+
+impl ProfiledRun {
+    fn new(
+        allocator: &mut Allocator,
+        runner: Rc<dyn TRunProgram>,
+        compiled: &CompiledProgram,
+        solution: Rc<SExp>,
+        max_cost: Option<Cost>,
+    ) -> Self {
+        let (profiler, result) = profile_run(
+            allocator,
+            runner,
+            prims::prim_map(),
+            compiled.symbol_table.clone(),
+            compiled.program.clone(),
+            solution,
+            max_cost,
+        );
+        ProfiledRun {
+            result: result.map_err(|e| e.to_string()),
+            cost: profiler.total(),
+            functions: profiler.functions().into_iter().collect(),
+        }
+    }
+}
+
+/// How one function's cost compares between the classic and modern runs.
+/// A function may appear in only one of them, such as when the modern
+/// compiler inlined it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionComparison {
+    pub name: String,
+    pub classic: Option<FunctionCost>,
+    pub modern: Option<FunctionCost>,
+}
+
+impl FunctionComparison {
+    /// Whether the function was called as often and cost as much in both.
+    pub fn same(&self) -> bool {
+        match (&self.classic, &self.modern) {
+            (Some(c), Some(m)) => c.calls == m.calls && c.inclusive == m.inclusive,
+            _ => false,
+        }
+    }
+}
+
+/// The classic and modern runs of a program on one solution.
+#[derive(Clone, Debug)]
+pub struct EquivalenceResult {
+    pub solution: Rc<SExp>,
+    pub classic: ProfiledRun,
+    pub modern: ProfiledRun,
+}
+
+impl EquivalenceResult {
+    /// Whether both gave the same value, or both failed.
+    pub fn results_match(&self) -> bool {
+        match (&self.classic.result, &self.modern.result) {
+            (Ok(c), Ok(m)) => sha256tree(c.clone()) == sha256tree(m.clone()),
+            (Err(_), Err(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// The modern run's cost less the classic one's.
+    pub fn cost_difference(&self) -> i128 {
+        self.modern.cost as i128 - self.classic.cost as i128
+    }
+
+    /// Each function known to either run, by name.
+    pub fn functions(&self) -> Vec<FunctionComparison> {
+        let names: BTreeSet<&String> = self
+            .classic
+            .functions
+            .keys()
+            .chain(self.modern.functions.keys())
+            .collect();
+        names
+            .into_iter()
+            .map(|name| FunctionComparison {
+                name: name.clone(),
+                classic: self.classic.functions.get(name).cloned(),
+                modern: self.modern.functions.get(name).cloned(),
+            })
+            .collect()
+    }
+}
+
+fn format_result(result: &Result<Rc<SExp>, String>) -> String {
+    match result {
+        Ok(r) => r.to_string(),
+        Err(e) => format!("failed: {e}"),
+    }
+}
+
+fn format_function_cost(f: &Option<FunctionCost>) -> String {
+    match f {
+        Some(f) => format!("{} ({} calls)", f.inclusive, f.calls),
+        None => "-".to_string(),
+    }
+}
+
+impl Display for EquivalenceResult {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if self.results_match() {
+            writeln!(
+                formatter,
+                "SAME {}: {}",
+                self.solution,
+                format_result(&self.modern.result)
+            )?;
+        } else {
+            writeln!(formatter, "DIFFERENT {}", self.solution)?;
+            writeln!(
+                formatter,
+                "  classic: {}",
+                format_result(&self.classic.result)
+            )?;
+            writeln!(
+                formatter,
+                "  modern:  {}",
+                format_result(&self.modern.result)
+            )?;
+        }
+        writeln!(
+            formatter,
+            "  cost: classic {} modern {} ({:+})",
+            self.classic.cost,
+            self.modern.cost,
+            self.cost_difference()
+        )?;
+        for f in self
+            .functions()
+            .iter()
+            .filter(|f| f.name != TOPLEVEL_NAME && !f.same())
+        {
+            writeln!(
+                formatter,
+                "  {}: classic {} modern {}",
+                f.name,
+                format_function_cost(&f.classic),
+                format_function_cost(&f.modern)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the classic and modern compilations of a program on each solution,
+/// so that their results, costs and the cost of each function can be
+/// compared before a deployed puzzle is moved to the modern dialect.
+pub fn check_equivalence(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    classic: &CompiledProgram,
+    modern: &CompiledProgram,
+    solutions: &[Rc<SExp>],
+    max_cost: Option<Cost>,
+) -> Vec<EquivalenceResult> {
+    solutions
+        .iter()
+        .map(|solution| EquivalenceResult {
+            solution: solution.clone(),
+            classic: ProfiledRun::new(
+                allocator,
+                runner.clone(),
+                classic,
+                solution.clone(),
+                max_cost,
+            ),
+            modern: ProfiledRun::new(
+                allocator,
+                runner.clone(),
+                modern,
+                solution.clone(),
+                max_cost,
+            ),
+        })
+        .collect()
+}
@@ -257,6 +257,21 @@ fn create_argument_captures(
     }
 }
 
+/// Bind the names in an argument list, as in a mod or defun, to the parts of
+/// a known environment, as the Evaluator's env for shrink_bodyform.
+pub fn argument_captures_for_env(
+    args: Rc<SExp>,
+    env: &SExp,
+) -> Result<HashMap<Vec<u8>, Rc<BodyForm>>, CompileErr> {
+    let mut argument_captures = HashMap::new();
+    create_argument_captures(
+        &mut argument_captures,
+        &ArgInputs::Whole(Rc::new(BodyForm::Quoted(env.clone()))),
+        args,
+    )?;
+    Ok(argument_captures)
+}
+
 fn arg_inputs_primitive(arginputs: Rc<ArgInputs>) -> bool {
     match arginputs.borrow() {
         ArgInputs::Whole(bf) => is_primitive(bf),
@@ -1284,6 +1299,11 @@ impl<'info> Evaluator {
         self.helpers.push(h.clone());
     }
 
+    /// The helpers the evaluator knows, in the order they were added.
+    pub fn helpers(&self) -> &[HelperForm] {
+        &self.helpers
+    }
+
     // The evaluator treats the forms coming up from constants as live.
     fn get_constant(&self, name: &[u8]) -> Option<Rc<BodyForm>> {
         for h in self.helpers.iter() {
@@ -769,12 +769,30 @@ pub fn frontend(
 
     let our_mod = rename_children_compileform(&compiled?);
 
-    let expr_names: HashSet<Vec<u8>> = collect_used_names_bodyform(our_mod.exp.borrow())
+    let helpers = if opts.frontend_check_live() {
+        live_helpers(&our_mod.helpers, our_mod.exp.borrow())
+    } else {
+        our_mod.helpers.clone()
+    };
+
+    Ok(CompileForm {
+        loc: our_mod.loc.clone(),
+        include_forms: includes.to_vec(),
+        args: our_mod.args.clone(),
+        helpers,
+        exp: our_mod.exp.clone(),
+    })
+}
+
+/// The helpers an expression uses, directly or through the helpers it uses,
+/// in the order they're given.
+pub fn live_helpers(helpers: &[HelperForm], exp: &BodyForm) -> Vec<HelperForm> {
+    let expr_names: HashSet<Vec<u8>> = collect_used_names_bodyform(exp)
         .iter()
         .map(|x| x.to_vec())
         .collect();
 
-    let helper_list = our_mod.helpers.iter().map(|h| (h.name(), h));
+    let helper_list = helpers.iter().map(|h| (h.name(), h));
     let mut helper_map = HashMap::new();
 
     for hpair in helper_list {
@@ -783,20 +801,11 @@ pub fn frontend(
 
     let helper_names = calculate_live_helpers(&HashSet::new(), &expr_names, &helper_map);
 
-    let mut live_helpers = Vec::new();
-    for h in our_mod.helpers {
-        if !opts.frontend_check_live() || helper_names.contains(h.name()) {
-            live_helpers.push(h);
-        }
-    }
-
-    Ok(CompileForm {
-        loc: our_mod.loc.clone(),
-        include_forms: includes.to_vec(),
-        args: our_mod.args.clone(),
-        helpers: live_helpers,
-        exp: our_mod.exp.clone(),
-    })
+    helpers
+        .iter()
+        .filter(|h| helper_names.contains(h.name()))
+        .cloned()
+        .collect()
 }
 
 fn is_quote_op(sexp: Rc<SExp>) -> bool {
@@ -0,0 +1,232 @@
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use crate::classic::clvm::keyword_to_atom;
+use crate::compiler::comptypes::CompileErr;
+use crate::compiler::equivalence::inject_dialect;
+use crate::compiler::sexp::{enlist, SExp};
+use crate::compiler::srcloc::Srcloc;
+use crate::util::number_from_u8;
+
+/// Something about a program's migration to a modern dialect: a rewrite that
+/// was made, or a construct left alone that may mean something different.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationNote {
+    pub loc: Srcloc,
+    pub message: String,
+}
+
+impl Display for MigrationNote {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(formatter, "{}: {}", self.loc, self.message)
+    }
+}
+
+/// A classic program rewritten in a modern dialect.
+#[derive(Clone, Debug)]
+pub struct Migration {
+    pub forms: Vec<Rc<SExp>>,
+    /// The classic idioms that were rewritten.
+    pub changes: Vec<MigrationNote>,
+    /// Constructs whose meaning may differ in the modern dialect, which need
+    /// checking by hand.
+    pub warnings: Vec<MigrationNote>,
+}
+
+impl Migration {
+    /// The migrated program as source text, one toplevel form per line.
+    /// Comments in the classic source aren't carried over.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for form in self.forms.iter() {
+            match form.proper_list() {
+                Some(elements) if elements.len() > 2 => {
+                    text += &format!("({} {}\n", elements[0], elements[1]);
+                    for element in elements.iter().skip(2).take(elements.len() - 3) {
+                        text += &format!("  {element}\n");
+                    }
+                    text += &format!("  {}\n  )\n", elements[elements.len() - 1]);
+                }
+                _ => {
+                    text += &format!("{form}\n");
+                }
+            }
+        }
+        text
+    }
+}
+
+fn note(loc: Srcloc, message: String) -> MigrationNote {
+    MigrationNote { loc, message }
+}
+
+fn atom_name(sexp: &SExp) -> Option<&[u8]> {
+    if let SExp::Atom(_, name) = sexp {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+// The classic compiler quotes a defconstant's body after assembling it, so
+// atoms named like operators become their opcodes, while the modern one keeps
+// a cons body's atoms as they're written.
+fn assembled_constant(body: &SExp) -> SExp {
+    match body {
+        SExp::Cons(l, a, b) => SExp::Cons(
+            l.clone(),
+            Rc::new(assembled_constant(a)),
+            Rc::new(assembled_constant(b)),
+        ),
+        SExp::Atom(l, name) => {
+            let keyword = String::from_utf8_lossy(name).to_string();
+            if let Some(opcode) = keyword_to_atom().get(&keyword) {
+                SExp::Integer(l.clone(), number_from_u8(opcode))
+            } else {
+                body.clone()
+            }
+        }
+        _ => body.clone(),
+    }
+}
+
+// Rewrite a classic defconstant so the modern compiler gives it the same value.
+fn migrate_defconstant(form: &[SExp], changes: &mut Vec<MigrationNote>) -> SExp {
+    let body = &form[2];
+    let name = String::from_utf8_lossy(atom_name(&form[1]).unwrap_or_default()).to_string();
+    let migrated = match body {
+        SExp::Cons(_, _, _) => {
+            let assembled = assembled_constant(body);
+            if assembled == *body {
+                return enlist(form[0].loc(), form.iter().cloned().map(Rc::new).collect());
+            }
+            changes.push(note(
+                body.loc(),
+                format!("defconstant {name}: operator names in the body written as their opcodes"),
+            ));
+            assembled
+        }
+        SExp::Atom(l, value) => {
+            let assembled = assembled_constant(body);
+            if assembled != *body {
+                changes.push(note(
+                    body.loc(),
+                    format!("defconstant {name}: the operator name written as its opcode"),
+                ));
+                assembled
+            } else {
+                // A bare name would be a reference to another binding in the
+                // modern dialect, rather than the atom itself.
+                changes.push(note(
+                    body.loc(),
+                    format!("defconstant {name}: the atom body written as a string"),
+                ));
+                SExp::QuotedString(l.clone(), b'"', value.clone())
+            }
+        }
+        _ => body.clone(),
+    };
+    let mut elements: Vec<Rc<SExp>> = form.iter().cloned().map(Rc::new).collect();
+    elements[2] = Rc::new(migrated);
+    enlist(form[0].loc(), elements)
+}
+
+// Find uses of @, which the classic compiler takes as the whole environment.
+fn find_at_captures(body: &SExp, warnings: &mut Vec<MigrationNote>) {
+    match body {
+        SExp::Cons(_, a, b) => {
+            if atom_name(a) == Some(b"q") {
+                return;
+            }
+            find_at_captures(a, warnings);
+            find_at_captures(b, warnings);
+        }
+        SExp::Atom(l, name) if name == b"@" => {
+            warnings.push(note(
+                l.clone(),
+                "@ is the whole environment in classic chialisp but not in the modern dialect"
+                    .to_string(),
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Rewrite a classic (mod args helpers... main) program in a modern dialect,
+/// such as *standard-cl-22*: the dialect's include is added after the
+/// arguments and defconstant bodies are rewritten to keep their classic
+/// values.  Macros, included files and uses of @ are left as they are but
+/// warned about.
+pub fn migrate(source: &[Rc<SExp>], dialect: &str) -> Result<Migration, CompileErr> {
+    let loc = source
+        .first()
+        .map(|s| s.loc())
+        .unwrap_or_else(|| Srcloc::start("*source*"));
+    let elements = match source {
+        [program] => program.proper_list(),
+        _ => None,
+    }
+    .filter(|e| e.len() > 2 && atom_name(&e[0]) == Some(b"mod"))
+    .ok_or_else(|| {
+        CompileErr(
+            loc.clone(),
+            "expected a single (mod args ...) form".to_string(),
+        )
+    })?;
+
+    let mut changes = Vec::new();
+    let mut warnings = Vec::new();
+    let mut migrated: Vec<Rc<SExp>> =
+        vec![Rc::new(elements[0].clone()), Rc::new(elements[1].clone())];
+    let main = &elements[elements.len() - 1];
+    for helper in elements.iter().skip(2).take(elements.len() - 3) {
+        let form = helper.proper_list().unwrap_or_default();
+        let keyword = form.first().and_then(atom_name).unwrap_or_default();
+        if keyword == b"defconstant" && form.len() == 3 {
+            migrated.push(Rc::new(migrate_defconstant(&form, &mut changes)));
+            continue;
+        }
+
+        if keyword == b"include" && form.len() == 2 {
+            let file = form[1].to_string();
+            if file.starts_with("*standard-cl-") {
+                return Err(CompileErr(
+                    helper.loc(),
+                    format!("already in the {file} dialect"),
+                ));
+            }
+            warnings.push(note(
+                helper.loc(),
+                format!("{file} is included in the modern dialect too and may need migrating"),
+            ));
+        } else if keyword == b"defmacro" {
+            warnings.push(note(
+                helper.loc(),
+                "macros expand differently in the modern dialect; check their uses".to_string(),
+            ));
+        } else {
+            find_at_captures(helper, &mut warnings);
+        }
+        if let Some(name) = form.get(1).and_then(atom_name) {
+            let name = String::from_utf8_lossy(name).to_string();
+            if keyword != b"defconstant" && keyword_to_atom().contains_key(&name) {
+                warnings.push(note(
+                    helper.loc(),
+                    format!(
+                        "classic chialisp calls the {name} operator rather than this definition"
+                    ),
+                ));
+            }
+        }
+        migrated.push(Rc::new(helper.clone()));
+    }
+    find_at_captures(main, &mut warnings);
+    migrated.push(Rc::new(main.clone()));
+
+    let forms = inject_dialect(&[Rc::new(enlist(loc, migrated))], dialect)?;
+    Ok(Migration {
+        forms,
+        changes,
+        warnings,
+    })
+}
@@ -1,5 +1,9 @@
+/// Backtraces of the chialisp functions a failing CLVM run was inside of.
+pub mod backtrace;
 /// Chialisp debugging.
 pub mod cldb;
+/// Running chialisp unit tests written as deftest forms.
+pub mod cltest;
 /// CLVM running.
 pub mod clvm;
 mod codegen;
@@ -12,19 +16,37 @@ pub mod compiler;
 /// - CompileForm - The type of finished (mod ) forms before code generation.
 /// - HelperForm - The type of declarations like macros, constants and functions.
 pub mod comptypes;
+/// Source level line and branch coverage of CLVM runs.
+pub mod coverage;
+/// A Debug Adapter Protocol server for debugging chialisp in editors.
+pub mod dap;
 ///
 pub mod debug;
+/// Interactive debugging of CLVM runs with breakpoints.
+pub mod debugger;
+/// Differential testing of the Evaluator against compiled code.
+pub mod differential;
+/// Checking classic and modern compilations of a program against each other.
+pub mod equivalence;
 pub mod evaluate;
 pub mod frontend;
 pub mod gensym;
 mod inline;
+/// Rewriting classic chialisp programs in a modern dialect.
+pub mod migrate;
 mod optimize;
 pub mod preprocessor;
 pub mod prims;
+/// Cost profiling of CLVM runs by chialisp function.
+pub mod profile;
+/// Property based testing of chialisp functions with generated arguments.
+pub mod proptest;
 pub mod rename;
 pub mod repl;
 pub mod runtypes;
 pub mod sexp;
 pub mod srcloc;
 pub mod stackvisit;
+/// Streamed structured traces of CLVM runs.
+pub mod trace;
 pub mod usecheck;
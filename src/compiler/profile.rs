@@ -0,0 +1,175 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+
+use crate::compiler::clvm::{check_max_cost, run_step, start_step, RunStep};
+use crate::compiler::debug::TOPLEVEL_NAME;
+use crate::compiler::debugger::CallStack;
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::SExp;
+
+/// Cost totals for one function in a profiled run.
+///
+/// Inclusive cost counts everything spent while the function was anywhere on the
+/// call stack (once, even when it recurses), while exclusive cost counts only
+/// what was spent while it was on top.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionCost {
+    pub calls: usize,
+    pub inclusive: Cost,
+    pub exclusive: Cost,
+}
+
+/// Observes the steps of a run and attributes their cost to the chialisp
+/// function on top of the call stack, tracked as the debugger tracks it.
+pub struct CostProfiler {
+    call_stack: CallStack,
+    functions: HashMap<String, FunctionCost>,
+    folded: BTreeMap<String, Cost>,
+    total: Cost,
+}
+
+impl CostProfiler {
+    pub fn new(symbol_table: HashMap<String, String>) -> Self {
+        let mut functions = HashMap::new();
+        functions.insert(
+            TOPLEVEL_NAME.to_string(),
+            FunctionCost {
+                calls: 1,
+                ..FunctionCost::default()
+            },
+        );
+        CostProfiler {
+            call_stack: CallStack::new(symbol_table),
+            functions,
+            folded: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    /// Account for the run having moved from the before step to the after
+    /// step.
+    pub fn observe(&mut self, before: &RunStep, after: &RunStep) {
+        if let Some(frame) = self.call_stack.observe(before) {
+            self.functions.entry(frame.name.clone()).or_default().calls += 1;
+        }
+
+        let spent = after.cost().saturating_sub(before.cost());
+        if spent == 0 {
+            return;
+        }
+        self.total += spent;
+
+        let names: Vec<&str> = std::iter::once(TOPLEVEL_NAME)
+            .chain(self.call_stack.frames().iter().map(|f| f.name.as_str()))
+            .collect();
+        // Inclusive cost is counted once per function however deeply it
+        // recurses.
+        let mut counted = HashSet::new();
+        for name in names.iter() {
+            if counted.insert(*name) {
+                if let Some(f) = self.functions.get_mut(*name) {
+                    f.inclusive += spent;
+                }
+            }
+        }
+        let functions = &mut self.functions;
+        if let Some(f) = names.last().and_then(|n| functions.get_mut(*n)) {
+            f.exclusive += spent;
+        }
+        *self.folded.entry(names.join(";")).or_insert(0) += spent;
+    }
+
+    /// The total cost observed.
+    pub fn total(&self) -> Cost {
+        self.total
+    }
+
+    /// Cost totals for each function seen, most expensive (inclusive) first.
+    pub fn functions(&self) -> Vec<(String, FunctionCost)> {
+        let mut result: Vec<(String, FunctionCost)> = self
+            .functions
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        result.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        result
+    }
+
+    /// Human readable tables of inclusive and exclusive cost per function.
+    pub fn format_tables(&self) -> String {
+        let mut functions = self.functions();
+        let mut result = format!("total cost = {}\n", self.total);
+        let heading = format!(
+            "{:>14} {:>14} {:>8}  function\n",
+            "inclusive", "exclusive", "calls"
+        );
+        let row = |name: &str, f: &FunctionCost| {
+            format!(
+                "{:>14} {:>14} {:>8}  {name}\n",
+                f.inclusive, f.exclusive, f.calls
+            )
+        };
+
+        result.push_str("\nby inclusive cost:\n");
+        result.push_str(&heading);
+        for (name, f) in functions.iter() {
+            result.push_str(&row(name, f));
+        }
+
+        functions.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(&b.0)));
+        result.push_str("\nby exclusive cost:\n");
+        result.push_str(&heading);
+        for (name, f) in functions.iter() {
+            result.push_str(&row(name, f));
+        }
+
+        result
+    }
+
+    /// The observed cost in the folded stack format read by flamegraph tools,
+    /// one "caller;callee cost" line per distinct call stack.
+    pub fn folded_stacks(&self) -> String {
+        self.folded
+            .iter()
+            .map(|(k, v)| format!("{k} {v}\n"))
+            .collect()
+    }
+}
+
+/// Run a program to completion with the stepping interpreter, attributing its
+/// cost to the functions named in the symbol table.  The profile is given even
+/// when the run fails, covering the cost spent up to the failure.
+pub fn profile_run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    symbol_table: HashMap<String, String>,
+    program: Rc<SExp>,
+    env: Rc<SExp>,
+    max_cost: Option<Cost>,
+) -> (CostProfiler, Result<Rc<SExp>, RunFailure>) {
+    let mut profiler = CostProfiler::new(symbol_table);
+    let mut step = start_step(program, env);
+
+    loop {
+        let new_step = match run_step(allocator, runner.clone(), prim_map.clone(), &step)
+            .and_then(|s| check_max_cost(&s, max_cost).map(|_| s))
+        {
+            Ok(s) => s,
+            Err(e) => {
+                return (profiler, Err(e));
+            }
+        };
+
+        profiler.observe(&step, &new_step);
+        if let RunStep::Done(_, x, _) = &new_step {
+            return (profiler, Ok(x.clone()));
+        }
+        step = new_step;
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+use num_bigint::ToBigInt;
+use rand::Rng;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+use crate::compiler::clvm::run_on_clvmr;
+use crate::compiler::compiler::{compile_pre_forms, compose_run_function, find_function_hash};
+use crate::compiler::comptypes::{CompileErr, CompilerOpts};
+use crate::compiler::sexp::{enlist, parse_sexp, random_atom, random_sexp, SExp};
+use crate::compiler::srcloc::Srcloc;
+use crate::util::Number;
+
+/// The size of the trees the Any generator makes, as random_sexp counts it.
+pub const ANY_SEXP_SIZE: usize = 8;
+
+/// A generator of values for an argument of a function under test.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgGenerator {
+    /// Any tree of atoms, from random_sexp.
+    Any,
+    /// A word atom, from random_atom.
+    Atom,
+    /// An integer, usually a small one.
+    Int,
+    /// An atom of exactly this many bytes, such as a 32 byte hash.
+    Bytes(usize),
+    /// A proper list of values from a generator.
+    List(Box<ArgGenerator>),
+    /// A list of conditions, as a puzzle would give.
+    Conditions,
+}
+
+impl ArgGenerator {
+    /// Read a generator given on the command line: any, atom, int, bytes<n>
+    /// (as in bytes32), list:<generator> or conditions.
+    pub fn parse(spec: &str) -> Result<ArgGenerator, String> {
+        if let Some(elements) = spec.strip_prefix("list:") {
+            return ArgGenerator::parse(elements).map(|g| ArgGenerator::List(Box::new(g)));
+        }
+        if let Some(n) = spec.strip_prefix("bytes") {
+            return n
+                .parse::<usize>()
+                .map(ArgGenerator::Bytes)
+                .map_err(|_| format!("bad byte count in generator {spec}"));
+        }
+        match spec {
+            "any" => Ok(ArgGenerator::Any),
+            "atom" => Ok(ArgGenerator::Atom),
+            "int" => Ok(ArgGenerator::Int),
+            "conditions" => Ok(ArgGenerator::Conditions),
+            _ => Err(format!(
+                "unknown generator {spec}, expected any, atom, int, bytes<n>, list:<generator> or conditions"
+            )),
+        }
+    }
+
+    /// Make a random value.
+    pub fn generate<R: Rng + ?Sized>(&self, rng: &mut R, loc: &Srcloc) -> Rc<SExp> {
+        match self {
+            ArgGenerator::Any => Rc::new(random_sexp(rng, ANY_SEXP_SIZE)),
+            ArgGenerator::Atom => Rc::new(random_atom(rng)),
+            ArgGenerator::Int => {
+                let n: i64 = if rng.gen_bool(0.75) {
+                    rng.gen_range(-128..=128)
+                } else {
+                    rng.gen()
+                };
+                Rc::new(SExp::Integer(loc.clone(), n.to_bigint().unwrap()))
+            }
+            ArgGenerator::Bytes(n) => {
+                let bytes: Vec<u8> = (0..*n).map(|_| rng.gen()).collect();
+                Rc::new(SExp::QuotedString(loc.clone(), b'"', bytes))
+            }
+            ArgGenerator::List(elements) => {
+                let length = rng.gen_range(0..=4);
+                Rc::new(enlist(
+                    loc.clone(),
+                    (0..length).map(|_| elements.generate(rng, loc)).collect(),
+                ))
+            }
+            ArgGenerator::Conditions => {
+                let length = rng.gen_range(0..=4);
+                Rc::new(enlist(
+                    loc.clone(),
+                    (0..length).map(|_| random_condition(rng, loc)).collect(),
+                ))
+            }
+        }
+    }
+
+    /// Smaller values of the same kind as a value, the most promising first,
+    /// for shrinking a counterexample.
+    pub fn shrink(&self, value: &SExp) -> Vec<Rc<SExp>> {
+        let loc = value.loc();
+        match (self, value) {
+            (ArgGenerator::Bytes(_), SExp::QuotedString(_, q, bytes)) => {
+                // Keep the length, zeroing the first byte that isn't.
+                if let Some(i) = bytes.iter().position(|b| *b != 0) {
+                    let mut zeroed = bytes.clone();
+                    zeroed[i] = 0;
+                    let mut all_zero = bytes.clone();
+                    all_zero.iter_mut().for_each(|b| *b = 0);
+                    vec![
+                        Rc::new(SExp::QuotedString(loc.clone(), *q, all_zero)),
+                        Rc::new(SExp::QuotedString(loc, *q, zeroed)),
+                    ]
+                } else {
+                    vec![]
+                }
+            }
+            (ArgGenerator::List(elements), _) => shrink_list(value, &|v| elements.shrink(v)),
+            (ArgGenerator::Conditions, _) => shrink_list(value, &|_| vec![]),
+            (_, SExp::Integer(_, n)) => shrink_number(&loc, n),
+            (_, SExp::Atom(_, a)) | (_, SExp::QuotedString(_, _, a)) => {
+                if a.is_empty() {
+                    vec![]
+                } else {
+                    vec![
+                        Rc::new(SExp::Nil(loc.clone())),
+                        Rc::new(SExp::Atom(loc, a[..a.len() / 2].to_vec())),
+                    ]
+                }
+            }
+            (_, SExp::Cons(_, a, b)) => {
+                let mut smaller = vec![Rc::new(SExp::Nil(loc.clone())), a.clone(), b.clone()];
+                for sa in self.shrink(a) {
+                    smaller.push(Rc::new(SExp::Cons(loc.clone(), sa, b.clone())));
+                }
+                for sb in self.shrink(b) {
+                    smaller.push(Rc::new(SExp::Cons(loc.clone(), a.clone(), sb)));
+                }
+                smaller
+            }
+            (_, SExp::Nil(_)) => vec![],
+        }
+    }
+}
+
+fn shrink_number(loc: &Srcloc, n: &Number) -> Vec<Rc<SExp>> {
+    let zero = 0.to_bigint().unwrap();
+    if *n == zero {
+        return vec![];
+    }
+    let two = 2.to_bigint().unwrap();
+    let one = 1.to_bigint().unwrap();
+    let toward_zero = if *n > zero { n - &one } else { n + &one };
+    let mut smaller = vec![
+        Rc::new(SExp::Integer(loc.clone(), zero.clone())),
+        Rc::new(SExp::Integer(loc.clone(), n / &two)),
+        Rc::new(SExp::Integer(loc.clone(), toward_zero)),
+    ];
+    if *n < zero {
+        smaller.push(Rc::new(SExp::Integer(loc.clone(), -n)));
+    }
+    smaller.dedup_by(|a, b| a == b);
+    smaller.retain(|s| s.as_ref() != &SExp::Integer(loc.clone(), n.clone()));
+    smaller
+}
+
+// Shorter lists first, then ones with an element shrunk.
+fn shrink_list(value: &SExp, shrink_element: &dyn Fn(&SExp) -> Vec<Rc<SExp>>) -> Vec<Rc<SExp>> {
+    let loc = value.loc();
+    let elements: Vec<Rc<SExp>> = value
+        .proper_list()
+        .unwrap_or_default()
+        .into_iter()
+        .map(Rc::new)
+        .collect();
+    let mut smaller = Vec::new();
+    if !elements.is_empty() {
+        smaller.push(Rc::new(SExp::Nil(loc.clone())));
+    }
+    for i in 0..elements.len() {
+        let mut without = elements.clone();
+        without.remove(i);
+        smaller.push(Rc::new(enlist(loc.clone(), without)));
+    }
+    for i in 0..elements.len() {
+        for s in shrink_element(&elements[i]) {
+            let mut with = elements.clone();
+            with[i] = s;
+            smaller.push(Rc::new(enlist(loc.clone(), with)));
+        }
+    }
+    smaller
+}
+
+fn random_condition<R: Rng + ?Sized>(rng: &mut R, loc: &Srcloc) -> Rc<SExp> {
+    let int = |n: i64| Rc::new(SExp::Integer(loc.clone(), n.to_bigint().unwrap()));
+    let bytes = |rng: &mut R, n: usize| {
+        Rc::new(SExp::QuotedString(
+            loc.clone(),
+            b'"',
+            (0..n).map(|_| rng.gen()).collect(),
+        ))
+    };
+    let condition = match rng.gen_range(0..5) {
+        // CREATE_COIN puzzle_hash amount
+        0 => vec![int(51), bytes(rng, 32), int(rng.gen_range(0..=1_000_000))],
+        // AGG_SIG_ME public_key message
+        1 => vec![int(50), bytes(rng, 48), bytes(rng, 32)],
+        // CREATE_COIN_ANNOUNCEMENT message
+        2 => vec![int(60), bytes(rng, 32)],
+        // ASSERT_COIN_ANNOUNCEMENT announcement_id
+        3 => vec![int(61), bytes(rng, 32)],
+        // ASSERT_MY_AMOUNT amount
+        _ => vec![int(73), int(rng.gen_range(0..=1_000_000))],
+    };
+    Rc::new(enlist(loc.clone(), condition))
+}
+
+/// The names of the arguments in an argument list, as in a defun, in order.
+pub fn argument_names(args: &SExp) -> Vec<Vec<u8>> {
+    match args {
+        SExp::Atom(_, name) => vec![name.clone()],
+        SExp::Cons(_, a, b) => {
+            let mut names = argument_names(a);
+            names.append(&mut argument_names(b));
+            names
+        }
+        _ => vec![],
+    }
+}
+
+// Build the environment an argument list describes from the values of its
+// names.
+fn build_env(args: &SExp, values: &HashMap<Vec<u8>, Rc<SExp>>) -> Rc<SExp> {
+    match args {
+        SExp::Atom(l, name) => values
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| Rc::new(SExp::Nil(l.clone()))),
+        SExp::Cons(l, a, b) => Rc::new(SExp::Cons(
+            l.clone(),
+            build_env(a, values),
+            build_env(b, values),
+        )),
+        _ => Rc::new(SExp::Nil(args.loc())),
+    }
+}
+
+/// Code that runs a function of a compiled program by itself, given the
+/// function's arguments as its environment, along with the function's
+/// argument list from the symbol table, as composed by compose_run_function.
+pub fn function_runner(
+    program: Rc<SExp>,
+    symbol_table: &HashMap<String, String>,
+    name: &str,
+) -> Result<(Rc<SExp>, Rc<SExp>), CompileErr> {
+    let loc = program.loc();
+    let hash = find_function_hash(symbol_table, name).ok_or_else(|| {
+        CompileErr(
+            loc.clone(),
+            format!("function not found in symbols: {name}"),
+        )
+    })?;
+    let arguments = symbol_table
+        .get(&format!("{hash}_arguments"))
+        .ok_or_else(|| CompileErr(loc.clone(), format!("no arguments known for {name}")))?;
+    let args = parse_sexp(
+        Srcloc::start(&format!("{name} arguments")),
+        arguments.bytes(),
+    )?
+    .first()
+    .cloned()
+    .unwrap_or_else(|| Rc::new(SExp::Nil(loc.clone())));
+    Ok((compose_run_function(program, symbol_table, name)?, args))
+}
+
+/// Compile a property of a function's result, RESULT, and its arguments,
+/// using the function's own argument names, into code that's run with
+/// (RESULT . arguments).  Helpers, such as the defuns and includes of the
+/// program the function is in, are available to it.
+pub fn compile_property(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    opts: Rc<dyn CompilerOpts>,
+    args: Rc<SExp>,
+    helpers: &[Rc<SExp>],
+    property: &str,
+) -> Result<Rc<SExp>, CompileErr> {
+    let loc = Srcloc::start("*property*");
+    let exp = parse_sexp(loc.clone(), property.bytes())?
+        .first()
+        .cloned()
+        .ok_or_else(|| CompileErr(loc.clone(), "empty property".to_string()))?;
+    let mut program = vec![
+        Rc::new(SExp::atom_from_string(loc.clone(), "mod")),
+        Rc::new(SExp::Cons(
+            loc.clone(),
+            Rc::new(SExp::atom_from_string(loc.clone(), "RESULT")),
+            args,
+        )),
+    ];
+    program.extend(helpers.iter().cloned());
+    program.push(exp);
+    compile_pre_forms(
+        allocator,
+        runner,
+        opts,
+        &[Rc::new(enlist(loc, program))],
+        &mut HashMap::new(),
+    )
+    .map(Rc::new)
+}
+
+/// A function under test, the property its results should have and how to
+/// generate its arguments.
+pub struct PropertyCheck {
+    /// Code running the function given its arguments, from function_runner.
+    pub function: Rc<SExp>,
+    /// The function's argument list.
+    pub args: Rc<SExp>,
+    /// Code of the property, from compile_property.
+    pub property: Rc<SExp>,
+    /// Generators by argument name, for arguments that shouldn't use Any.
+    pub generators: HashMap<Vec<u8>, ArgGenerator>,
+}
+
+/// Arguments for which a function raised or its result didn't have the
+/// property, shrunk as far as they would go.
+#[derive(Debug, Clone)]
+pub struct Counterexample {
+    /// The arguments by name, after shrinking.
+    pub arguments: Vec<(String, Rc<SExp>)>,
+    /// The result of the function for them, or how it failed.
+    pub result: Result<Rc<SExp>, String>,
+    /// The arguments first found to fail.
+    pub original: Vec<(String, Rc<SExp>)>,
+    /// How many times the arguments were made smaller.
+    pub shrinks: usize,
+    /// Which trial, counting from 1, found the failure.
+    pub trial: usize,
+}
+
+fn format_arguments(arguments: &[(String, Rc<SExp>)]) -> String {
+    arguments
+        .iter()
+        .map(|(name, value)| format!("{name} = {value}"))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl Display for Counterexample {
+    fn fmt(&self, formatter: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        writeln!(
+            formatter,
+            "counterexample found on trial {} after {} shrinks:",
+            self.trial, self.shrinks
+        )?;
+        writeln!(formatter, "  {}", format_arguments(&self.arguments))?;
+        match &self.result {
+            Ok(r) => writeln!(formatter, "  result: {r}")?,
+            Err(e) => writeln!(formatter, "  failed: {e}")?,
+        }
+        write!(
+            formatter,
+            "  originally: {}",
+            format_arguments(&self.original)
+        )
+    }
+}
+
+fn run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    code: Rc<SExp>,
+    env: Rc<SExp>,
+) -> Result<Rc<SExp>, String> {
+    run_on_clvmr(allocator, runner, code, env)
+        .map(|(_, result)| result)
+        .map_err(|e| CompileErr::from(e).1)
+}
+
+impl PropertyCheck {
+    fn generator(&self, name: &[u8]) -> ArgGenerator {
+        self.generators
+            .get(name)
+            .cloned()
+            .unwrap_or(ArgGenerator::Any)
+    }
+
+    // Run the function and property for some arguments, giving whether the
+    // property held along with the function's result.
+    fn holds(
+        &self,
+        allocator: &mut Allocator,
+        runner: Rc<dyn TRunProgram>,
+        values: &HashMap<Vec<u8>, Rc<SExp>>,
+    ) -> (bool, Result<Rc<SExp>, String>) {
+        let env = build_env(&self.args, values);
+        let result = run(
+            allocator,
+            runner.clone(),
+            self.function.clone(),
+            env.clone(),
+        );
+        match &result {
+            Ok(r) => {
+                let property_env = Rc::new(SExp::Cons(env.loc(), r.clone(), env));
+                let held = run(allocator, runner, self.property.clone(), property_env)
+                    .map(|p| !matches!(p.as_ref(), SExp::Nil(_)))
+                    .unwrap_or(false);
+                (held, result)
+            }
+            Err(_) => (false, result),
+        }
+    }
+
+    fn arguments(&self, values: &HashMap<Vec<u8>, Rc<SExp>>) -> Vec<(String, Rc<SExp>)> {
+        argument_names(&self.args)
+            .iter()
+            .filter_map(|name| {
+                values
+                    .get(name)
+                    .map(|v| (String::from_utf8_lossy(name).to_string(), v.clone()))
+            })
+            .collect()
+    }
+
+    /// Run the function with arguments from the generators up to trials
+    /// times, checking the property for each result.  The first failure is
+    /// shrunk, one argument at a time, to the smallest arguments that still
+    /// fail, trying at most max_shrinks smaller ones.
+    pub fn check<R: Rng + ?Sized>(
+        &self,
+        allocator: &mut Allocator,
+        runner: Rc<dyn TRunProgram>,
+        rng: &mut R,
+        trials: usize,
+        max_shrinks: usize,
+    ) -> Option<Counterexample> {
+        let loc = self.args.loc();
+        let names = argument_names(&self.args);
+
+        for trial in 1..=trials {
+            let mut values: HashMap<Vec<u8>, Rc<SExp>> = names
+                .iter()
+                .map(|n| (n.clone(), self.generator(n).generate(rng, &loc)))
+                .collect();
+            let (held, mut result) = self.holds(allocator, runner.clone(), &values);
+            if held {
+                continue;
+            }
+
+            let original = self.arguments(&values);
+            let mut shrinks = 0;
+            let mut tries = 0;
+            let mut shrunk = true;
+            while shrunk && tries < max_shrinks {
+                shrunk = false;
+                'names: for name in names.iter() {
+                    for smaller in self.generator(name).shrink(&values[name]) {
+                        tries += 1;
+                        if tries > max_shrinks {
+                            break 'names;
+                        }
+                        let mut candidate = values.clone();
+                        candidate.insert(name.clone(), smaller);
+                        let (held, candidate_result) =
+                            self.holds(allocator, runner.clone(), &candidate);
+                        if !held {
+                            values = candidate;
+                            result = candidate_result;
+                            shrinks += 1;
+                            shrunk = true;
+                            break 'names;
+                        }
+                    }
+                }
+            }
+
+            return Some(Counterexample {
+                arguments: self.arguments(&values),
+                result,
+                original,
+                shrinks,
+                trial,
+            });
+        }
+
+        None
+    }
+}
+
+/// The helper forms of a (mod args helpers... main) program's source, so a
+/// property can use the program's functions and includes.
+pub fn program_helper_forms(source: &SExp) -> Vec<Rc<SExp>> {
+    let forms = source.proper_list().unwrap_or_default();
+    if forms.len() < 3 || forms[0].to_string() != "mod" {
+        return vec![];
+    }
+    forms[2..forms.len() - 1]
+        .iter()
+        .cloned()
+        .map(Rc::new)
+        .collect()
+}
@@ -2,18 +2,21 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 
 use std::env;
+use std::fs;
 use std::mem::swap;
 use std::rc::Rc;
 
 use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
 
 use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
-use crate::compiler::comptypes::{BodyForm, CompileErr, CompilerOpts};
+use crate::compiler::clvm::run_on_clvmr;
+use crate::compiler::codegen::codegen;
+use crate::compiler::comptypes::{BodyForm, CompileErr, CompileForm, CompilerOpts, HelperForm};
 use crate::compiler::evaluate::{first_of_alist, second_of_alist, Evaluator, EVAL_STACK_LIMIT};
-use crate::compiler::frontend::frontend;
-use crate::compiler::sexp::{parse_sexp, SExp};
+use crate::compiler::frontend::{frontend, live_helpers};
+use crate::compiler::sexp::{decode_string, enlist, parse_sexp, SExp, SExpParser};
 use crate::compiler::srcloc::Srcloc;
-use crate::util::ErrInto;
 
 /// An object implementing a full repl for the language of chialisp toplevel forms
 /// and expressions.
@@ -26,14 +29,35 @@ use crate::util::ErrInto;
 /// Each form used by the repl has a result, which is nil for helperforms and
 /// code for expressions...  If the expression fully evaluated, the result is
 /// a quoted constant.
+///
+/// In execution mode, expressions are instead compiled with the helpers defined
+/// so far and run on clvmr, giving the quoted result, with the exact cost of
+/// the run available from last_cost.  The run's environment is the solution,
+/// whose arguments expressions can refer to by name.
+///
+/// Lines starting with a colon outside of a form are meta-commands, handled by
+/// meta_command.
 pub struct Repl {
-    depth: i32,
-    input_exp: String,
+    // The input read so far toward the next complete form.
+    reader: SExpParser,
 
     toplevel_forms: HashSet<String>,
 
     opts: Rc<dyn CompilerOpts>,
+    runner: Rc<dyn TRunProgram>,
     evaluator: Evaluator,
+    // Names of the helpers defined in the session, in the order they were
+    // first defined.
+    defined: Vec<Vec<u8>>,
+    // The forms each helper was defined by, for :show.
+    sources: HashMap<Vec<u8>, Rc<SExp>>,
+
+    // Whether expressions are compiled and run rather than evaluated, the
+    // argument names and value of the environment they're run in and the
+    // cost of the last run.
+    execute: bool,
+    solution: Option<(Rc<SExp>, Rc<SExp>)>,
+    last_cost: Option<Cost>,
 
     loc: Srcloc,
     stack_limit: Option<usize>,
@@ -71,16 +95,48 @@ fn program_with_helper(names: Vec<Rc<SExp>>, parsed_program: Rc<SExp>) -> Rc<SEx
     ))
 }
 
-fn count_depth(s: &str) -> i32 {
-    let mut count: i32 = 0;
-    for ch in s.as_bytes().iter() {
-        if *ch as char == '(' {
-            count += 1;
-        } else if *ch as char == ')' {
-            count -= 1;
-        }
+// An evaluator knowing only the standard environment.
+fn starting_evaluator(
+    opts: Rc<dyn CompilerOpts>,
+    runner: Rc<dyn TRunProgram>,
+    loc: &Srcloc,
+) -> Evaluator {
+    // Setup the stdenv
+    let starter_empty_program = program_with_helper(
+        vec![
+            Rc::new(SExp::atom_from_string(loc.clone(), "if")),
+            Rc::new(SExp::atom_from_string(loc.clone(), "list")),
+        ],
+        Rc::new(SExp::Cons(
+            loc.clone(),
+            Rc::new(SExp::atom_from_string(loc.clone(), "defconstant")),
+            Rc::new(SExp::Cons(
+                loc.clone(),
+                Rc::new(SExp::atom_from_string(loc.clone(), "$interpreter-version")),
+                Rc::new(SExp::Cons(
+                    loc.clone(),
+                    Rc::new(SExp::atom_from_string(
+                        loc.clone(),
+                        env!("CARGO_PKG_VERSION"),
+                    )),
+                    Rc::new(SExp::Nil(loc.clone())),
+                )),
+            )),
+        )),
+    );
+    let start_program_fe = frontend(opts.clone(), &[starter_empty_program]).unwrap();
+    Evaluator::new(opts, runner, start_program_fe.helpers)
+}
+
+// A one line description of a helper: its kind, name and arguments.
+fn describe_helper(h: &HelperForm) -> String {
+    let name = decode_string(h.name());
+    match h {
+        HelperForm::Defconstant(_) => format!("defconstant {name}"),
+        HelperForm::Defmacro(mac) => format!("defmacro {name} {}", mac.args),
+        HelperForm::Defun(true, defun) => format!("defun-inline {name} {}", defun.orig_args),
+        HelperForm::Defun(false, defun) => format!("defun {name} {}", defun.orig_args),
     }
-    count
 }
 
 impl Repl {
@@ -96,89 +152,400 @@ impl Repl {
             toplevel_forms.insert(w.to_string());
         }
 
-        // Setup the stdenv
-        let starter_empty_program = program_with_helper(
-            vec![
-                Rc::new(SExp::atom_from_string(loc.clone(), "if")),
-                Rc::new(SExp::atom_from_string(loc.clone(), "list")),
-            ],
-            Rc::new(SExp::Cons(
-                loc.clone(),
-                Rc::new(SExp::atom_from_string(loc.clone(), "defconstant")),
-                Rc::new(SExp::Cons(
-                    loc.clone(),
-                    Rc::new(SExp::atom_from_string(loc.clone(), "$interpreter-version")),
-                    Rc::new(SExp::Cons(
-                        loc.clone(),
-                        Rc::new(SExp::atom_from_string(
-                            loc.clone(),
-                            env!("CARGO_PKG_VERSION"),
-                        )),
-                        Rc::new(SExp::Nil(loc.clone())),
-                    )),
-                )),
-            )),
-        );
-        let start_program_fe = frontend(opts.clone(), &[starter_empty_program]).unwrap();
-        let evaluator = Evaluator::new(opts.clone(), runner.clone(), start_program_fe.helpers);
+        let evaluator = starting_evaluator(opts.clone(), runner.clone(), &loc);
 
         Repl {
-            depth: 0,
-            input_exp: "".to_string(),
+            reader: SExpParser::new(loc.clone()),
             toplevel_forms,
             evaluator,
             opts,
+            runner,
+            defined: Vec::new(),
+            sources: HashMap::new(),
+            execute: false,
+            solution: None,
+            last_cost: None,
             loc,
             stack_limit: Some(EVAL_STACK_LIMIT),
         }
     }
 
+    fn add_helper(&mut self, h: &HelperForm, source: Option<Rc<SExp>>) {
+        self.evaluator.add_helper(h);
+        if let Some(source) = source {
+            self.sources.insert(h.name().clone(), source);
+        }
+        if !self.defined.contains(h.name()) {
+            self.defined.push(h.name().clone());
+        }
+    }
+
+    /// Handle a meta-command, a line starting with a colon given outside of a
+    /// form, giving the text to show for it.  None is given for other input,
+    /// which is for process_line.
+    ///
+    /// - :load file -- define the helpers of a chialisp program, found in the
+    ///   search paths if not at the given path.
+    /// - :include file -- define the helpers of a library as (include file)
+    ///   would.
+    /// - :helpers -- list the helpers defined in the session.
+    /// - :show name -- show the definition of a helper.
+    /// - :save file -- write the helpers defined in the session to a library
+    ///   file, which :load and include can read.
+    /// - :reset -- forget everything defined in the session.
+    /// - :compile expr -- show the CLVM the expression compiles to using the
+    ///   helpers defined, rather than its value.
+    /// - :run and :eval -- switch to execution mode or back to evaluation.
+    /// - :solution args value -- run expressions with the environment value,
+    ///   whose arguments they can refer to by the names in args, as in
+    ///   :solution (X Y) (5 6).  Without args and value, forget it.
+    pub fn meta_command(
+        &mut self,
+        allocator: &mut Allocator,
+        line: &str,
+    ) -> Option<Result<String, CompileErr>> {
+        let line = line.trim();
+        if self.reader.is_incomplete() || !line.starts_with(':') {
+            return None;
+        }
+
+        let (command, rest) = line[1..]
+            .split_once(char::is_whitespace)
+            .unwrap_or((&line[1..], ""));
+        let rest = rest.trim();
+        Some(match (command, rest.is_empty()) {
+            ("load", false) => self.load(rest),
+            ("include", false) => self.include(rest),
+            ("helpers", true) => Ok(self.list_helpers()),
+            ("show", false) => self.show(rest),
+            ("save", false) => self.save(rest),
+            ("reset", true) => {
+                self.reset();
+                Ok("reset".to_string())
+            }
+            ("compile", false) => self.compile(allocator, rest),
+            ("run", true) => {
+                self.set_execute(true);
+                Ok("running compiled code".to_string())
+            }
+            ("eval", true) => {
+                self.set_execute(false);
+                Ok("evaluating".to_string())
+            }
+            ("solution", _) => self.solution_command(rest),
+            _ => Err(CompileErr(
+                self.loc.clone(),
+                format!(
+                    "unknown command {line}, expected :load file, :include file, :helpers, :show name, :save file, :reset, :compile expr, :run, :eval or :solution args value"
+                ),
+            )),
+        })
+    }
+
+    // The form among the elements of source that defines the named helper.
+    fn helper_source(&self, source: &SExp, name: &[u8]) -> Option<Rc<SExp>> {
+        let elements = if let SExp::Cons(_, _, _) = source {
+            source.proper_list()?
+        } else {
+            return None;
+        };
+        elements.into_iter().map(Rc::new).find(|form| {
+            let keyword = first_of_alist(form.clone())
+                .map(|kw| self.toplevel_forms.contains(&kw.to_string()))
+                .unwrap_or(false);
+            keyword
+                && second_of_alist(form.clone())
+                    .map(|n| n.to_string().as_bytes() == name)
+                    .unwrap_or(false)
+        })
+    }
+
+    // Define the helpers of a frontend form whose source is in a real file,
+    // as opposed to the standard environment's, giving their names.  Their
+    // definitions are looked for among the elements of source.
+    fn define_helpers(&mut self, forms: &[Rc<SExp>], source: &SExp) -> Result<String, CompileErr> {
+        let program = frontend(self.opts.set_frontend_check_live(false), forms)?;
+        let mut names = Vec::new();
+        for h in program.helpers.iter() {
+            if !h.loc().file.starts_with('*') {
+                let helper_source = self.helper_source(source, h.name());
+                self.add_helper(h, helper_source);
+                names.push(decode_string(h.name()));
+            }
+        }
+        Ok(format!("defined {}", names.join(" ")))
+    }
+
+    fn load(&mut self, filename: &str) -> Result<String, CompileErr> {
+        let (path, content) = match fs::read_to_string(filename) {
+            Ok(content) => (filename.to_string(), content),
+            Err(_) => self
+                .opts
+                .read_new_file(self.opts.filename(), filename.to_string())?,
+        };
+        let parsed = parse_sexp(Srcloc::start(&path), content.bytes())?;
+        let source = parsed
+            .first()
+            .cloned()
+            .unwrap_or_else(|| Rc::new(SExp::Nil(self.loc.clone())));
+
+        // A library, such as one written by :save, is a list of helpers to
+        // be given to a mod.
+        let is_mod = first_of_alist(source.clone())
+            .map(|head| head.to_string() == "mod")
+            .unwrap_or(false);
+        if is_mod {
+            return self.define_helpers(&parsed, &source);
+        }
+
+        let l = source.loc();
+        let mut mod_form = vec![
+            Rc::new(SExp::atom_from_string(l.clone(), "mod")),
+            Rc::new(SExp::Nil(l.clone())),
+        ];
+        mod_form.append(
+            &mut source
+                .proper_list()
+                .unwrap_or_default()
+                .into_iter()
+                .map(Rc::new)
+                .collect(),
+        );
+        mod_form.push(Rc::new(SExp::Nil(l.clone())));
+        self.define_helpers(&[Rc::new(enlist(l, mod_form))], &source)
+    }
+
+    // The helpers defined in the session, as the text of a library that
+    // :load and include can read.
+    fn library_text(&self) -> String {
+        let mut text = "(\n".to_string();
+        for name in self.defined.iter() {
+            let source = self.sources.get(name).map(|s| s.to_string()).or_else(|| {
+                self.evaluator
+                    .helpers()
+                    .iter()
+                    .find(|h| h.name() == name)
+                    .map(|h| h.to_sexp().to_string())
+            });
+            if let Some(source) = source {
+                text += &format!("  {source}\n");
+            }
+        }
+        text + ")\n"
+    }
+
+    fn save(&self, filename: &str) -> Result<String, CompileErr> {
+        fs::write(filename, self.library_text()).map_err(|e| {
+            CompileErr(self.loc.clone(), format!("could not write {filename}: {e}"))
+        })?;
+        Ok(format!(
+            "saved {} helpers to {filename}",
+            self.defined.len()
+        ))
+    }
+
+    fn include(&mut self, filename: &str) -> Result<String, CompileErr> {
+        let parsed = parse_sexp(
+            self.loc.clone(),
+            format!("(mod () (include {filename}) ())").bytes(),
+        )?;
+        let (path, content) = self
+            .opts
+            .read_new_file(self.opts.filename(), filename.to_string())?;
+        let library = parse_sexp(Srcloc::start(&path), content.bytes())?;
+        let source = library
+            .first()
+            .cloned()
+            .unwrap_or_else(|| Rc::new(SExp::Nil(self.loc.clone())));
+        self.define_helpers(&parsed, &source)
+    }
+
+    fn list_helpers(&self) -> String {
+        self.evaluator
+            .helpers()
+            .iter()
+            .filter(|h| self.defined.contains(h.name()))
+            .map(describe_helper)
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn show(&self, name: &str) -> Result<String, CompileErr> {
+        if let Some(source) = self.sources.get(name.as_bytes()) {
+            return Ok(source.to_string());
+        }
+
+        self.evaluator
+            .helpers()
+            .iter()
+            .find(|h| h.name() == name.as_bytes())
+            .map(|h| h.to_sexp().to_string())
+            .ok_or_else(|| CompileErr(self.loc.clone(), format!("no helper named {name}")))
+    }
+
+    /// Forget the helpers defined and any partial input, leaving only the
+    /// standard environment.
+    pub fn reset(&mut self) {
+        self.evaluator = starting_evaluator(self.opts.clone(), self.runner.clone(), &self.loc);
+        self.defined.clear();
+        self.sources.clear();
+        self.discard_input();
+    }
+
+    fn compile(&self, allocator: &mut Allocator, expr: &str) -> Result<String, CompileErr> {
+        let parsed = parse_sexp(self.loc.clone(), format!("(mod () {expr})").bytes())?;
+        let program = frontend(self.opts.clone(), &parsed)?;
+        self.compile_program(allocator, program)
+            .map(|code| code.to_string())
+    }
+
+    // Generate code for a program with the helpers defined in the session it
+    // uses, along with its own.
+    fn compile_program(
+        &self,
+        allocator: &mut Allocator,
+        mut program: CompileForm,
+    ) -> Result<Rc<SExp>, CompileErr> {
+        let mut helpers = self.evaluator.helpers().to_vec();
+        helpers.append(&mut program.helpers);
+        program.helpers = live_helpers(&helpers, program.exp.as_ref());
+        codegen(
+            allocator,
+            self.runner.clone(),
+            self.opts.clone(),
+            &program,
+            &mut HashMap::new(),
+        )
+        .map(Rc::new)
+    }
+
+    fn solution_command(&mut self, rest: &str) -> Result<String, CompileErr> {
+        let parsed = parse_sexp(self.loc.clone(), rest.bytes())?;
+        match parsed.as_slice() {
+            [] => {
+                self.solution = None;
+                Ok("no solution".to_string())
+            }
+            [args, value] => {
+                self.set_solution(args.clone(), value.clone());
+                Ok(format!("solution {args} = {value}"))
+            }
+            _ => Err(CompileErr(
+                self.loc.clone(),
+                "expected :solution args value, as in :solution (X Y) (5 6)".to_string(),
+            )),
+        }
+    }
+
+    /// Choose whether expressions are compiled and run on clvmr (true) or
+    /// simplified by the Evaluator (false, the default).
+    pub fn set_execute(&mut self, execute: bool) {
+        self.execute = execute;
+    }
+
+    /// Set the environment expressions are run in for execution mode: args is
+    /// the argument list naming its parts, as in a mod, and value the
+    /// environment itself.
+    pub fn set_solution(&mut self, args: Rc<SExp>, value: Rc<SExp>) {
+        self.solution = Some((args, value));
+    }
+
+    /// The exact CLVM cost of the last expression run in execution mode.
+    pub fn last_cost(&self) -> Option<Cost> {
+        self.last_cost
+    }
+
+    // Compile a program, taking the solution's arguments when it doesn't name
+    // its own, and run it in the solution on clvmr.
+    fn run_program(
+        &mut self,
+        allocator: &mut Allocator,
+        mut program: CompileForm,
+    ) -> Result<Rc<BodyForm>, CompileErr> {
+        let env = if let Some((args, value)) = &self.solution {
+            if let SExp::Nil(_) = program.args.as_ref() {
+                program.args = args.clone();
+            }
+            value.clone()
+        } else {
+            Rc::new(SExp::Nil(self.loc.clone()))
+        };
+
+        let code = self.compile_program(allocator, program)?;
+        let (cost, result) = run_on_clvmr(allocator, self.runner.clone(), code, env)?;
+        self.last_cost = Some(cost);
+        Ok(Rc::new(BodyForm::Quoted(result.as_ref().clone())))
+    }
+
     /// There is a stack depth limit in Evaluator which limits the depth to which
     /// evaluation can take place.  This configures that.
     pub fn set_stack_limit(&mut self, l: Option<usize>) {
         self.stack_limit = l;
     }
 
-    /// Process one line of input.  If the line ends with the parenthesis depth
-    /// greater than zero, then the data is taken, but no attempt is made at
-    /// parsing.  When a line ends with parenthesis depth 0, parsing is done.
+    /// Whether the input given so far ends inside a list or string, so that
+    /// process_line is waiting for more.
+    pub fn is_incomplete(&self) -> bool {
+        self.reader.is_incomplete()
+    }
+
+    /// Handle a line of input as the repl binary does, giving the text to
+    /// show for it: a meta-command's text, the result of a form, preceded by
+    /// its cost in execution mode, or its failure.  Nothing is shown while a
+    /// form is unfinished.
+    pub fn respond(&mut self, allocator: &mut Allocator, line: String) -> String {
+        match self.meta_command(allocator, &line) {
+            Some(Ok(text)) => text,
+            Some(Err(e)) => format!("failed: {e:?}"),
+            None => match self.process_line(allocator, line) {
+                Ok(Some(result)) => {
+                    if let Some(cost) = self.last_cost() {
+                        format!("cost = {cost}\n{}", result.to_sexp())
+                    } else {
+                        result.to_sexp().to_string()
+                    }
+                }
+                Ok(None) => "".to_string(),
+                Err(e) => format!("failed: {e:?}"),
+            },
+        }
+    }
+
+    /// Forget input that ended inside a list or string, as when a line being
+    /// typed is interrupted.
+    pub fn discard_input(&mut self) {
+        self.reader = SExpParser::new(self.loc.clone());
+    }
+
+    /// Process one line of input.  The line is parsed along with any input
+    /// before it that ended inside a list or string.  If it still ends inside
+    /// one, nothing more is done and None is given, while input that can't
+    /// parse is an error and is discarded.
     ///
-    /// SExp's parse_sexp is used, which can handle parsing of multiple complete
-    /// forms in the same text.  The forms will be handled one at a time, first
-    /// checking whether they qualify as HelperForms and second treating each
-    /// as an expression.
+    /// Input can hold multiple complete forms.  The forms will be handled one
+    /// at a time, first checking whether they qualify as HelperForms and
+    /// second treating each as an expression, which is run in execution mode.
     pub fn process_line(
         &mut self,
         allocator: &mut Allocator,
         line: String,
     ) -> Result<Option<Rc<BodyForm>>, CompileErr> {
-        self.depth += count_depth(&line);
-
-        let mut input_taken = String::new();
-        swap(&mut input_taken, &mut self.input_exp);
-        let input_taken = input_taken + "\n" + &line;
-
-        if self.depth < 0 {
-            let loc = self.loc.clone();
-            let result = parse_sexp(loc, input_taken.bytes())
-                .map(|_v| {
-                    panic!("too many parens but parsed anyway");
-                })
-                .err_into();
-            self.input_exp = "".to_string();
-            self.depth = 0;
-            return result;
+        if let Err((l, e)) = self.reader.feed(line.bytes().chain(std::iter::once(b'\n'))) {
+            self.reader = SExpParser::new(self.loc.clone());
+            return Err(CompileErr(l, e));
         }
 
-        if self.depth > 0 {
-            self.input_exp = input_taken;
+        if self.reader.is_incomplete() {
             return Ok(None);
         }
 
-        self.input_exp = "".to_string();
+        self.last_cost = None;
 
-        parse_sexp(self.loc.clone(), input_taken.bytes())
-            .err_into()
+        let mut reader = SExpParser::new(self.loc.clone());
+        swap(&mut reader, &mut self.reader);
+        reader
+            .finish()
+            .map_err(CompileErr::from)
             .and_then(|parsed_program| {
                 if parsed_program.is_empty() {
                     return Ok(None);
@@ -191,11 +558,13 @@ impl Repl {
                 if is_helper {
                     let prog0 = parsed_program[0].clone();
                     let name = second_of_alist(prog0.clone())?;
-                    let built_program = program_with_helper(vec![name], prog0);
+                    let built_program = program_with_helper(vec![name], prog0.clone());
                     let program = frontend(self.opts.clone(), &[built_program])?;
-                    self.evaluator
-                        .add_helper(&program.helpers[program.helpers.len() - 1]);
+                    self.add_helper(&program.helpers[program.helpers.len() - 1], Some(prog0));
                     Ok(Some(Rc::new(BodyForm::Quoted(SExp::Nil(self.loc.clone())))))
+                } else if self.execute {
+                    let program = frontend(self.opts.clone(), &parsed_program)?;
+                    self.run_program(allocator, program).map(Some)
                 } else {
                     frontend(self.opts.clone(), &parsed_program)
                         .and_then(|program| {
@@ -213,3 +582,64 @@ impl Repl {
             })
     }
 }
+
+/// A line of a replayed transcript whose output wasn't the one recorded.
+#[derive(Debug, Clone)]
+pub struct ReplayMismatch {
+    /// The line number of the input in the transcript.
+    pub line: usize,
+    pub input: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+fn prompted_input(line: &str) -> Option<&str> {
+    [">>> ", "... "]
+        .iter()
+        .find_map(|prompt| line.strip_prefix(prompt))
+        .or_else(|| {
+            if line == ">>>" || line == "..." {
+                Some("")
+            } else {
+                None
+            }
+        })
+}
+
+/// Run a transcript of a repl session, doctest style, giving how many inputs
+/// were run and the ones whose output differed from the output recorded.
+///
+/// Lines starting with the prompt, >>> or ..., are input and the lines
+/// after one up to the next prompt are the output expected for it, which is
+/// compared to what respond gives, ignoring surrounding blank lines.  Lines before
+/// the first prompt are ignored.
+pub fn replay_transcript(
+    allocator: &mut Allocator,
+    repl: &mut Repl,
+    transcript: &str,
+) -> (usize, Vec<ReplayMismatch>) {
+    let mut inputs: Vec<(usize, String, Vec<String>)> = Vec::new();
+    for (i, line) in transcript.lines().enumerate() {
+        if let Some(input) = prompted_input(line) {
+            inputs.push((i + 1, input.to_string(), Vec::new()));
+        } else if let Some((_, _, expected)) = inputs.last_mut() {
+            expected.push(line.to_string());
+        }
+    }
+
+    let mut mismatches = Vec::new();
+    for (line, input, expected) in inputs.iter() {
+        let expected = expected.join("\n").trim().to_string();
+        let actual = repl.respond(allocator, input.clone()).trim().to_string();
+        if expected != actual {
+            mismatches.push(ReplayMismatch {
+                line: *line,
+                input: input.clone(),
+                expected,
+                actual,
+            });
+        }
+    }
+
+    (inputs.len(), mismatches)
+}
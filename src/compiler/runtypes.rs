@@ -1,6 +1,7 @@
 use std::fmt::Display;
 use std::rc::Rc;
 
+use crate::compiler::comptypes::CompileErr;
 use crate::compiler::sexp::SExp;
 use crate::compiler::srcloc::Srcloc;
 
@@ -27,3 +28,12 @@ impl Display for RunFailure {
         Ok(())
     }
 }
+
+impl From<RunFailure> for CompileErr {
+    fn from(e: RunFailure) -> Self {
+        match e {
+            RunFailure::RunErr(l, e) => CompileErr(l, e),
+            RunFailure::RunExn(l, e) => CompileErr(l, format!("exception {e}")),
+        }
+    }
+}
@@ -1,8 +1,5 @@
-#[cfg(test)]
 use rand::distributions::Standard;
-#[cfg(test)]
 use rand::prelude::Distribution;
-#[cfg(test)]
 use rand::Rng;
 
 use std::borrow::Borrow;
@@ -45,7 +42,7 @@ pub enum SExp {
     Atom(Srcloc, Vec<u8>),
 }
 
-#[cfg(test)]
+/// A random lowercase name at least min_size letters long.
 pub fn random_atom_name<R: Rng + ?Sized>(rng: &mut R, min_size: usize) -> Vec<u8> {
     let mut bytevec: Vec<u8> = Vec::new();
     let mut len = 0;
@@ -62,12 +59,12 @@ pub fn random_atom_name<R: Rng + ?Sized>(rng: &mut R, min_size: usize) -> Vec<u8
     bytevec
 }
 
-#[cfg(test)]
+/// A random atom with a lowercase name.
 pub fn random_atom<R: Rng + ?Sized>(rng: &mut R) -> SExp {
     SExp::Atom(Srcloc::start("*rng*"), random_atom_name(rng, 1))
 }
 
-#[cfg(test)]
+/// A random tree of lists, conses and atoms, where remaining bounds its size.
 pub fn random_sexp<R: Rng + ?Sized>(rng: &mut R, remaining: usize) -> SExp {
     if remaining < 2 {
         random_atom(rng)
@@ -106,7 +103,6 @@ pub fn random_sexp<R: Rng + ?Sized>(rng: &mut R, remaining: usize) -> SExp {
 }
 
 // Thanks: https://stackoverflow.com/questions/48490049/how-do-i-choose-a-random-value-from-an-enum
-#[cfg(test)]
 impl Distribution<SExp> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> SExp {
         random_sexp(rng, MAX_SEXP_COST)
@@ -768,46 +764,88 @@ fn parse_sexp_step(loc: Srcloc, p: &SExpParseState, this_char: u8) -> SExpParseR
     }
 }
 
-fn parse_sexp_inner<I>(
-    mut start: Srcloc,
-    mut parse_state: SExpParseState,
-    s: I,
-) -> Result<Vec<Rc<SExp>>, (Srcloc, String)>
-where
-    I: Iterator<Item = u8>,
-{
-    let mut res = Vec::new();
+/// A parser for chialisp input given a piece at a time, such as lines typed
+/// into a repl.  It keeps parse_sexp's state between pieces, so input that
+/// ends inside a list or string can be told apart from input that can never
+/// parse.
+#[derive(Debug)]
+pub struct SExpParser {
+    loc: Srcloc,
+    state: SExpParseState,
+    forms: Vec<Rc<SExp>>,
+}
+
+impl SExpParser {
+    pub fn new(start: Srcloc) -> Self {
+        SExpParser {
+            loc: start,
+            state: SExpParseState::Empty,
+            forms: Vec::new(),
+        }
+    }
 
-    for this_char in s {
-        let next_location = start.clone().advance(this_char);
+    /// Parse more input.  An error means the input can't be completed into
+    /// valid forms.
+    pub fn feed<I>(&mut self, input: I) -> Result<(), (Srcloc, String)>
+    where
+        I: Iterator<Item = u8>,
+    {
+        for this_char in input {
+            let next_location = self.loc.clone().advance(this_char);
 
-        match parse_sexp_step(start.clone(), parse_state.borrow(), this_char) {
-            SExpParseResult::Error(l, e) => {
-                return Err((l, e));
-            }
-            SExpParseResult::Resume(new_parse_state) => {
-                start = next_location;
-                parse_state = new_parse_state;
-            }
-            SExpParseResult::Emit(o, new_parse_state) => {
-                start = next_location;
-                parse_state = new_parse_state;
-                res.push(o);
+            match parse_sexp_step(self.loc.clone(), self.state.borrow(), this_char) {
+                SExpParseResult::Error(l, e) => {
+                    return Err((l, e));
+                }
+                SExpParseResult::Resume(new_parse_state) => {
+                    self.loc = next_location;
+                    self.state = new_parse_state;
+                }
+                SExpParseResult::Emit(o, new_parse_state) => {
+                    self.loc = next_location;
+                    self.state = new_parse_state;
+                    self.forms.push(o);
+                }
             }
         }
+
+        Ok(())
     }
 
-    match parse_state {
-        SExpParseState::Empty => Ok(res),
-        SExpParseState::Bareword(l, t) => Ok(vec![Rc::new(make_atom(l, t))]),
-        SExpParseState::CommentText(_, _) => Ok(res),
-        SExpParseState::QuotedText(l, _, _) => Err((l, "unterminated quoted string".to_string())),
-        SExpParseState::QuotedEscaped(l, _, _) => {
-            Err((l, "unterminated quoted string with escape".to_string()))
+    /// Whether the input so far ends inside a list or a string, so more is
+    /// needed to finish it.
+    pub fn is_incomplete(&self) -> bool {
+        !matches!(
+            self.state,
+            SExpParseState::Empty
+                | SExpParseState::CommentText(_, _)
+                | SExpParseState::Bareword(_, _)
+        )
+    }
+
+    /// Finish parsing, giving the forms read or an error if the input ended
+    /// inside a form.
+    pub fn finish(self) -> Result<Vec<Rc<SExp>>, (Srcloc, String)> {
+        let mut forms = self.forms;
+        match self.state {
+            SExpParseState::Empty => Ok(forms),
+            SExpParseState::Bareword(l, t) => {
+                forms.push(Rc::new(make_atom(l, t)));
+                Ok(forms)
+            }
+            SExpParseState::CommentText(_, _) => Ok(forms),
+            SExpParseState::QuotedText(l, _, _) => {
+                Err((l, "unterminated quoted string".to_string()))
+            }
+            SExpParseState::QuotedEscaped(l, _, _) => {
+                Err((l, "unterminated quoted string with escape".to_string()))
+            }
+            SExpParseState::OpenList(l) => Err((l, "Unterminated list (empty)".to_string())),
+            SExpParseState::ParsingList(l, _, _) => Err((l, "Unterminated mid list".to_string())),
+            SExpParseState::TermList(l, _, _, _, _) => {
+                Err((l, "Unterminated tail list".to_string()))
+            }
         }
-        SExpParseState::OpenList(l) => Err((l, "Unterminated list (empty)".to_string())),
-        SExpParseState::ParsingList(l, _, _) => Err((l, "Unterminated mid list".to_string())),
-        SExpParseState::TermList(l, _, _, _, _) => Err((l, "Unterminated tail list".to_string())),
     }
 }
 
@@ -820,5 +858,7 @@ pub fn parse_sexp<I>(start: Srcloc, input: I) -> Result<Vec<Rc<SExp>>, (Srcloc,
 where
     I: Iterator<Item = u8>,
 {
-    parse_sexp_inner(start, SExpParseState::Empty, input)
+    let mut parser = SExpParser::new(start);
+    parser.feed(input)?;
+    parser.finish()
 }
@@ -236,3 +236,41 @@ fn combine_src_location(a: &Srcloc, b: &Srcloc) -> Srcloc {
         _ => add_onto(b, a),
     }
 }
+
+// Parse "file(line):col" into its parts.
+fn parse_src_point(s: &str) -> Option<(&str, usize, usize)> {
+    let paren = s.rfind('(')?;
+    let (line, col) = s[paren + 1..].split_once("):")?;
+    Some((&s[..paren], line.parse().ok()?, col.parse().ok()?))
+}
+
+/// Parse the string form of a srcloc, as found in symbol tables, back into a
+/// Srcloc.  Both the single point and range forms are accepted.
+pub fn parse_srcloc(s: &str) -> Option<Srcloc> {
+    let (tail_file, until_line, until_col) = parse_src_point(s)?;
+
+    // A range repeats the file name, as in "f(1):2-f(1):5", so look for a
+    // split where the start names the same file as the end.
+    for (i, _) in tail_file.match_indices('-') {
+        let file = &tail_file[i + 1..];
+        if let Some((start_file, line, col)) = parse_src_point(&tail_file[..i]) {
+            if start_file == file {
+                return Some(Srcloc {
+                    file: Rc::new(file.to_string()),
+                    line,
+                    col,
+                    until: Some(Until {
+                        line: until_line,
+                        col: until_col,
+                    }),
+                });
+            }
+        }
+    }
+
+    Some(Srcloc::new(
+        Rc::new(tail_file.to_string()),
+        until_line,
+        until_col,
+    ))
+}
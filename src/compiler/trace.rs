@@ -0,0 +1,217 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
+use num_bigint::ToBigInt;
+
+use crate::classic::clvm::__type_compatibility__::{Bytes, BytesFromType};
+use crate::classic::clvm_tools::debug::{TraceRecord, TraceSink};
+use crate::classic::clvm_tools::stages::stage_0::TRunProgram;
+
+use crate::compiler::clvm::{
+    check_max_cost, convert_to_clvm_rs, run_step, sha256tree, start_step, truthy, RunStep,
+};
+use crate::compiler::debug::function_name_from_symbol_table;
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::SExp;
+
+/// One evaluation in a run: an operator applied to evaluated arguments, an
+/// environment path looked up or a quoted value.
+///
+/// Applications of the apply operator are finished when the applied program
+/// returns, so their cost includes everything run on their behalf.
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    /// The operator consed onto its evaluated arguments, the quote form or
+    /// the path.
+    pub form: Rc<SExp>,
+    pub env: Rc<SExp>,
+    pub result: Rc<SExp>,
+    pub cost: Cost,
+    /// How deeply nested the evaluation is in the run.
+    pub depth: usize,
+    /// The chialisp function being applied or, for other evaluations, the
+    /// innermost function they're part of, if known from the symbol table.
+    pub function: Option<String>,
+}
+
+impl TraceEntry {
+    /// The entry as a TraceRecord, with its values in allocator.
+    pub fn to_record(&self, allocator: &mut Allocator) -> Result<TraceRecord, RunFailure> {
+        Ok(TraceRecord {
+            form: convert_to_clvm_rs(allocator, self.form.clone())?,
+            env: convert_to_clvm_rs(allocator, self.env.clone())?,
+            result: Some(convert_to_clvm_rs(allocator, self.result.clone())?),
+            symbol: self.function.clone(),
+            depth: Some(self.depth),
+            cost: Some(self.cost),
+        })
+    }
+}
+
+struct PendingApply {
+    entry: TraceEntry,
+    start_cost: Cost,
+}
+
+/// Turns pairs of successive steps from a run into finished TraceEntry values.
+pub struct StepTracer {
+    symbol_table: HashMap<String, String>,
+    pending: Vec<PendingApply>,
+}
+
+fn operator_form(head: &Rc<SExp>, tail: &Rc<SExp>) -> Rc<SExp> {
+    Rc::new(SExp::Cons(head.loc(), head.clone(), tail.clone()))
+}
+
+impl StepTracer {
+    pub fn new(symbol_table: HashMap<String, String>) -> Self {
+        StepTracer {
+            symbol_table,
+            pending: Vec::new(),
+        }
+    }
+
+    fn current_function(&self) -> Option<String> {
+        self.pending
+            .iter()
+            .rev()
+            .find_map(|p| p.entry.function.clone())
+    }
+
+    fn entry(
+        &self,
+        form: Rc<SExp>,
+        env: &Rc<SExp>,
+        result: Rc<SExp>,
+        cost: Cost,
+        depth: usize,
+    ) -> TraceEntry {
+        TraceEntry {
+            form,
+            env: env.clone(),
+            result,
+            cost,
+            depth,
+            function: self.current_function(),
+        }
+    }
+
+    /// Account for the run having moved from the before step to the after
+    /// step, giving the evaluations that finished.
+    ///
+    /// An evaluation finishing at some depth also finishes the applications of
+    /// programs waiting at that depth, since the evaluation was their body.
+    pub fn observe(&mut self, before: &RunStep, after: &RunStep) -> Vec<TraceEntry> {
+        let depth = before.depth();
+        let spent = after.cost().saturating_sub(before.cost());
+        let finished = match (before, after) {
+            (RunStep::Step(sexp, env, _, _, _), RunStep::OpResult(_, result, _, _, _)) => {
+                if let SExp::Cons(_, _, _) = sexp.borrow() {
+                    None
+                } else {
+                    Some(self.entry(sexp.clone(), env, result.clone(), spent, depth))
+                }
+            }
+            (RunStep::Step(sexp, env, _, _, _), _) => match sexp.borrow() {
+                SExp::Cons(_, head, quoted) if head.get_number().ok() == 1_i32.to_bigint() => {
+                    Some(self.entry(sexp.clone(), env, quoted.clone(), spent, depth))
+                }
+                _ => None,
+            },
+            (RunStep::Op(head, env, tail, None, _, _, _), _) => {
+                let args = tail.proper_list().unwrap_or_default();
+                let op = head.get_number().ok();
+                if let RunStep::OpResult(_, result, _, _, _) = after {
+                    Some(self.entry(operator_form(head, tail), env, result.clone(), spent, depth))
+                } else if op == 3_i32.to_bigint() && args.len() == 3 {
+                    let outcome = if truthy(Rc::new(args[0].clone())) {
+                        &args[1]
+                    } else {
+                        &args[2]
+                    };
+                    Some(self.entry(
+                        operator_form(head, tail),
+                        env,
+                        Rc::new(outcome.clone()),
+                        spent,
+                        depth,
+                    ))
+                } else if let Some((program, _)) = before.applied_program() {
+                    let hash = Bytes::new(Some(BytesFromType::Raw(sha256tree(program)))).hex();
+                    let function = function_name_from_symbol_table(&self.symbol_table, &hash)
+                        .or_else(|| self.current_function());
+                    self.pending.push(PendingApply {
+                        entry: TraceEntry {
+                            form: operator_form(head, tail),
+                            env: env.clone(),
+                            result: Rc::new(SExp::Nil(head.loc())),
+                            cost: 0,
+                            depth,
+                            function,
+                        },
+                        start_cost: before.cost(),
+                    });
+                    None
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let mut result = Vec::new();
+        if let Some(f) = finished {
+            while self.pending.last().map(|p| p.entry.depth) == Some(depth) {
+                if let Some(mut p) = self.pending.pop() {
+                    p.entry.result = f.result.clone();
+                    p.entry.cost = after.cost().saturating_sub(p.start_cost);
+                    result.push(p.entry);
+                }
+            }
+            result.insert(0, f);
+        }
+        result
+    }
+}
+
+/// Run a program to completion with the stepping interpreter, giving sink a
+/// TraceRecord for each evaluation as soon as it's finished.
+///
+/// The records' values are put in an allocator of their own, which is
+/// dropped once the sink has them, so a long trace doesn't fill the run's.
+#[allow(clippy::too_many_arguments)]
+pub fn trace_run(
+    allocator: &mut Allocator,
+    runner: Rc<dyn TRunProgram>,
+    prim_map: Rc<HashMap<Vec<u8>, Rc<SExp>>>,
+    symbol_table: HashMap<String, String>,
+    program: Rc<SExp>,
+    env: Rc<SExp>,
+    max_cost: Option<Cost>,
+    sink: &mut dyn TraceSink,
+) -> Result<Rc<SExp>, RunFailure> {
+    let mut tracer = StepTracer::new(symbol_table);
+    let mut step = start_step(program, env);
+
+    loop {
+        let new_step = run_step(allocator, runner.clone(), prim_map.clone(), &step)?;
+        check_max_cost(&new_step, max_cost)?;
+
+        let entries = tracer.observe(&step, &new_step);
+        if !entries.is_empty() {
+            let mut record_allocator = Allocator::new();
+            for entry in entries.iter() {
+                let record = entry.to_record(&mut record_allocator)?;
+                sink.record(&mut record_allocator, &record);
+            }
+        }
+
+        if let RunStep::Done(_, x, _) = &new_step {
+            return Ok(x.clone());
+        }
+        step = new_step;
+    }
+}
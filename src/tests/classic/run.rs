@@ -10,7 +10,7 @@ use rand::Rng;
 use rand_chacha::ChaChaRng;
 
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -19,10 +19,11 @@ use clvmr::allocator::Allocator;
 
 use crate::classic::clvm::__type_compatibility__::{bi_one, bi_zero, Stream};
 use crate::classic::clvm_tools::binutils::disassemble;
-use crate::classic::clvm_tools::cmds::launch_tool;
+use crate::classic::clvm_tools::cmds::{launch_tool, CldbFormat};
 use crate::classic::clvm_tools::node_path::NodePath;
 
 use crate::compiler::clvm::convert_to_clvm_rs;
+use crate::compiler::coverage::Coverage;
 use crate::compiler::sexp;
 use crate::compiler::sexp::decode_string;
 use crate::util::{number_from_u8, Number};
@@ -851,3 +852,381 @@ fn test_modern_sets_source_file_in_symbols() {
         Some("resources/tests/steprun/fact.cl".to_string())
     );
 }
+
+#[test]
+fn test_brun_profile_attributes_cost_to_functions() {
+    let sym_file = "test_brun_profile_attributes_cost_to_functions.sym".to_string();
+    let folded_file = "test_brun_profile_attributes_cost_to_functions.folded".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let mut s = Stream::new(None);
+    launch_tool(
+        &mut s,
+        &vec![
+            "brun".to_string(),
+            "--profile".to_string(),
+            "-y".to_string(),
+            sym_file.clone(),
+            "--folded-output-file".to_string(),
+            folded_file.clone(),
+            program,
+            "(5)".to_string(),
+        ],
+        "brun",
+        0,
+    );
+    let output = s.get_value().decode();
+    let folded = fs::read_to_string(&folded_file).expect("should have written folded stacks");
+    fs::remove_file(&sym_file).expect("should have existed");
+    fs::remove_file(&folded_file).expect("should have existed");
+
+    let lines: Vec<&str> = output.lines().filter(|l| !l.is_empty()).collect();
+    assert_eq!(lines[0], "120");
+    let total: u64 = lines[1]
+        .strip_prefix("total cost = ")
+        .expect("should report total cost")
+        .parse()
+        .expect("should be a number");
+
+    // Every folded stack starts at the toplevel and the costs sum to the total.
+    let mut folded_total = 0;
+    for line in folded.lines() {
+        let (stack, cost) = line.rsplit_once(' ').expect("should be stack and cost");
+        assert!(stack.starts_with("(main)"));
+        folded_total += cost.parse::<u64>().expect("should be a number");
+    }
+    assert_eq!(folded_total, total);
+    assert!(folded.contains("(main);factorial;factorial;factorial;factorial;factorial;fact-base "));
+
+    // factorial is called 5 times and fact-base once.
+    let factorial_row = lines
+        .iter()
+        .find(|l| l.ends_with("  factorial"))
+        .expect("should have a row for factorial");
+    let fields: Vec<&str> = factorial_row.split_whitespace().collect();
+    assert_eq!(fields[2], "5");
+    assert!(lines.iter().any(|l| l.ends_with("1  fact-base")));
+}
+
+#[test]
+fn test_brun_coverage_merges_runs() {
+    let sym_file = "test_brun_coverage_merges_runs.sym".to_string();
+    let coverage_file = "test_brun_coverage_merges_runs.json".to_string();
+    let lcov_file = "test_brun_coverage_merges_runs.lcov".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let run_with_coverage = |env: &str| {
+        let mut s = Stream::new(None);
+        launch_tool(
+            &mut s,
+            &vec![
+                "brun".to_string(),
+                "-y".to_string(),
+                sym_file.clone(),
+                "--coverage".to_string(),
+                coverage_file.clone(),
+                "--lcov".to_string(),
+                lcov_file.clone(),
+                program.clone(),
+                env.to_string(),
+            ],
+            "brun",
+            0,
+        );
+        // The result comes first, followed by the -y trace.
+        let output = s.get_value().decode();
+        output.lines().next().unwrap_or_default().to_string()
+    };
+
+    // Only the then branch of the if in factorial is taken for 1.
+    assert_eq!(run_with_coverage("(1)"), "1");
+    let lcov = fs::read_to_string(&lcov_file).expect("should have written lcov");
+    assert!(lcov.contains("SF:resources/tests/steprun/fact.cl\n"));
+    assert!(lcov.contains("BRDA:4,0,0,1\nBRDA:4,0,1,0\nBRF:2\nBRH:1\n"));
+
+    assert_eq!(run_with_coverage("(5)"), "120");
+    let coverage = Coverage::from_json(
+        &fs::read_to_string(&coverage_file).expect("should have written coverage"),
+    )
+    .expect("should be coverage json");
+    let lcov = fs::read_to_string(&lcov_file).expect("should have written lcov");
+    fs::remove_file(&sym_file).expect("should have existed");
+    fs::remove_file(&coverage_file).expect("should have existed");
+    fs::remove_file(&lcov_file).expect("should have existed");
+
+    let fact = coverage
+        .files
+        .get("resources/tests/steprun/fact.cl")
+        .expect("should have covered fact.cl");
+    let branch = fact
+        .branches
+        .get(&4)
+        .and_then(|cols| cols.values().next())
+        .expect("should have the branch for the if");
+    assert_eq!((branch.then_taken, branch.else_taken), (2, 4));
+    assert!(fact.lines.values().all(|hits| *hits > 0));
+    assert!(lcov.contains("BRH:2\n"));
+}
+
+#[test]
+fn test_brun_trace_json() {
+    let sym_file = "test_brun_trace_json.sym".to_string();
+    let trace_file = "test_brun_trace_json.jsonl".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let mut s = Stream::new(None);
+    launch_tool(
+        &mut s,
+        &vec![
+            "brun".to_string(),
+            "-y".to_string(),
+            sym_file.clone(),
+            "--trace-json".to_string(),
+            trace_file.clone(),
+            program,
+            "(5)".to_string(),
+        ],
+        "brun",
+        0,
+    );
+    // -y still prints its trace after the result.
+    let output = s.get_value().decode();
+    let mut lines = output.lines();
+    assert_eq!(lines.next(), Some("120"));
+    assert!(lines.any(|l| l.contains("factorial")));
+    let trace = fs::read_to_string(&trace_file).expect("should have written trace");
+    fs::remove_file(&sym_file).expect("should have existed");
+    fs::remove_file(&trace_file).expect("should have existed");
+
+    let entries: Vec<serde_json::Value> = trace
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("each line should be json"))
+        .collect();
+    let last_mul = entries
+        .iter()
+        .rev()
+        .find(|e| e["operator"] == "*")
+        .expect("should have multiplied");
+    assert_eq!(last_mul["args"], serde_json::json!(["5", "24"]));
+    assert_eq!(last_mul["result"], "120");
+    assert_eq!(last_mul["function"], "factorial");
+
+    // The program itself is an apply, so it finishes last with the result.
+    let last = entries.last().expect("should have entries");
+    assert_eq!(last["operator"], "a");
+    assert_eq!(last["result"], "120");
+    assert!(entries
+        .iter()
+        .all(|e| e["cost"].as_u64().is_some() && e["depth"].as_u64().is_some()));
+}
+
+#[test]
+fn test_brun_trace_json_to_stdout() {
+    let mut s = Stream::new(None);
+    launch_tool(
+        &mut s,
+        &vec![
+            "brun".to_string(),
+            "--trace-json".to_string(),
+            "-".to_string(),
+            "(+ (q . 1) 2)".to_string(),
+            "(1000)".to_string(),
+        ],
+        "brun",
+        0,
+    );
+    // The result was handed to the real stdout ahead of the trace, which is
+    // streamed there as the run goes rather than collected in the Stream.
+    let output = s.get_value().decode();
+    assert!(!output.contains("1001"));
+    assert!(!output.lines().any(|l| l.starts_with('{')));
+}
+
+#[test]
+fn test_cldb_output_formats() {
+    let rows: Vec<BTreeMap<String, String>> = vec![
+        [("Operator", "16"), ("Value", "5")],
+        [("Final", "5"), ("Total-Cost", "1054")],
+    ]
+    .iter()
+    .map(|r| {
+        r.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    })
+    .collect();
+
+    assert_eq!(CldbFormat::parse("xml"), None);
+
+    let json = CldbFormat::parse("json")
+        .expect("should exist")
+        .rows_string(rows.clone());
+    let parsed: Vec<BTreeMap<String, String>> =
+        serde_json::from_str(&json).expect("should be a json array");
+    assert_eq!(parsed, rows);
+
+    // Each row is a json object on its own line.
+    let jsonl = CldbFormat::parse("jsonl")
+        .expect("should exist")
+        .rows_string(rows.clone());
+    let parsed: Vec<BTreeMap<String, String>> = jsonl
+        .lines()
+        .map(|l| serde_json::from_str(l).expect("each line should be json"))
+        .collect();
+    assert_eq!(parsed, rows);
+
+    let yaml = CldbFormat::Yaml.rows_string(rows);
+    assert!(yaml.starts_with("---\n- Operator: \"16\""));
+}
+
+#[test]
+fn test_brun_mock() {
+    let sym_file = "test_brun_mock.sym".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let brun_with_mocks = |mocks: &[&str]| {
+        let mut args = vec!["brun".to_string(), "-y".to_string(), sym_file.clone()];
+        for m in mocks.iter() {
+            args.push("--mock".to_string());
+            args.push(m.to_string());
+        }
+        args.push(program.clone());
+        args.push("(4)".to_string());
+        let mut s = Stream::new(None);
+        launch_tool(&mut s, &args, "brun", 0);
+        s.get_value().decode().trim().to_string()
+    };
+
+    // The base case is replaced, so the product ends with 100 instead of 1.
+    assert_eq!(brun_with_mocks(&["fact-base=(* VALUE 100)"]), "2400");
+    // The outermost call is replaced, so nothing else runs.
+    assert_eq!(
+        brun_with_mocks(&["factorial=(+ VALUE 1)", "fact-base=(x)"]),
+        "5"
+    );
+    assert!(brun_with_mocks(&["fact-base=(x VALUE)"]).starts_with("FAIL: mock of fact-base"));
+    assert_eq!(
+        brun_with_mocks(&["missing=1"]),
+        "FAIL: no function named missing in the symbol table"
+    );
+    fs::remove_file(&sym_file).expect("should have existed");
+}
+
+#[test]
+fn test_brun_function() {
+    let sym_file = "test_brun_function.sym".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let brun_function = |args: &[&str]| {
+        let mut brun_args = vec!["brun".to_string()];
+        brun_args.extend(args.iter().map(|a| a.to_string()));
+        brun_args.push(program.clone());
+        brun_args.push("(4)".to_string());
+        let mut s = Stream::new(None);
+        launch_tool(&mut s, &brun_args, "brun", 0);
+        let output = s.get_value().decode();
+        output.lines().next().unwrap_or_default().to_string()
+    };
+
+    // fact-base just gives its argument back, where the program would
+    // compute 4!.
+    assert_eq!(brun_function(&[]), "24");
+    assert_eq!(
+        brun_function(&["--function", "fact-base", "--symbol-table", &sym_file]),
+        "4"
+    );
+    assert_eq!(
+        brun_function(&["--function", "factorial", "-y", &sym_file]),
+        "24"
+    );
+    assert_eq!(
+        brun_function(&["--function", "missing", "-y", &sym_file]),
+        "FAIL: *command*(1):1: function not found in symbols: missing"
+    );
+    assert_eq!(
+        brun_function(&["--function", "fact-base"]),
+        "FAIL: --function needs a --symbol-table"
+    );
+    fs::remove_file(&sym_file).expect("should have existed");
+}
+
+#[test]
+fn test_brun_backtrace_on_raise() {
+    let sym_file = "test_brun_backtrace.sym".to_string();
+    let program = do_basic_run(&vec![
+        "run".to_string(),
+        "--symbol-output-file".to_string(),
+        sym_file.clone(),
+        "resources/tests/steprun/fact_raise.cl".to_string(),
+    ])
+    .trim()
+    .to_string();
+
+    let mut s = Stream::new(None);
+    launch_tool(
+        &mut s,
+        &vec![
+            "brun".to_string(),
+            "-y".to_string(),
+            sym_file.clone(),
+            program.clone(),
+            "(3)".to_string(),
+        ],
+        "brun",
+        0,
+    );
+    let output = s.get_value().decode();
+    let mut lines = output.lines();
+    assert_eq!(
+        lines.next(),
+        Some("FAIL: clvm raise (\"reached the base\" 1)")
+    );
+    assert_eq!(lines.next(), Some("backtrace:"));
+    let frames: Vec<&str> = lines.take(5).collect();
+    assert!(frames[0].starts_with("  0: fact-base (VALUE = 1) at "));
+    assert!(frames[0].contains("fact_raise.cl(3)"));
+    assert!(frames[1].starts_with("  1: factorial (VALUE = 1) at "));
+    assert!(frames[2].starts_with("  2: factorial (VALUE = 2) at "));
+    assert!(frames[3].starts_with("  3: factorial (VALUE = 3) at "));
+    assert!(frames[4].starts_with("  4: (main) (INDEX = 3) at "));
+
+    // Without a symbol table there's nothing to name, so nothing more is said.
+    let plain = do_basic_brun(&vec!["brun".to_string(), program, "(3)".to_string()]);
+    assert_eq!(plain.trim(), "FAIL: clvm raise (\"reached the base\" 1)");
+    fs::remove_file(&sym_file).expect("should have existed");
+}
@@ -1,5 +1,7 @@
 use num_bigint::ToBigInt;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -11,12 +13,18 @@ use crate::classic::clvm::__type_compatibility__::{t, Bytes, BytesFromType, Stre
 use crate::classic::clvm::serialize::{sexp_from_stream, SimpleCreateCLVMObject};
 use crate::classic::clvm::sexp::{First, NodeSel, Rest, SelectNode, ThisNode};
 use crate::classic::clvm_tools::cmds::{launch_tool, OpcConversion, OpdConversion, TConversion};
+use crate::classic::clvm_tools::debug::{
+    trace_pre_eval, trace_to_table, trace_to_text, JsonTraceSink, NullTraceSink, TextTraceSink,
+    TraceRecorder,
+};
 
 use crate::classic::clvm_tools::binutils::{assemble, assemble_from_ir, disassemble};
 use crate::classic::clvm_tools::ir::reader::read_ir;
 use crate::classic::clvm_tools::node_path::NodePath;
 use crate::classic::clvm_tools::stages;
-use crate::classic::clvm_tools::stages::stage_0::{DefaultProgramRunner, TRunProgram};
+use crate::classic::clvm_tools::stages::stage_0::{
+    DefaultProgramRunner, RunProgramOption, TRunProgram,
+};
 use crate::classic::clvm_tools::stages::stage_2::operators::run_program_for_search_paths;
 
 #[test]
@@ -709,3 +717,128 @@ fn test_fancy_destructuring_type_language() {
     assert_eq!(disassemble(&mut allocator, name_by_cons), "88");
     assert_eq!(disassemble(&mut allocator, rest), "((+ 3 1))");
 }
+
+#[test]
+fn test_trace_recorder_sinks() {
+    let mut allocator = Allocator::new();
+    let program = assemble(&mut allocator, "(+ (q . 1) 2)").unwrap();
+    let env = assemble(&mut allocator, "(1000)").unwrap();
+    let recorder = Rc::new(RefCell::new(TraceRecorder::new(None)));
+    let runner = DefaultProgramRunner::new();
+    let result = runner
+        .run_program(
+            &mut allocator,
+            program,
+            env,
+            Some(RunProgramOption {
+                max_cost: None,
+                pre_eval_f: Some(TraceRecorder::pre_eval(recorder.clone())),
+                strict: false,
+            }),
+        )
+        .unwrap();
+    assert_eq!(disassemble(&mut allocator, result.1), "1001");
+
+    let recorder_ref = RefCell::borrow(&recorder);
+    assert_eq!(recorder_ref.records().len(), 3);
+
+    let mut json_output: Vec<u8> = Vec::new();
+    let mut json_sink = JsonTraceSink::new(&mut json_output, &disassemble);
+    recorder_ref.replay(&mut allocator, false, &mut json_sink);
+    assert!(!json_sink.failed);
+    let lines: Vec<serde_json::Value> = std::str::from_utf8(&json_output)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(lines[0]["operator"], "+");
+    assert_eq!(lines[0]["args"], serde_json::json!(["(q . 1)", "2"]));
+    assert_eq!(lines[0]["result"], "1001");
+    assert_eq!(lines[0]["depth"], 1);
+    // The classic hook doesn't give costs.
+    assert!(lines[0].get("cost").is_none());
+    let quoted = lines
+        .iter()
+        .find(|l| l["operator"] == "q")
+        .expect("should have quoted");
+    assert_eq!(quoted["args"], serde_json::json!([]));
+    assert_eq!(quoted["depth"], 2);
+    let path_lookup = lines
+        .iter()
+        .find(|l| l["operator"] == "path")
+        .expect("should have looked up the environment");
+    assert_eq!(path_lookup["args"], serde_json::json!(["2"]));
+    assert_eq!(path_lookup["result"], "1000");
+
+    let mut text = Stream::new(None);
+    recorder_ref.replay(
+        &mut allocator,
+        false,
+        &mut TextTraceSink::new(&mut text, &disassemble),
+    );
+    assert!(text
+        .get_value()
+        .decode()
+        .starts_with("(+ (q . 1) 2) [(1000)] => 1001\n\n"));
+
+    // Only evaluations that didn't finish are given with only_exn.
+    let mut only_exn = Stream::new(None);
+    recorder_ref.replay(
+        &mut allocator,
+        true,
+        &mut TextTraceSink::new(&mut only_exn, &disassemble),
+    );
+    assert_eq!(only_exn.get_value().decode(), "");
+    recorder_ref.replay(&mut allocator, false, &mut NullTraceSink {});
+}
+
+#[test]
+fn test_trace_log_wrappers() {
+    let mut allocator = Allocator::new();
+    let form = assemble(&mut allocator, "(+ (q . 1) 2)").unwrap();
+    let env = assemble(&mut allocator, "(1000)").unwrap();
+
+    let log = RefCell::new(Vec::new());
+    let entry = trace_pre_eval(
+        &mut allocator,
+        &|_, e| log.borrow_mut().push(e),
+        None,
+        form,
+        env,
+    )
+    .unwrap();
+    assert_eq!(log.borrow().len(), 1);
+    assert_eq!(
+        disassemble(&mut allocator, entry.unwrap()),
+        "((+ (q . 1) 2) (1000))"
+    );
+
+    // With a symbol table, only the forms it names are logged.
+    let named: HashMap<String, String> = HashMap::new();
+    let skipped = trace_pre_eval(
+        &mut allocator,
+        &|_, e| log.borrow_mut().push(e),
+        Some(named),
+        form,
+        env,
+    )
+    .unwrap();
+    assert!(skipped.is_none());
+    assert_eq!(log.borrow().len(), 1);
+
+    let finished = assemble(&mut allocator, "((+ (q . 1) 2) (1000) 1001)").unwrap();
+    let trace = vec![finished, log.borrow()[0]];
+    let mut text = Stream::new(None);
+    trace_to_text(&mut allocator, &mut text, false, &trace, None, &disassemble);
+    assert_eq!(
+        text.get_value().decode(),
+        "(+ (q . 1) 2) [(1000)] => 1001\n\n(+ (q . 1) 2) [(1000)] => (didn't finish)\n\n"
+    );
+
+    let mut table = Stream::new(None);
+    trace_to_table(&mut allocator, &mut table, true, &trace, None, &disassemble);
+    assert!(table
+        .get_value()
+        .decode()
+        .starts_with("exp: 16\narg: ((q . 1) 2)\nenv: (1000)\nval: (didn't finish)\n"));
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::cltest::{find_test_files, junit_xml, run_test_file, TestOutcome};
+
+#[test]
+fn test_cltest_finds_test_files() {
+    let found: Vec<String> = find_test_files(Path::new("resources/tests/cltest"))
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            "resources/tests/cltest/failing_test.clsp",
+            "resources/tests/cltest/math_test.clsp",
+            "resources/tests/cltest/nested/program_test.clsp",
+        ]
+    );
+}
+
+#[test]
+fn test_cltest_deftests_pass() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let results = run_test_file(
+        &mut allocator,
+        runner,
+        &["resources/tests".to_string()],
+        "resources/tests/cltest/math_test.clsp",
+    );
+    let names: Vec<String> = results.iter().map(|r| r.name.clone()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "double-doubles",
+            "double-of-constant",
+            "lists-compare",
+            "sha256tree-of-atom"
+        ]
+    );
+    assert!(results.iter().all(|r| r.passed()));
+    assert_eq!(results[0].cost, Some(1806));
+    assert_eq!(results[0].loc.line, 7);
+}
+
+#[test]
+fn test_cltest_failures_and_errors() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let results = run_test_file(
+        &mut allocator,
+        runner,
+        &[],
+        "resources/tests/cltest/failing_test.clsp",
+    );
+    assert_eq!(results.len(), 2);
+    if let TestOutcome::Fail { expected, actual } = &results[0].outcome {
+        assert_eq!(expected.to_string(), "4");
+        assert_eq!(actual.to_string(), "6");
+    } else {
+        panic!("expected a failure, got {:?}", results[0].outcome);
+    }
+    assert_eq!(
+        results[0].to_string(),
+        "FAIL double-is-wrong (cost 1806) at resources/tests/cltest/failing_test.clsp(5):1\n  expected: 4\n  actual:   6"
+    );
+    assert!(matches!(results[1].outcome, TestOutcome::Error(_, _)));
+
+    let xml = junit_xml(&results);
+    assert!(xml.contains("<testsuites tests=\"2\" failures=\"1\" errors=\"1\">"));
+    assert!(xml.contains("<failure message=\"expected 4 but got 6\">"));
+}
+
+#[test]
+fn test_cltest_whole_program_file() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let results = run_test_file(
+        &mut allocator,
+        runner,
+        &[],
+        "resources/tests/cltest/nested/program_test.clsp",
+    );
+    assert_eq!(results.len(), 1);
+    assert!(results[0].passed());
+    assert!(results[0].cost.is_some());
+}
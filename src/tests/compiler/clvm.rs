@@ -8,16 +8,25 @@ use rand_chacha::ChaChaRng;
 use num_bigint::ToBigInt;
 
 use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::fs;
 use std::rc::Rc;
 
 use clvm_rs::allocator::Allocator;
+use clvm_rs::cost::Cost;
 
 use crate::classic::clvm::__type_compatibility__::{bi_one, bi_zero, Bytes, BytesFromType};
 use crate::classic::clvm::casts::{bigint_to_bytes_clvm, bigint_to_bytes_unsigned};
-use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
-
-use crate::compiler::clvm::{parse_and_run, sha256tree};
+use crate::classic::clvm_tools::binutils::assemble_from_ir;
+use crate::classic::clvm_tools::ir::reader::read_ir;
+use crate::classic::clvm_tools::stages::stage_0::{DefaultProgramRunner, TRunProgram};
+
+use crate::compiler::clvm::{
+    parse_and_run, path_lookup_cost, run_on_clvmr, run_with_cost, sha256tree,
+};
+use crate::compiler::compiler::{compile_file, DefaultCompilerOpts};
+use crate::compiler::prims;
+use crate::compiler::profile::profile_run;
 use crate::compiler::runtypes::RunFailure;
 use crate::compiler::sexp::{parse_sexp, SExp};
 use crate::compiler::srcloc::Srcloc;
@@ -233,3 +242,131 @@ fn test_sha256_tree_hash() {
         "156e86309040ed6bbfee805c9c6ca7eebc140490bd1b97d6d18fb8ebc91fd05a"
     );
 }
+
+#[cfg(test)]
+fn run_costs_modern_and_clvmr(to_run: &str, args: &str) -> (Cost, Cost) {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let loc = Srcloc::start("*test*");
+
+    let program = parse_sexp(loc.clone(), to_run.bytes()).expect("should parse");
+    let env = parse_sexp(loc, args.bytes()).expect("should parse");
+    let (modern_cost, _) = run_with_cost(
+        &mut allocator,
+        runner.clone(),
+        prims::prim_map(),
+        program[0].clone(),
+        env[0].clone(),
+        Some(TEST_TIMEOUT),
+        None,
+    )
+    .expect("should run");
+
+    let classic_program = assemble_from_ir(&mut allocator, Rc::new(read_ir(to_run).unwrap()))
+        .expect("should assemble");
+    let classic_env =
+        assemble_from_ir(&mut allocator, Rc::new(read_ir(args).unwrap())).expect("should assemble");
+    let clvmr_cost = runner
+        .run_program(&mut allocator, classic_program, classic_env, None)
+        .expect("should run")
+        .0;
+
+    (modern_cost, clvmr_cost)
+}
+
+#[test]
+fn test_run_step_cost_matches_clvmr() {
+    let programs = [
+        (
+            "(a (q 2 4 (c 2 (c 6 ()))) (c (q 13 26729 \"there\" \"fool\") 1))",
+            "()",
+        ),
+        (
+            "(a (q 2 (q 2 2 (c 2 (c 3 (q)))) (c (q 2 (i 5 (q 4 (q . 4) (c 9 (c (a 2 (c 2 (c 13 (q)))) (q)))) (q 1)) 1) 1)) 1)",
+            "(1 2)",
+        ),
+        ("(2 (3 (1) (1 16 (1 . 1) (1 . 3)) (1 16 (1 . 5) (1 . 8))) 1)", "()"),
+        ("(divmod (1 . 300000003392) (1 . 10000000))", "()"),
+        ("(sha256 2 5 (q . \"hello\"))", "(1 2)"),
+        ("(c (f 2) (r 2))", "((1 2 3))"),
+        ("(concat (substr 2 (q . 1)) (strlen 5) ())", "(\"hello\" \"there\")"),
+        ("(l 2)", "((1 2 3))"),
+        ("(* 2 2 2 (- 2 5))", "(1000000000000 3)"),
+    ];
+
+    for (program, args) in programs.iter() {
+        let (modern_cost, clvmr_cost) = run_costs_modern_and_clvmr(program, args);
+        assert_eq!(modern_cost, clvmr_cost, "cost mismatch for {program}");
+    }
+}
+
+#[test]
+fn test_profile_total_matches_clvmr() {
+    let filename = "resources/tests/steprun/fact.cl";
+    let content = fs::read_to_string(filename).expect("should exist");
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(filename));
+    let mut symbol_table = HashMap::new();
+    let program = Rc::new(
+        compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts,
+            &content,
+            &mut symbol_table,
+        )
+        .expect("should compile"),
+    );
+    let loc = program.loc();
+    let env = parse_sexp(loc, "(5)".bytes()).expect("should parse");
+
+    let (profiler, result) = profile_run(
+        &mut allocator,
+        runner.clone(),
+        prims::prim_map(),
+        symbol_table,
+        program.clone(),
+        env[0].clone(),
+        None,
+    );
+    let (clvmr_cost, clvmr_result) =
+        run_on_clvmr(&mut allocator, runner, program, env[0].clone()).expect("should run");
+
+    assert_eq!(result.expect("should run"), clvmr_result);
+    assert_eq!(profiler.total(), clvmr_cost);
+    // The per function inclusive costs can't add up to more than the run.
+    assert!(profiler
+        .functions()
+        .iter()
+        .all(|(_, f)| f.inclusive <= clvmr_cost));
+}
+
+#[test]
+fn test_path_lookup_cost() {
+    assert_eq!(path_lookup_cost(&[]), 44);
+    assert_eq!(path_lookup_cost(&[1]), 44);
+    assert_eq!(path_lookup_cost(&[2]), 48);
+    assert_eq!(path_lookup_cost(&[5]), 52);
+    assert_eq!(path_lookup_cost(&[0, 1]), 48);
+    assert_eq!(path_lookup_cost(&[1, 0]), 76);
+}
+
+#[test]
+fn test_run_step_max_cost() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let loc = Srcloc::start("*test*");
+    let program = parse_sexp(loc.clone(), "(sha256 2 5)".bytes()).expect("should parse");
+    let env = parse_sexp(loc, "(1 2)".bytes()).expect("should parse");
+    let result = run_with_cost(
+        &mut allocator,
+        runner,
+        prims::prim_map(),
+        program[0].clone(),
+        env[0].clone(),
+        Some(TEST_TIMEOUT),
+        Some(100),
+    );
+    assert!(matches!(result, Err(RunFailure::RunErr(_, e)) if e == "cost exceeded"));
+}
@@ -5,7 +5,7 @@ use clvm_rs::allocator::Allocator;
 
 use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
 use crate::compiler::clvm::run;
-use crate::compiler::compiler::{compile_file, DefaultCompilerOpts};
+use crate::compiler::compiler::{compile_file, compose_run_function, DefaultCompilerOpts};
 use crate::compiler::comptypes::{CompileErr, CompilerOpts};
 use crate::compiler::runtypes::RunFailure;
 use crate::compiler::sexp::{parse_sexp, SExp};
@@ -1192,3 +1192,52 @@ fn test_inline_out_of_bounds_diagnostic() {
         assert!(false);
     }
 }
+
+#[test]
+fn test_compose_run_function() {
+    let prog = indoc! {"
+(mod (N)
+  (include *standard-cl-21*)
+  (defun fact (N) (if (= N 0) 1 (* N (fact (- N 1)))))
+  (defun add3 (A B C) (+ A B C))
+  (add3 (fact N) 1 2)
+  )"};
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(&"*test*".to_string()));
+    let srcloc = Srcloc::start(&"*test*".to_string());
+    let mut symbol_table = HashMap::new();
+    let program = Rc::new(
+        compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts,
+            prog,
+            &mut symbol_table,
+        )
+        .expect("should compile"),
+    );
+
+    let mut run_function = |name: &str, args: &str| {
+        let composed = compose_run_function(program.clone(), &symbol_table, name)?;
+        let sexp_args = parse_sexp(srcloc.clone(), args.bytes())?[0].clone();
+        run(
+            &mut allocator,
+            runner.clone(),
+            Rc::new(HashMap::new()),
+            composed,
+            sexp_args,
+            Some(TEST_TIMEOUT),
+        )
+        .map(|r| r.to_string())
+        .map_err(|e| match e {
+            RunFailure::RunErr(l, s) => CompileErr(l, s),
+            RunFailure::RunExn(l, s) => CompileErr(l, s.to_string()),
+        })
+    };
+
+    assert_eq!(run_function("fact", "(5)").unwrap(), "120");
+    assert_eq!(run_function("add3", "(1 2 3)").unwrap(), "6");
+    let missing = run_function("missing", "()").unwrap_err();
+    assert_eq!(missing.1, "function not found in symbols: missing");
+}
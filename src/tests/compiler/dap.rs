@@ -0,0 +1,169 @@
+use std::io::Cursor;
+
+use serde_json::{json, Value};
+
+use crate::compiler::dap::{read_dap_message, serve_dap, write_dap_message};
+
+const PROGRAM: &str = "resources/tests/dap/sum_squares.clsp";
+
+// Play the part of an editor: send every request up front, then read back
+// everything the server said.
+fn run_dap_script(requests: &[Value]) -> Vec<Value> {
+    let mut input = Vec::new();
+    for (i, request) in requests.iter().enumerate() {
+        let mut message = request.clone();
+        message["seq"] = json!(i + 1);
+        message["type"] = json!("request");
+        write_dap_message(&mut input, &message).expect("should write");
+    }
+
+    let mut output = Vec::new();
+    serve_dap(&mut Cursor::new(input), &mut output).expect("should serve");
+
+    let mut reader = Cursor::new(output);
+    let mut messages = Vec::new();
+    while let Some(m) = read_dap_message(&mut reader).expect("should be framed") {
+        messages.push(m);
+    }
+    messages
+}
+
+fn response<'a>(messages: &'a [Value], command: &str) -> Vec<&'a Value> {
+    messages
+        .iter()
+        .filter(|m| m["type"] == "response" && m["command"] == command)
+        .collect()
+}
+
+fn events<'a>(messages: &'a [Value], event: &str) -> Vec<&'a Value> {
+    messages
+        .iter()
+        .filter(|m| m["type"] == "event" && m["event"] == event)
+        .collect()
+}
+
+fn variable_names(variables: &Value) -> Vec<(String, String)> {
+    variables["body"]["variables"]
+        .as_array()
+        .expect("should have variables")
+        .iter()
+        .map(|v| {
+            (
+                v["name"].as_str().unwrap().to_string(),
+                v["value"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_dap_line_breakpoint_scopes_and_exit() {
+    let messages = run_dap_script(&[
+        json!({"command": "initialize", "arguments": {"adapterID": "cldb"}}),
+        json!({"command": "launch", "arguments": {"program": PROGRAM, "args": "(3 4)"}}),
+        json!({"command": "setBreakpoints", "arguments": {
+            "source": {"path": PROGRAM},
+            "breakpoints": [{"line": 6}]
+        }}),
+        json!({"command": "configurationDone"}),
+        json!({"command": "threads"}),
+        json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+        json!({"command": "scopes", "arguments": {"frameId": 0}}),
+        json!({"command": "variables", "arguments": {"variablesReference": 1}}),
+        json!({"command": "variables", "arguments": {"variablesReference": 2}}),
+        json!({"command": "continue", "arguments": {"threadId": 1}}),
+        json!({"command": "disconnect"}),
+    ]);
+
+    assert!(messages
+        .iter()
+        .filter(|m| m["type"] == "response")
+        .all(|m| m["success"] == true));
+    assert_eq!(events(&messages, "initialized").len(), 1);
+    assert_eq!(
+        response(&messages, "setBreakpoints")[0]["body"]["breakpoints"][0]["verified"],
+        true
+    );
+
+    let stopped = events(&messages, "stopped");
+    assert_eq!(stopped.len(), 1);
+    assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+
+    // The let form is inlined, so it has no frame of its own.
+    let frames = &response(&messages, "stackTrace")[0]["body"]["stackFrames"];
+    assert_eq!(frames[0]["name"], "sum-squares");
+    assert_eq!(frames[0]["line"], 6);
+    assert_eq!(frames[0]["source"]["name"], "sum_squares.clsp");
+    assert_eq!(frames[1]["name"], "(main)");
+    assert_eq!(frames[1]["line"], 7);
+
+    let variables = response(&messages, "variables");
+    assert_eq!(
+        variable_names(variables[0]),
+        vec![
+            ("A".to_string(), "3".to_string()),
+            ("B".to_string(), "4".to_string())
+        ]
+    );
+    assert_eq!(
+        variable_names(variables[1]),
+        vec![
+            ("X".to_string(), "3".to_string()),
+            ("Y".to_string(), "4".to_string())
+        ]
+    );
+
+    assert_eq!(events(&messages, "output")[0]["body"]["output"], "25\n");
+    assert_eq!(events(&messages, "exited")[0]["body"]["exitCode"], 0);
+    assert_eq!(events(&messages, "terminated").len(), 1);
+}
+
+#[test]
+fn test_dap_function_breakpoint_and_stepping() {
+    let messages = run_dap_script(&[
+        json!({"command": "initialize"}),
+        json!({"command": "launch", "arguments": {"program": PROGRAM, "args": "(1 2)", "stopOnEntry": true}}),
+        json!({"command": "setFunctionBreakpoints", "arguments": {
+            "breakpoints": [{"name": "sum-squares"}, {"name": "missing"}]
+        }}),
+        json!({"command": "configurationDone"}),
+        json!({"command": "continue", "arguments": {"threadId": 1}}),
+        json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+        json!({"command": "next", "arguments": {"threadId": 1}}),
+        json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+        json!({"command": "evaluate", "arguments": {"expression": "(sum-squares A (+ B 1))"}}),
+        json!({"command": "stepOut", "arguments": {"threadId": 1}}),
+        json!({"command": "evaluate", "arguments": {"expression": "(* X Y)"}}),
+        json!({"command": "restartFrame", "arguments": {"frameId": 0}}),
+        json!({"command": "disconnect"}),
+    ]);
+
+    let breakpoints = &response(&messages, "setFunctionBreakpoints")[0]["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], true);
+    assert_eq!(breakpoints[1]["verified"], false);
+
+    let reasons: Vec<Value> = events(&messages, "stopped")
+        .iter()
+        .map(|e| e["body"]["reason"].clone())
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![json!("entry"), json!("breakpoint"), json!("step")]
+    );
+
+    let traces = response(&messages, "stackTrace");
+    assert_eq!(traces[0]["body"]["stackFrames"][0]["name"], "sum-squares");
+    assert_eq!(traces[1]["body"]["stackFrames"][0]["name"], "sum-squares");
+
+    // Finishing the only function finishes the program.
+    assert_eq!(events(&messages, "output")[0]["body"]["output"], "5\n");
+    assert_eq!(events(&messages, "exited").len(), 1);
+
+    // Expressions can call the program's functions on the arguments in scope,
+    // which are the program's own once the run is outside of any function.
+    let evaluated = response(&messages, "evaluate");
+    assert_eq!(evaluated[0]["body"]["result"], "10");
+    assert_eq!(evaluated[1]["body"]["result"], "2");
+
+    assert_eq!(response(&messages, "restartFrame")[0]["success"], false);
+}
@@ -0,0 +1,660 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::backtrace::{backtrace_run, run_with_backtrace};
+use crate::compiler::cldb::{
+    compile_classic_program, env_changes, fold_unchanged, format_env_changes, is_classic_mod,
+    program_helpers, CldbMockFunction, CldbNoOverride, CldbOverrideBespokeCode, CldbRun,
+    CldbRunEnv, CldbSingleBespokeOverride,
+};
+use crate::compiler::clvm::{run, start_step};
+use crate::compiler::compiler::{compile_file, DefaultCompilerOpts};
+use crate::compiler::debugger::CldbDebugger;
+use crate::compiler::evaluate::Evaluator;
+use crate::compiler::prims;
+use crate::compiler::runtypes::RunFailure;
+use crate::compiler::sexp::parse_sexp;
+use crate::compiler::srcloc::Srcloc;
+
+const FACT_FILE: &str = "resources/tests/steprun/fact.cl";
+const FACT_RAISE_FILE: &str = "resources/tests/steprun/fact_raise.cl";
+
+fn fact_debugger(allocator: &mut Allocator, args: &str) -> CldbDebugger {
+    file_debugger(allocator, FACT_FILE, args)
+}
+
+fn file_debugger(allocator: &mut Allocator, file: &str, args: &str) -> CldbDebugger {
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(file));
+    let content = fs::read_to_string(file).expect("should exist");
+    let mut symbol_table = HashMap::new();
+    let program = compile_file(
+        allocator,
+        runner.clone(),
+        opts.clone(),
+        &content,
+        &mut symbol_table,
+    )
+    .expect("should compile");
+    let args = parse_sexp(Srcloc::start("*args*"), args.bytes()).expect("should parse")[0].clone();
+
+    let mut prim_map = HashMap::new();
+    for p in prims::prims().iter() {
+        prim_map.insert(p.0.clone(), Rc::new(p.1.clone()));
+    }
+    let mut env = CldbRunEnv::new(
+        Some(file.to_string()),
+        content.lines().map(|l| l.to_string()).collect(),
+        Box::new(CldbNoOverride::new()),
+    );
+    env.set_symbol_table(symbol_table.clone());
+    let mut debugger = CldbDebugger::new(
+        runner,
+        Rc::new(prim_map),
+        symbol_table,
+        Box::new(env),
+        start_step(Rc::new(program), args),
+    );
+    debugger.set_reverse_stepping(true);
+    debugger
+        .set_program_source(opts, &content)
+        .expect("should have helpers");
+    debugger
+}
+
+fn stopped(rows: &[BTreeMap<String, String>]) -> BTreeMap<String, String> {
+    rows.last().cloned().expect("should have a row")
+}
+
+#[test]
+fn test_cldb_debugger_function_breakpoint_and_backtrace() {
+    let mut allocator = Allocator::new();
+    let mut debugger = fact_debugger(&mut allocator, "(3)");
+
+    let missing = debugger.command(&mut allocator, "break no-such-function");
+    assert!(missing[0].contains_key("Error"));
+
+    let added = debugger.command(&mut allocator, "break factorial");
+    assert_eq!(added[0]["Breakpoint"], "1");
+
+    let stop = stopped(&debugger.command(&mut allocator, "continue"));
+    assert_eq!(stop["Stopped"], "breakpoint 1");
+    assert_eq!(stop["Function"], "factorial");
+    assert_eq!(debugger.command(&mut allocator, "print 5")[0]["Value"], "3");
+
+    let stop = stopped(&debugger.command(&mut allocator, "c"));
+    assert_eq!(stop["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.command(&mut allocator, "p 5")[0]["Value"], "2");
+
+    let backtrace = debugger.command(&mut allocator, "backtrace");
+    let functions: Vec<String> = backtrace.iter().map(|r| r["Function"].clone()).collect();
+    assert_eq!(functions, vec!["factorial", "factorial", "(main)"]);
+    assert_eq!(backtrace[1]["Arguments"], "VALUE = 3");
+    assert_eq!(backtrace[1]["Left-Env"], "fact-base factorial");
+    assert_eq!(backtrace[2]["Arguments"], "INDEX = 3");
+    assert!(backtrace[1]["Location"].starts_with(&format!("{FACT_FILE}(4)")));
+
+    // Finishing the inner call returns to the outer one.
+    debugger.command(&mut allocator, "delete 1");
+    let stop = stopped(&debugger.command(&mut allocator, "finish"));
+    assert_eq!(stop["Stopped"], "finish");
+    assert_eq!(debugger.frames().len(), 1);
+
+    let end = debugger.command(&mut allocator, "continue");
+    assert_eq!(end.len(), 1);
+    assert_eq!(end[0]["Final"], "6");
+    assert!(debugger.is_ended());
+    assert!(debugger.command(&mut allocator, "step")[0].contains_key("Error"));
+}
+
+#[test]
+fn test_cldb_debugger_line_breakpoint_and_next() {
+    let mut allocator = Allocator::new();
+    let mut debugger = fact_debugger(&mut allocator, "(4)");
+
+    debugger.command(&mut allocator, "break fact.cl:3");
+    let stop = stopped(&debugger.command(&mut allocator, "continue"));
+    assert_eq!(stop["Stopped"], "breakpoint 1");
+    assert_eq!(stop["Function"], "fact-base");
+    assert!(stop["Location"].starts_with(&format!("{FACT_FILE}(3)")));
+    assert_eq!(debugger.frames().len(), 5);
+    assert_eq!(debugger.command(&mut allocator, "print 5")[0]["Value"], "1");
+
+    // Stepping over never goes deeper than where it started.
+    while !debugger.is_ended() {
+        let frames = debugger.frames().len();
+        let rows = debugger.command(&mut allocator, "next");
+        assert!(debugger.is_ended() || debugger.frames().len() <= frames);
+        if debugger.is_ended() {
+            assert_eq!(rows.last().unwrap()["Final"], "24");
+        }
+    }
+}
+
+#[test]
+fn test_cldb_debugger_reverse_step_and_continue() {
+    let mut allocator = Allocator::new();
+    let mut debugger = fact_debugger(&mut allocator, "(3)");
+
+    debugger.command(&mut allocator, "break factorial");
+    debugger.command(&mut allocator, "continue");
+    let first_stop_cost = debugger.run().cost();
+    let second = stopped(&debugger.command(&mut allocator, "continue"));
+    assert_eq!(second["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.frames().len(), 2);
+
+    // Going back to the previous breakpoint restores the call stack too.
+    let back = stopped(&debugger.command(&mut allocator, "reverse-continue"));
+    assert_eq!(back["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.run().cost(), first_stop_cost);
+    assert_eq!(debugger.frames().len(), 1);
+    assert_eq!(debugger.command(&mut allocator, "print 5")[0]["Value"], "3");
+
+    // Stepping forward then back lands where stepping forward started.
+    let row = debugger.run().row();
+    debugger.command(&mut allocator, "step");
+    debugger.command(&mut allocator, "step");
+    let after_one = debugger.command(&mut allocator, "reverse-step");
+    assert_eq!(after_one[0]["Row"], row.to_string());
+    assert_eq!(debugger.run().row(), row + 1);
+
+    let back = stopped(&debugger.command(&mut allocator, "rc"));
+    assert_eq!(back["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.run().cost(), first_stop_cost);
+    let start = stopped(&debugger.command(&mut allocator, "rc"));
+    assert_eq!(start["Stopped"], "start");
+    assert_eq!(debugger.run().row(), 0);
+    assert!(debugger.command(&mut allocator, "rs")[0].contains_key("Error"));
+
+    // Running again gives the same result, and it can be traced back to the
+    // multiplications that made it.
+    let end = debugger.command(&mut allocator, "continue");
+    assert_eq!(end.last().unwrap()["Stopped"], "breakpoint 1");
+    debugger.command(&mut allocator, "delete 1");
+    let end = debugger.command(&mut allocator, "continue");
+    assert_eq!(end[0]["Final"], "6");
+
+    let origin = debugger.command(&mut allocator, "origin 6");
+    assert_eq!(origin[0]["Operator"], "18");
+    assert_eq!(origin[0]["Arguments"], "(3 2)");
+    assert!(origin
+        .iter()
+        .any(|r| r.get("Arguments") == Some(&"(2 1)".to_string())));
+    assert!(debugger.command(&mut allocator, "origin 77")[0].contains_key("Error"));
+}
+
+#[test]
+fn test_cldb_debugger_watches_and_conditional_breakpoint() {
+    let mut allocator = Allocator::new();
+    let mut debugger = fact_debugger(&mut allocator, "(4)");
+
+    // Outside of any function only the program's arguments are in scope.
+    let added = debugger.command(&mut allocator, "watch (factorial (- INDEX 1))");
+    assert_eq!(added[0]["Watch"], "1");
+    assert_eq!(added[0]["Value"], "6");
+    debugger.command(&mut allocator, "watch (* VALUE 10)");
+    assert!(debugger.command(&mut allocator, "watch (+ 1")[0].contains_key("Error"));
+    assert_eq!(debugger.watches().len(), 2);
+
+    let added = debugger.command(&mut allocator, "break if (= VALUE 2)");
+    assert_eq!(added[0]["Location"], "if (= VALUE 2)");
+    let rows = debugger.command(&mut allocator, "continue");
+    assert_eq!(stopped(&rows)["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.command(&mut allocator, "print 5")[0]["Value"], "2");
+    assert_eq!(rows[0]["Watch (* VALUE 10)"], "20");
+    assert!(rows[0]["Watch (factorial (- INDEX 1))"].starts_with("error"));
+
+    // The condition stops holding in the call for 1 and holds again once
+    // that call returns to the one for 2.
+    debugger.command(&mut allocator, "unwatch 1");
+    let rows = debugger.command(&mut allocator, "continue");
+    assert_eq!(stopped(&rows)["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.frames().len(), 3);
+    assert_eq!(rows[0]["Watch (* VALUE 10)"], "20");
+
+    let rows = debugger.command(&mut allocator, "continue");
+    let end = rows.last().unwrap();
+    assert_eq!(end["Final"], "24");
+    assert!(!end.contains_key("Watch (factorial (- INDEX 1))"));
+    assert!(end["Watch (* VALUE 10)"].starts_with("error"));
+
+    // Going back finds where the condition began to hold.
+    let back = stopped(&debugger.command(&mut allocator, "reverse-continue"));
+    assert_eq!(back["Stopped"], "breakpoint 1");
+    assert_eq!(debugger.command(&mut allocator, "print 5")[0]["Value"], "2");
+}
+
+#[test]
+fn test_cldb_named_arguments_of_applied_functions() {
+    let mut allocator = Allocator::new();
+    let mut debugger = fact_debugger(&mut allocator, "(3)");
+
+    let mut applied = Vec::new();
+    while !debugger.is_ended() {
+        if let Some(row) = debugger.advance(&mut allocator) {
+            if let Some(arguments) = row.get("Function-Arguments") {
+                applied.push((row["Function"].clone(), arguments.clone()));
+                // The functions in the environment aren't arguments.
+                assert_eq!(row["Left-Env"], "fact-base factorial");
+            }
+        }
+    }
+
+    let expected: Vec<(String, String)> = [
+        ("factorial", "VALUE = 3"),
+        ("factorial", "VALUE = 2"),
+        ("factorial", "VALUE = 1"),
+        ("fact-base", "VALUE = 1"),
+    ]
+    .iter()
+    .map(|(f, a)| (f.to_string(), a.to_string()))
+    .collect();
+    assert_eq!(applied, expected);
+}
+
+#[test]
+fn test_cldb_mock_function_uses_program_helpers() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(FACT_FILE));
+    let content = fs::read_to_string(FACT_FILE).expect("should exist");
+    let mut symbol_table = HashMap::new();
+    let program = compile_file(
+        &mut allocator,
+        runner.clone(),
+        opts.clone(),
+        &content,
+        &mut symbol_table,
+    )
+    .expect("should compile");
+
+    let helpers = program_helpers(opts.clone(), &content).expect("should have helpers");
+    let evaluator = Rc::new(Evaluator::new(opts.clone(), runner.clone(), helpers));
+    assert!(CldbMockFunction::from_spec(
+        opts.clone(),
+        evaluator.clone(),
+        &symbol_table,
+        "factorial"
+    )
+    .is_err());
+    let mock = CldbMockFunction::from_spec(
+        opts,
+        evaluator,
+        &symbol_table,
+        "factorial=(fact-base (* VALUE 10))",
+    )
+    .expect("should make a mock");
+    let mut overrides: HashMap<String, Box<dyn CldbSingleBespokeOverride>> = HashMap::new();
+    overrides.insert(mock.name().to_string(), Box::new(mock));
+
+    let env = CldbRunEnv::new(
+        None,
+        vec![],
+        Box::new(CldbOverrideBespokeCode::new(symbol_table, overrides)),
+    );
+    let args = parse_sexp(Srcloc::start("*args*"), "(4)".bytes()).expect("should parse")[0].clone();
+    let mut run = CldbRun::new(
+        runner,
+        prims::prim_map(),
+        Box::new(env),
+        start_step(Rc::new(program), args),
+    );
+    while !run.is_ended() {
+        run.step(&mut allocator);
+    }
+    assert_eq!(run.final_result().expect("should finish").to_string(), "40");
+}
+
+#[test]
+fn test_cldb_debugger_backtrace_on_failure() {
+    let mut allocator = Allocator::new();
+    let mut debugger = file_debugger(&mut allocator, FACT_RAISE_FILE, "(3)");
+
+    let rows = debugger.command(&mut allocator, "continue");
+    let failure = rows
+        .iter()
+        .position(|r| r.contains_key("Failure"))
+        .expect("should fail");
+    let frames = &rows[failure + 1..];
+    let functions: Vec<&str> = frames.iter().map(|r| r["Function"].as_str()).collect();
+    assert_eq!(
+        functions,
+        vec!["fact-base", "factorial", "factorial", "factorial", "(main)"]
+    );
+    assert_eq!(frames[0]["Arguments"], "VALUE = 1");
+    assert!(frames[0]["Location"].starts_with(&format!("{FACT_RAISE_FILE}(3)")));
+    assert_eq!(frames[3]["Arguments"], "VALUE = 3");
+    assert_eq!(frames[4]["Arguments"], "INDEX = 3");
+
+    // The backtrace stays available once the run has failed.
+    assert_eq!(debugger.command(&mut allocator, "bt"), frames.to_vec());
+}
+
+#[test]
+fn test_backtrace_run() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let run_file = |allocator: &mut Allocator, file: &str, args: &str| {
+        let opts = Rc::new(DefaultCompilerOpts::new(file));
+        let content = fs::read_to_string(file).expect("should exist");
+        let mut symbol_table = HashMap::new();
+        let program = compile_file(allocator, runner.clone(), opts, &content, &mut symbol_table)
+            .expect("should compile");
+        let args =
+            parse_sexp(Srcloc::start("*args*"), args.bytes()).expect("should parse")[0].clone();
+        backtrace_run(
+            allocator,
+            runner.clone(),
+            prims::prim_map(),
+            symbol_table,
+            Rc::new(program),
+            args,
+            None,
+        )
+    };
+
+    let result = run_file(&mut allocator, FACT_FILE, "(3)").expect("should run");
+    assert_eq!(result.to_string(), "6");
+
+    let failed = run_file(&mut allocator, FACT_RAISE_FILE, "(2)").expect_err("should raise");
+    assert!(matches!(&failed.failure, RunFailure::RunErr(_, e) if e.starts_with("clvm raise")));
+    let names: Vec<&str> = failed.frames.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["fact-base", "factorial", "factorial", "(main)"]);
+    let text = failed.to_string();
+    let mut lines = text.lines();
+    assert!(lines.next().unwrap().contains("clvm raise"));
+    assert_eq!(lines.next(), Some("backtrace:"));
+    assert!(lines.next().unwrap().starts_with(&format!(
+        "  0: fact-base (VALUE = 1) at {FACT_RAISE_FILE}(3)"
+    )));
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("  1: factorial (VALUE = 1) at "));
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("  2: factorial (VALUE = 2) at "));
+    assert!(lines
+        .next()
+        .unwrap()
+        .starts_with("  3: (main) (INDEX = 2) at "));
+}
+
+#[test]
+fn test_run_with_backtrace() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(FACT_RAISE_FILE));
+    let content = fs::read_to_string(FACT_RAISE_FILE).expect("should exist");
+    let mut symbol_table = HashMap::new();
+    let program = Rc::new(
+        compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts,
+            &content,
+            &mut symbol_table,
+        )
+        .expect("should compile"),
+    );
+    let args = parse_sexp(Srcloc::start("*args*"), "(2)".bytes()).expect("should parse")[0].clone();
+
+    let failed = run_with_backtrace(
+        &mut allocator,
+        runner.clone(),
+        prims::prim_map(),
+        Some(symbol_table.clone()),
+        program.clone(),
+        args.clone(),
+        None,
+        None,
+    )
+    .expect_err("should raise");
+    let names: Vec<&str> = failed.frames.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["fact-base", "factorial", "factorial", "(main)"]);
+
+    // run is the same run without a symbol table to name the frames.
+    let plain = run(
+        &mut allocator,
+        runner.clone(),
+        prims::prim_map(),
+        program.clone(),
+        args.clone(),
+        None,
+    )
+    .expect_err("should raise");
+    assert_eq!(plain, failed.failure);
+
+    // Running out of iterations is a failure with a backtrace too.
+    let timed_out = run_with_backtrace(
+        &mut allocator,
+        runner,
+        prims::prim_map(),
+        Some(symbol_table),
+        program,
+        args,
+        Some(100),
+        None,
+    )
+    .expect_err("should time out");
+    assert!(matches!(&timed_out.failure, RunFailure::RunErr(_, e) if e == "timeout"));
+    assert_eq!(
+        timed_out.frames.last().map(|f| f.name.as_str()),
+        Some("(main)")
+    );
+}
+
+#[test]
+fn test_cldb_classic_program() {
+    let file = "resources/tests/steprun/classic_fact.clvm";
+    let mut allocator = Allocator::new();
+    let content = fs::read_to_string(file).expect("should exist");
+    assert!(is_classic_mod(&mut allocator, &content));
+    let modern = fs::read_to_string(FACT_FILE).expect("should exist");
+    assert!(!is_classic_mod(&mut allocator, &modern));
+
+    let mut symbol_table = HashMap::new();
+    let program = compile_classic_program(&mut allocator, &[], file, &content, &mut symbol_table)
+        .expect("should compile");
+    assert_eq!(symbol_table["__chia__main_arguments"], "(INDEX)");
+    let factorial_hash = symbol_table
+        .iter()
+        .find(|(_, v)| *v == "factorial")
+        .map(|(k, _)| k.clone())
+        .expect("should name factorial");
+    assert_eq!(
+        symbol_table[&format!("{factorial_hash}_arguments")],
+        "(VALUE)"
+    );
+
+    let mut env = CldbRunEnv::new(
+        Some(file.to_string()),
+        content.lines().map(|l| l.to_string()).collect(),
+        Box::new(CldbNoOverride::new_symbols(symbol_table.clone())),
+    );
+    env.set_symbol_table(symbol_table.clone());
+    let args = parse_sexp(Srcloc::start("*args*"), "(3)".bytes()).expect("should parse")[0].clone();
+    let mut debugger = CldbDebugger::new(
+        Rc::new(DefaultProgramRunner::new()),
+        prims::prim_map(),
+        symbol_table,
+        Box::new(env),
+        start_step(program, args),
+    );
+
+    debugger.command(&mut allocator, "break factorial");
+    let stop = stopped(&debugger.command(&mut allocator, "continue"));
+    assert_eq!(stop["Function"], "factorial");
+    // Stopped on entry, the run is still where the main expression called it.
+    assert!(stop["Location"].starts_with(&format!("{file}(4)")));
+    debugger.command(&mut allocator, "step");
+    let stop = stopped(&debugger.command(&mut allocator, "step"));
+    assert!(stop["Location"].starts_with(&format!("{file}(3)")));
+    let backtrace = debugger.command(&mut allocator, "backtrace");
+    assert_eq!(backtrace[0]["Arguments"], "VALUE = 3");
+    assert_eq!(backtrace[1]["Arguments"], "INDEX = 3");
+
+    debugger.command(&mut allocator, "delete 1");
+    let rows = debugger.command(&mut allocator, "continue");
+    assert_eq!(rows.last().expect("should finish")["Final"], "6");
+}
+
+#[test]
+fn test_env_changes_and_folding() {
+    let parse =
+        |s: &str| parse_sexp(Srcloc::start("*env*"), s.bytes()).expect("should parse")[0].clone();
+    let old = parse("((100 200) 3 4)");
+    let new = parse("((100 200) 3 5)");
+    let changes = env_changes(old.clone(), new.clone());
+    // The third element of the environment is at path 11.
+    assert_eq!(format_env_changes(&changes), "11: 4 -> 5");
+    assert_eq!(
+        format_env_changes(&env_changes(old.clone(), old.clone())),
+        "unchanged"
+    );
+    // Values are compared as clvm values, however they're written.
+    assert_eq!(
+        format_env_changes(&env_changes(parse("(89)"), parse("(\"Y\")"))),
+        "unchanged"
+    );
+
+    let folded = fold_unchanged(old.clone(), new).to_string();
+    assert!(folded.starts_with("(#"));
+    assert!(folded.ends_with(" 3 5)"));
+    assert!(fold_unchanged(old.clone(), old)
+        .to_string()
+        .starts_with('#'));
+}
+
+#[test]
+fn test_cldb_env_diff_rows() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(FACT_FILE));
+    let content = fs::read_to_string(FACT_FILE).expect("should exist");
+    let mut symbol_table = HashMap::new();
+    let program = compile_file(
+        &mut allocator,
+        runner.clone(),
+        opts,
+        &content,
+        &mut symbol_table,
+    )
+    .expect("should compile");
+
+    let mut env = CldbRunEnv::new(None, vec![], Box::new(CldbNoOverride::new()));
+    env.set_env_diff(true);
+    let args = parse_sexp(Srcloc::start("*args*"), "(3)".bytes()).expect("should parse")[0].clone();
+    let mut run = CldbRun::new(
+        runner,
+        prims::prim_map(),
+        Box::new(env),
+        start_step(Rc::new(program), args),
+    );
+    let mut diffs = Vec::new();
+    while !run.is_ended() {
+        if let Some(row) = run.step(&mut allocator) {
+            if let Some(diff) = row.get("Env-Diff") {
+                diffs.push(diff.clone());
+                if diff == "5: 3 -> 2" {
+                    // The functions on the left are the same, so they're folded.
+                    assert!(row["Env"].starts_with('#'));
+                    assert_eq!(row["Env-Args"], "(2)");
+                }
+            }
+        }
+    }
+    assert!(diffs.contains(&"5: 3 -> 2".to_string()));
+    assert!(diffs.contains(&"5: 2 -> 1".to_string()));
+    assert!(diffs.contains(&"unchanged".to_string()));
+}
+
+#[test]
+fn test_cldb_run_keeps_history_only_when_asked() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(FACT_FILE));
+    let content = fs::read_to_string(FACT_FILE).expect("should exist");
+    let program = Rc::new(
+        compile_file(
+            &mut allocator,
+            runner.clone(),
+            opts,
+            &content,
+            &mut HashMap::new(),
+        )
+        .expect("should compile"),
+    );
+    let args = parse_sexp(Srcloc::start("*args*"), "(3)".bytes()).expect("should parse")[0].clone();
+
+    for keep_history in [false, true] {
+        let env = CldbRunEnv::new(None, vec![], Box::new(CldbNoOverride::new()));
+        let mut run = CldbRun::new(
+            runner.clone(),
+            prims::prim_map(),
+            Box::new(env),
+            start_step(program.clone(), args.clone()),
+        );
+        run.set_keep_history(keep_history);
+        while !run.is_ended() {
+            run.step(&mut allocator);
+        }
+        assert_eq!(run.final_result().expect("should finish").to_string(), "6");
+        assert_eq!(run.rows().len(), if keep_history { run.row() } else { 0 });
+        assert_eq!(run.history_len() > 0, keep_history);
+        assert_eq!(run.step_back(), keep_history);
+    }
+
+    let mut debugger = fact_debugger(&mut allocator, "(3)");
+    debugger.set_reverse_stepping(false);
+    debugger.command(&mut allocator, "continue");
+    assert!(debugger.command(&mut allocator, "reverse-step")[0].contains_key("Error"));
+}
+
+#[test]
+fn test_cldb_env_diff_after_step_back() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts = Rc::new(DefaultCompilerOpts::new(FACT_FILE));
+    let content = fs::read_to_string(FACT_FILE).expect("should exist");
+    let program = compile_file(
+        &mut allocator,
+        runner.clone(),
+        opts,
+        &content,
+        &mut HashMap::new(),
+    )
+    .expect("should compile");
+
+    let mut env = CldbRunEnv::new(None, vec![], Box::new(CldbNoOverride::new()));
+    env.set_env_diff(false);
+    let args = parse_sexp(Srcloc::start("*args*"), "(3)".bytes()).expect("should parse")[0].clone();
+    let mut run = CldbRun::new(
+        runner,
+        prims::prim_map(),
+        Box::new(env),
+        start_step(Rc::new(program), args),
+    );
+    run.set_keep_history(true);
+    let mut env_diffs = |run: &mut CldbRun| {
+        let mut diffs = Vec::new();
+        while !run.is_ended() {
+            if let Some(diff) = run
+                .step(&mut allocator)
+                .and_then(|r| r.get("Env-Diff").cloned())
+            {
+                diffs.push(diff);
+            }
+        }
+        diffs
+    };
+    let forward = env_diffs(&mut run);
+    while run.step_back() {}
+    // Going forward again compares each apply with the same environment.
+    assert_eq!(env_diffs(&mut run), forward);
+}
@@ -0,0 +1,78 @@
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::binutils::assemble;
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::clvm::convert_from_clvm_rs;
+use crate::compiler::compiler::DefaultCompilerOpts;
+use crate::compiler::comptypes::CompilerOpts;
+use crate::compiler::differential::{differential_run, DifferentialResult, Verdict};
+use crate::compiler::sexp::parse_sexp;
+use crate::compiler::srcloc::Srcloc;
+
+fn run_differential(program: &str, inputs: &[&str]) -> Vec<DifferentialResult> {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts: Rc<dyn CompilerOpts> = Rc::new(DefaultCompilerOpts::new("*test*"));
+    let loc = Srcloc::start("*test*");
+    let program = parse_sexp(loc.clone(), program.bytes()).unwrap();
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|i| {
+            let node = assemble(&mut allocator, i).unwrap();
+            convert_from_clvm_rs(&mut allocator, loc.clone(), node).unwrap()
+        })
+        .collect();
+    differential_run(&mut allocator, runner, opts, &program, &inputs).unwrap()
+}
+
+#[test]
+fn test_differential_factorial_agrees() {
+    let results = run_differential(
+        "(mod (N) (include *standard-cl-21*) (defun fact (N) (if (= N 0) 1 (* N (fact (- N 1))))) (fact N))",
+        &["(5)", "(0)"],
+    );
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|r| r.verdict == Verdict::Agree));
+    assert_eq!(results[0].evaluated.as_ref().unwrap().to_string(), "120");
+    assert_eq!(results[0].compiled.as_ref().unwrap().1.to_string(), "120");
+    assert_eq!(results[1].evaluated.as_ref().unwrap().to_string(), "1");
+}
+
+#[test]
+fn test_differential_destructured_arguments() {
+    let results = run_differential(
+        "(mod ((A . B) C) (include *standard-cl-21*) (defun-inline sq (Y) (* Y Y)) (let ((Z (sq C))) (list (+ A Z) B)))",
+        &["((1 . 2) 3)", "((10 20 30) 4)"],
+    );
+    assert!(results.iter().all(|r| r.verdict == Verdict::Agree));
+    assert_eq!(results[0].evaluated.as_ref().unwrap().to_string(), "(10 2)");
+    assert_eq!(
+        results[1].compiled.as_ref().unwrap().1.to_string(),
+        "(26 (20 30))"
+    );
+}
+
+#[test]
+fn test_differential_both_failing_agree() {
+    let results = run_differential(
+        "(mod (X) (include *standard-cl-21*) (if X (x X) 1))",
+        &["(3)", "(())"],
+    );
+    assert_eq!(results[0].verdict, Verdict::Agree);
+    assert!(results[0].evaluated.is_err());
+    assert!(results[0].compiled.is_err());
+    assert_eq!(results[1].verdict, Verdict::Agree);
+    assert_eq!(results[1].to_string(), "AGREE (()): 1 (cost 662)");
+}
+
+#[test]
+fn test_differential_apply_quoted_input() {
+    let results = run_differential(
+        "(mod (X) (include *standard-cl-21*) (a X ()))",
+        &["((q . 7))"],
+    );
+    assert_eq!(results[0].verdict, Verdict::Agree);
+    assert_eq!(results[0].evaluated.as_ref().unwrap().to_string(), "7");
+}
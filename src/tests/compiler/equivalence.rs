@@ -0,0 +1,87 @@
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::equivalence::{
+    check_equivalence, compile_classic, compile_modern, inject_dialect, EquivalenceResult,
+};
+use crate::compiler::sexp::parse_sexp;
+use crate::compiler::srcloc::Srcloc;
+
+const FACT_PROGRAM: &str = indoc! {"
+    (mod (N)
+      (defconstant K 3)
+      (defun fact (N) (if (= N 0) 1 (* N (fact (- N 1)))))
+      (+ (fact N) K))"};
+
+fn compare_dialects(program: &str, dialect: &str, solutions: &[&str]) -> Vec<EquivalenceResult> {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let loc = Srcloc::start("*test*");
+    let classic = compile_classic(&mut allocator, &[], "*test*", program).unwrap();
+    let source = parse_sexp(loc.clone(), program.bytes()).unwrap();
+    let modern_forms = inject_dialect(&source, dialect).unwrap();
+    let modern =
+        compile_modern(&mut allocator, runner.clone(), &[], "*test*", &modern_forms).unwrap();
+    let solutions: Vec<_> = solutions
+        .iter()
+        .map(|s| parse_sexp(loc.clone(), s.bytes()).unwrap()[0].clone())
+        .collect();
+    check_equivalence(&mut allocator, runner, &classic, &modern, &solutions, None)
+}
+
+#[test]
+fn test_inject_dialect() {
+    let source = parse_sexp(Srcloc::start("*test*"), "(mod (X) (c X X))".bytes()).unwrap();
+    let injected = inject_dialect(&source, "*standard-cl-22*").unwrap();
+    assert_eq!(
+        injected[0].to_string(),
+        "(mod (X) (include *standard-cl-22*) (c X X))"
+    );
+}
+
+#[test]
+fn test_inject_dialect_needs_mod() {
+    let source = parse_sexp(Srcloc::start("*test*"), "(defun f (X) X)".bytes()).unwrap();
+    assert!(inject_dialect(&source, "*standard-cl-22*").is_err());
+}
+
+#[test]
+fn test_compile_classic_refuses_modern() {
+    let mut allocator = Allocator::new();
+    let result = compile_classic(
+        &mut allocator,
+        &[],
+        "*test*",
+        "(mod (X) (include *standard-cl-21*) X)",
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_equivalence_factorial_same_results() {
+    let results = compare_dialects(FACT_PROGRAM, "*standard-cl-21*", &["(5)", "(0)"]);
+    assert!(results.iter().all(|r| r.results_match()));
+    assert_eq!(
+        results[0].modern.result.as_ref().unwrap().to_string(),
+        "123"
+    );
+    assert_eq!(results[0].classic.cost, 15061);
+    assert_eq!(results[0].cost_difference(), 874);
+
+    let fact = results[0]
+        .functions()
+        .into_iter()
+        .find(|f| f.name == "fact")
+        .unwrap();
+    assert_eq!(fact.classic.unwrap().calls, 6);
+    assert_eq!(fact.modern.unwrap().calls, 6);
+}
+
+#[test]
+fn test_equivalence_reports_different_results() {
+    let results = compare_dialects("(mod (X) (c (f X) @))", "*standard-cl-22*", &["((3 4))"]);
+    assert!(!results[0].results_match());
+    assert!(results[0].to_string().starts_with("DIFFERENT ((3 4))"));
+}
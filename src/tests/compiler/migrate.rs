@@ -0,0 +1,135 @@
+use std::rc::Rc;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::equivalence::{check_equivalence, compile_classic, compile_modern};
+use crate::compiler::migrate::{migrate, Migration};
+use crate::compiler::sexp::parse_sexp;
+use crate::compiler::srcloc::Srcloc;
+
+const CLASSIC_PROGRAM: &str = indoc! {"
+    (mod (X)
+      ; classic constants
+      (defconstant OP (+ 1 2))
+      (defconstant NAME foo)
+      (defconstant PAIR (1 . 2))
+      (defun g (Y) (c Y OP))
+      (c (g X) (c NAME PAIR)))"};
+
+fn migrate_text(program: &str) -> Migration {
+    let source = parse_sexp(Srcloc::start("*test*"), program.bytes()).unwrap();
+    migrate(&source, "*standard-cl-22*").unwrap()
+}
+
+#[test]
+fn test_migrate_constants() {
+    let migration = migrate_text(CLASSIC_PROGRAM);
+    assert_eq!(
+        migration.text(),
+        indoc! {"
+            (mod (X)
+              (include *standard-cl-22*)
+              (defconstant OP (16 1 2))
+              (defconstant NAME \"foo\")
+              (defconstant PAIR (1 . 2))
+              (defun g (Y) (c Y OP))
+              (c (g X) (c NAME PAIR))
+              )
+        "}
+    );
+    assert_eq!(migration.changes.len(), 2);
+    assert!(migration.warnings.is_empty());
+}
+
+#[test]
+fn test_migrate_keeps_classic_results() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let loc = Srcloc::start("*test*");
+    let migration = migrate_text(CLASSIC_PROGRAM);
+    let classic = compile_classic(&mut allocator, &[], "*test*", CLASSIC_PROGRAM).unwrap();
+    let modern = compile_modern(
+        &mut allocator,
+        runner.clone(),
+        &[],
+        "*test*",
+        &migration.forms,
+    )
+    .unwrap();
+    let solutions: Vec<_> = ["(5)", "((1 2))"]
+        .iter()
+        .map(|s| parse_sexp(loc.clone(), s.bytes()).unwrap()[0].clone())
+        .collect();
+    let results = check_equivalence(&mut allocator, runner, &classic, &modern, &solutions, None);
+    assert!(results.iter().all(|r| r.results_match()));
+    assert_eq!(
+        results[0].modern.result.as_ref().unwrap().to_string(),
+        "((5 16 1 2) 6713199 1 . 2)"
+    );
+}
+
+#[test]
+fn test_migrate_warnings() {
+    let migration = migrate_text(indoc! {"
+        (mod (X)
+          (include \"helpers.clib\")
+          (defmacro twice (A) (list + A A))
+          (defun f (Y) Y)
+          (c (f X) @))"});
+    let messages: Vec<String> = migration
+        .warnings
+        .iter()
+        .map(|w| w.message.clone())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "\"helpers.clib\" is included in the modern dialect too and may need migrating"
+                .to_string(),
+            "macros expand differently in the modern dialect; check their uses".to_string(),
+            "classic chialisp calls the f operator rather than this definition".to_string(),
+            "@ is the whole environment in classic chialisp but not in the modern dialect"
+                .to_string(),
+        ]
+    );
+}
+
+#[test]
+fn test_migrate_refuses_modern() {
+    let source = parse_sexp(
+        Srcloc::start("*test*"),
+        "(mod (X) (include *standard-cl-21*) X)".bytes(),
+    )
+    .unwrap();
+    assert!(migrate(&source, "*standard-cl-22*").is_err());
+}
+
+#[test]
+fn test_migrate_operator_name_constant() {
+    let program = "(mod (X) (defconstant D +) (c D X))";
+    let migration = migrate_text(program);
+    assert_eq!(
+        migration.forms[0].to_string(),
+        "(mod (X) (include *standard-cl-22*) (defconstant D 16) (c D X))"
+    );
+
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let classic = compile_classic(&mut allocator, &[], "*test*", program).unwrap();
+    let modern = compile_modern(
+        &mut allocator,
+        runner.clone(),
+        &[],
+        "*test*",
+        &migration.forms,
+    )
+    .unwrap();
+    let solution = parse_sexp(Srcloc::start("*test*"), "(5)".bytes()).unwrap()[0].clone();
+    let results = check_equivalence(&mut allocator, runner, &classic, &modern, &[solution], None);
+    assert!(results[0].results_match());
+    assert_eq!(
+        results[0].modern.result.as_ref().unwrap().to_string(),
+        "(16 . 5)"
+    );
+}
@@ -1,12 +1,19 @@
 use num_bigint::ToBigInt;
 use std::rc::Rc;
 
-use crate::compiler::sexp::{parse_sexp, SExp};
+use crate::compiler::sexp::{parse_sexp, SExp, SExpParser};
 use crate::compiler::srcloc::{Srcloc, Until};
 
+mod cltest;
 mod clvm;
 mod compiler;
+mod dap;
+mod debugger;
+mod differential;
+mod equivalence;
 mod evaluate;
+mod migrate;
+mod proptest;
 mod repl;
 mod srcloc;
 mod usecheck;
@@ -36,3 +43,28 @@ fn test_sexp_parse_print() {
 
     assert_eq!(parse_result.unwrap()[0].to_string(), "(hi . 3)".to_string())
 }
+
+#[test]
+fn test_sexp_parser_incremental() {
+    let mut parser = SExpParser::new(Srcloc::start("test.cl"));
+    parser
+        .feed("(defun f (X) ; a comment )) with parens\n".bytes())
+        .unwrap();
+    assert!(parser.is_incomplete());
+    parser.feed("  (c X \")\"\n".bytes()).unwrap();
+    assert!(parser.is_incomplete());
+    parser.feed("  ))\n 3".bytes()).unwrap();
+    assert!(!parser.is_incomplete());
+    let forms = parser.finish().unwrap();
+    assert_eq!(forms.len(), 2);
+    assert_eq!(forms[0].to_string(), "(defun f (X) (c X \")\"))");
+    assert_eq!(forms[1].to_string(), "3");
+
+    let mut parser = SExpParser::new(Srcloc::start("test.cl"));
+    assert!(parser.feed("(a b))".bytes()).is_err());
+
+    let mut parser = SExpParser::new(Srcloc::start("test.cl"));
+    parser.feed("\"unterminated".bytes()).unwrap();
+    assert!(parser.is_incomplete());
+    assert!(parser.finish().is_err());
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use clvm_rs::allocator::Allocator;
+
+use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::compiler::compiler::{compile_file, DefaultCompilerOpts};
+use crate::compiler::comptypes::CompilerOpts;
+use crate::compiler::proptest::{
+    compile_property, function_runner, program_helper_forms, ArgGenerator, PropertyCheck,
+};
+use crate::compiler::sexp::{parse_sexp, SExp};
+use crate::compiler::srcloc::Srcloc;
+
+const CLAMP_PROGRAM: &str = "resources/tests/proptest/clamp.clsp";
+
+fn clamp_check(
+    allocator: &mut Allocator,
+    function: &str,
+    property: &str,
+    generators: &[(&str, &str)],
+) -> PropertyCheck {
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let opts: Rc<dyn CompilerOpts> = Rc::new(DefaultCompilerOpts::new(CLAMP_PROGRAM));
+    let content = fs::read_to_string(CLAMP_PROGRAM).unwrap();
+    let mut symbol_table = HashMap::new();
+    let program = compile_file(
+        allocator,
+        runner.clone(),
+        opts.clone(),
+        &content,
+        &mut symbol_table,
+    )
+    .unwrap();
+    let (code, args) = function_runner(Rc::new(program), &symbol_table, function).unwrap();
+    let source = parse_sexp(Srcloc::start(CLAMP_PROGRAM), content.bytes()).unwrap();
+    let helpers = program_helper_forms(&source[0]);
+    let property =
+        compile_property(allocator, runner, opts, args.clone(), &helpers, property).unwrap();
+    PropertyCheck {
+        function: code,
+        args,
+        property,
+        generators: generators
+            .iter()
+            .map(|(name, g)| (name.as_bytes().to_vec(), ArgGenerator::parse(g).unwrap()))
+            .collect(),
+    }
+}
+
+#[test]
+fn test_arg_generator_parse() {
+    assert_eq!(ArgGenerator::parse("int"), Ok(ArgGenerator::Int));
+    assert_eq!(ArgGenerator::parse("bytes32"), Ok(ArgGenerator::Bytes(32)));
+    assert_eq!(
+        ArgGenerator::parse("list:bytes48"),
+        Ok(ArgGenerator::List(Box::new(ArgGenerator::Bytes(48))))
+    );
+    assert!(ArgGenerator::parse("bytesish").is_err());
+    assert!(ArgGenerator::parse("float").is_err());
+}
+
+#[test]
+fn test_arg_generators_make_their_kind() {
+    let mut rng = ChaCha8Rng::seed_from_u64(7);
+    let loc = Srcloc::start("*test*");
+    for _ in 0..20 {
+        if let SExp::QuotedString(_, _, bytes) =
+            ArgGenerator::Bytes(32).generate(&mut rng, &loc).as_ref()
+        {
+            assert_eq!(bytes.len(), 32);
+        } else {
+            panic!("bytes32 should make an atom");
+        }
+
+        let conditions = ArgGenerator::Conditions.generate(&mut rng, &loc);
+        for c in conditions.proper_list().unwrap() {
+            let c = c.proper_list().unwrap();
+            assert!([50, 51, 60, 61, 73].contains(&c[0].to_string().parse::<i32>().unwrap()));
+        }
+    }
+
+    let shrunk =
+        ArgGenerator::Bytes(4).shrink(&SExp::QuotedString(loc.clone(), b'"', vec![1, 2, 3, 4]));
+    for s in shrunk.iter() {
+        if let SExp::QuotedString(_, _, bytes) = s.as_ref() {
+            assert_eq!(bytes.len(), 4);
+        } else {
+            panic!("shrinking bytes should keep them bytes");
+        }
+    }
+}
+
+#[test]
+fn test_property_holds() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let check = clamp_check(
+        &mut allocator,
+        "clamp",
+        "(not (> RESULT LIMIT))",
+        &[("N", "int")],
+    );
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    assert!(check
+        .check(&mut allocator, runner, &mut rng, 100, 1000)
+        .is_none());
+}
+
+#[test]
+fn test_property_counterexample_is_shrunk() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let check = clamp_check(&mut allocator, "clamp", "(> 50 RESULT)", &[("N", "int")]);
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    let counterexample = check
+        .check(&mut allocator, runner, &mut rng, 100, 1000)
+        .unwrap();
+    assert_eq!(counterexample.arguments.len(), 1);
+    assert_eq!(counterexample.arguments[0].0, "N");
+    assert_eq!(counterexample.arguments[0].1.to_string(), "50");
+    assert_eq!(counterexample.result.unwrap().to_string(), "50");
+}
+
+#[test]
+fn test_property_raise_is_a_counterexample() {
+    let mut allocator = Allocator::new();
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let check = clamp_check(
+        &mut allocator,
+        "pair-hash",
+        "(= (strlen RESULT) 32)",
+        &[("A", "bytes32"), ("B", "any")],
+    );
+    let mut rng = ChaCha8Rng::seed_from_u64(3);
+    let counterexample = check
+        .check(&mut allocator, runner, &mut rng, 100, 1000)
+        .unwrap();
+    assert!(counterexample.result.is_err());
+    // sha256 of a pair raises, and the smallest pair is (()).
+    assert_eq!(counterexample.arguments[1].1.to_string(), "(())");
+}
@@ -2,10 +2,13 @@ use std::rc::Rc;
 
 use clvm_rs::allocator::Allocator;
 
-use crate::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
+use crate::classic::clvm_tools::stages::stage_0::{DefaultProgramRunner, TRunProgram};
+use crate::compiler::clvm::convert_to_clvm_rs;
 use crate::compiler::compiler::DefaultCompilerOpts;
-use crate::compiler::comptypes::CompileErr;
-use crate::compiler::repl::Repl;
+use crate::compiler::comptypes::{CompileErr, CompilerOpts};
+use crate::compiler::repl::{replay_transcript, Repl};
+use crate::compiler::sexp::parse_sexp;
+use crate::compiler::srcloc::Srcloc;
 
 fn test_repl_outcome_with_stack_limit<S>(
     inputs: Vec<S>,
@@ -238,3 +241,252 @@ fn test_eval_less_than_forever_recursive() {
         "(q . 4)"
     );
 }
+
+fn test_repl_session(search_paths: &[String], inputs: &[&str]) -> Vec<Result<String, CompileErr>> {
+    let mut allocator = Allocator::new();
+    let opts = Rc::new(DefaultCompilerOpts::new("*repl-test*")).set_search_paths(search_paths);
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let mut repl = Repl::new(opts, runner);
+
+    inputs
+        .iter()
+        .map(|i| {
+            repl.meta_command(&mut allocator, i).unwrap_or_else(|| {
+                repl.process_line(&mut allocator, i.to_string())
+                    .map(|r| r.map(|r| r.to_sexp().to_string()).unwrap_or_default())
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_repl_load_and_list_helpers() {
+    let results = test_repl_session(
+        &["resources/tests/steprun".to_string()],
+        &[
+            ":load fact.cl",
+            "(factorial 4)",
+            "(defconstant FOUR 4)",
+            ":helpers",
+            ":show fact-base",
+        ],
+    );
+    assert_eq!(results[0].as_ref().unwrap(), "defined fact-base factorial");
+    assert_eq!(results[1].as_ref().unwrap(), "(q . 24)");
+    assert_eq!(
+        results[3].as_ref().unwrap(),
+        "defun fact-base (VALUE)\ndefun factorial (VALUE)\ndefconstant FOUR"
+    );
+    assert_eq!(
+        results[4].as_ref().unwrap(),
+        "(defun fact-base (VALUE) VALUE)"
+    );
+}
+
+#[test]
+fn test_repl_reset_forgets_helpers() {
+    let results = test_repl_session(
+        &[],
+        &[
+            ":load resources/tests/steprun/fact.cl",
+            ":reset",
+            ":helpers",
+            "(factorial 4)",
+        ],
+    );
+    assert_eq!(results[1].as_ref().unwrap(), "reset");
+    assert_eq!(results[2].as_ref().unwrap(), "");
+    assert!(results[3].is_err());
+}
+
+#[test]
+fn test_repl_include_library() {
+    let results = test_repl_session(
+        &["resources/tests".to_string()],
+        &[
+            ":include sha256tree.clib",
+            "(sha256tree 1)",
+            ":show sha256tree",
+        ],
+    );
+    assert_eq!(results[0].as_ref().unwrap(), "defined sha256tree");
+    assert_eq!(
+        results[1].as_ref().unwrap(),
+        "(q . -44412188149083219915772186748035909266791016930429887947443501395007119841358)"
+    );
+    assert!(results[2]
+        .as_ref()
+        .unwrap()
+        .starts_with("(defun sha256tree (TREE)"));
+}
+
+#[test]
+fn test_repl_compile_expression() {
+    let results = test_repl_session(
+        &[],
+        &[
+            "(defun double (X) (* X 2))",
+            "(defun unused (X) X)",
+            ":compile (double 3)",
+            ":nonsense",
+        ],
+    );
+    assert_eq!(
+        results[2].as_ref().unwrap(),
+        "(2 (1 2 2 (4 2 (4 (1 . 3) ()))) (4 (1 2 (1 18 5 (1 . 2)) 1) 1))"
+    );
+    assert!(results[3].is_err());
+}
+
+#[test]
+fn test_repl_execution_mode_cost() {
+    let mut allocator = Allocator::new();
+    let opts = Rc::new(DefaultCompilerOpts::new("*repl-test*"));
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let mut repl = Repl::new(opts, runner.clone());
+    repl.process_line(&mut allocator, "(defun double (X) (* X 2))".to_string())
+        .unwrap();
+    let compiled = repl
+        .meta_command(&mut allocator, ":compile (double 21)")
+        .unwrap()
+        .unwrap();
+    repl.set_execute(true);
+
+    let result = repl
+        .process_line(&mut allocator, "(double 21)".to_string())
+        .unwrap()
+        .unwrap();
+    assert_eq!(result.to_sexp().to_string(), "(q . 42)");
+
+    let code = parse_sexp(Srcloc::start("*test*"), compiled.bytes()).unwrap();
+    let code_node = convert_to_clvm_rs(&mut allocator, code[0].clone()).unwrap();
+    let nil = allocator.null();
+    let expected = runner
+        .run_program(&mut allocator, code_node, nil, None)
+        .unwrap();
+    assert_eq!(repl.last_cost(), Some(expected.0));
+
+    repl.process_line(&mut allocator, "(defconstant TWO 2)".to_string())
+        .unwrap();
+    assert_eq!(repl.last_cost(), None);
+}
+
+#[test]
+fn test_repl_execution_mode_solution() {
+    let results = test_repl_session(
+        &[],
+        &[
+            ":run",
+            "(defun double (X) (* X 2))",
+            ":solution (A B) (3 4)",
+            "(+ (double A) B)",
+            "(x A)",
+            ":solution",
+            ":eval",
+            "(double 5)",
+        ],
+    );
+    assert_eq!(results[2].as_ref().unwrap(), "solution (A B) = (3 4)");
+    assert_eq!(results[3].as_ref().unwrap(), "(q . 10)");
+    assert!(results[4].is_err());
+    assert_eq!(results[7].as_ref().unwrap(), "(q . 10)");
+}
+
+#[test]
+fn test_repl_parens_in_strings_and_comments() {
+    assert_eq!(
+        test_repl_outcome(vec![
+            "(defun f (X) ; a comment with )) parens",
+            "  (+ X \")\"))",
+            "(f 1)"
+        ])
+        .unwrap()
+        .unwrap(),
+        "(q . 42)"
+    );
+}
+
+#[test]
+fn test_repl_too_many_parens_is_an_error() {
+    let mut allocator = Allocator::new();
+    let opts = Rc::new(DefaultCompilerOpts::new("*repl-test*"));
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let mut repl = Repl::new(opts, runner);
+
+    assert!(repl
+        .process_line(&mut allocator, "(+ 1".to_string())
+        .unwrap()
+        .is_none());
+    assert!(repl.is_incomplete());
+    assert!(repl
+        .process_line(&mut allocator, "2))".to_string())
+        .is_err());
+    assert!(!repl.is_incomplete());
+    assert_eq!(
+        repl.process_line(&mut allocator, "(+ 1 2)".to_string())
+            .unwrap()
+            .unwrap()
+            .to_sexp()
+            .to_string(),
+        "(q . 3)"
+    );
+
+    repl.process_line(&mut allocator, "(+ 1".to_string())
+        .unwrap();
+    repl.discard_input();
+    assert!(!repl.is_incomplete());
+}
+
+#[test]
+fn test_repl_save_and_load_library() {
+    let saved = tempfile::NamedTempFile::new().unwrap();
+    let saved_path = saved.path().to_str().unwrap().to_string();
+    let save_command = format!(":save {saved_path}");
+    let load_command = format!(":load {saved_path}");
+
+    let results = test_repl_session(
+        &[],
+        &[
+            "(defun double (X) (* X 2))",
+            "(defconstant TWO 2)",
+            "(defun double (X) (+ X X))",
+            &save_command,
+        ],
+    );
+    assert_eq!(
+        results[3].as_ref().unwrap(),
+        &format!("saved 2 helpers to {saved_path}")
+    );
+    assert_eq!(
+        std::fs::read_to_string(&saved_path).unwrap(),
+        "(\n  (defun double (X) (+ X X))\n  (defconstant TWO 2)\n)\n"
+    );
+
+    let results = test_repl_session(&[], &[&load_command, "(double TWO)"]);
+    assert_eq!(results[0].as_ref().unwrap(), "defined double TWO");
+    assert_eq!(results[1].as_ref().unwrap(), "(q . 4)");
+}
+
+#[test]
+fn test_repl_replay_transcript() {
+    let mut allocator = Allocator::new();
+    let opts = Rc::new(DefaultCompilerOpts::new("*repl-test*"));
+    let runner = Rc::new(DefaultProgramRunner::new());
+    let mut repl = Repl::new(opts.clone(), runner.clone());
+    let transcript = std::fs::read_to_string("resources/tests/repl/session.txt").unwrap();
+    let (inputs, mismatches) = replay_transcript(&mut allocator, &mut repl, &transcript);
+    assert_eq!(inputs, 6);
+    assert!(mismatches.is_empty());
+
+    let mut repl = Repl::new(opts, runner);
+    let (inputs, mismatches) = replay_transcript(
+        &mut allocator,
+        &mut repl,
+        ">>> (+ 1 2)\n(q . 3)\n>>> (+ 2 2)\n(q . 5)\n",
+    );
+    assert_eq!(inputs, 2);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].line, 3);
+    assert_eq!(mismatches[0].expected, "(q . 5)");
+    assert_eq!(mismatches[0].actual, "(q . 4)");
+}
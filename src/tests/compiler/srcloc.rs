@@ -1,4 +1,4 @@
-use crate::compiler::srcloc::Srcloc;
+use crate::compiler::srcloc::{parse_srcloc, Srcloc};
 
 // _ is the start to end range.
 // . is the target range.
@@ -78,3 +78,13 @@ fn test_overlap_6() {
     assert!(start.overlap(&overlapping));
     assert!(overlapping.overlap(&start));
 }
+
+#[test]
+fn test_parse_srcloc_roundtrip() {
+    let start = Srcloc::start(&"a-file(1).cl").advance(b'\n').advance(b' ');
+    assert_eq!(parse_srcloc(&start.to_string()), Some(start.clone()));
+    let range = start.ext(&start.advance(b' ').advance(b' '));
+    assert_eq!(range.to_string(), "a-file(1).cl(2):2-a-file(1).cl(2):5");
+    assert_eq!(parse_srcloc(&range.to_string()), Some(range));
+    assert_eq!(parse_srcloc("factorial"), None);
+}
@@ -12,19 +12,18 @@ use wasm_bindgen::JsCast;
 
 use clvmr::allocator::Allocator;
 
-use clvm_tools_rs::classic::clvm::__type_compatibility__::{Bytes, BytesFromType, Stream};
+use clvm_tools_rs::classic::clvm::__type_compatibility__::Stream;
 use clvm_tools_rs::classic::clvm::serialize::sexp_to_stream;
 use clvm_tools_rs::classic::clvm_tools::clvmc::compile_clvm_inner;
 use clvm_tools_rs::classic::clvm_tools::stages::stage_0::DefaultProgramRunner;
 use clvm_tools_rs::compiler::cldb::{
-    hex_to_modern_sexp, CldbOverrideBespokeCode, CldbRun, CldbRunEnv, CldbRunnable,
+    hex_to_modern_sexp, CldbOverrideBespokeCode, CldbRunEnv, CldbRunnable,
     CldbSingleBespokeOverride,
 };
 use clvm_tools_rs::compiler::clvm::{convert_to_clvm_rs, start_step};
-use clvm_tools_rs::compiler::compiler::{
-    extract_program_and_env, path_to_function, rewrite_in_program, DefaultCompilerOpts,
-};
+use clvm_tools_rs::compiler::compiler::DefaultCompilerOpts;
 use clvm_tools_rs::compiler::comptypes::CompileErr;
+use clvm_tools_rs::compiler::debugger::CldbDebugger;
 use clvm_tools_rs::compiler::prims;
 use clvm_tools_rs::compiler::repl::Repl;
 use clvm_tools_rs::compiler::runtypes::RunFailure;
@@ -41,7 +40,7 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 struct JsRunStep {
     allocator: Allocator,
-    cldbrun: CldbRun,
+    debugger: CldbDebugger,
 }
 
 struct JsRepl {
@@ -240,11 +239,18 @@ pub fn create_clvm_runner(
     ));
     let prim_map_rc = Rc::new(prim_map);
     let step = start_step(program.clone(), args.clone());
-    let cldbenv = CldbRunEnv::new(None, vec![], runner_override);
-    let cldbrun = CldbRun::new(runner.clone(), prim_map_rc.clone(), Box::new(cldbenv), step);
+    let mut cldbenv = CldbRunEnv::new(None, vec![], runner_override);
+    cldbenv.set_symbol_table(symbol_table.clone());
+    let debugger = CldbDebugger::new(
+        runner.clone(),
+        prim_map_rc.clone(),
+        symbol_table,
+        Box::new(cldbenv),
+        step,
+    );
 
     let this_id = get_next_id();
-    insert_runner(this_id, JsRunStep { allocator, cldbrun });
+    insert_runner(this_id, JsRunStep { allocator, debugger });
 
     return JsValue::from(this_id);
 }
@@ -252,7 +258,7 @@ pub fn create_clvm_runner(
 #[wasm_bindgen]
 pub fn final_value(runner: i32) -> JsValue {
     with_runner(runner, |r| {
-        r.cldbrun.final_result().map(|v| js_object_from_sexp(v))
+        r.debugger.run().final_result().map(|v| js_object_from_sexp(v))
     })
     .unwrap_or_else(|| JsValue::null())
 }
@@ -266,16 +272,31 @@ pub fn remove_clvm_runner(runner: i32) {
 #[wasm_bindgen]
 pub fn run_step(runner: i32) -> JsValue {
     with_runner(runner, |r| {
-        if r.cldbrun.is_ended() {
+        if r.debugger.is_ended() {
             return None;
         }
 
-        r.cldbrun.step(&mut r.allocator)
+        r.debugger.advance(&mut r.allocator)
     })
     .map(|result_hash| btreemap_to_object(result_hash.iter()))
     .unwrap_or_else(|| JsValue::null())
 }
 
+// Perform a cldb debugger command such as "break fact" or "next", returning
+// the resulting rows.
+#[wasm_bindgen]
+pub fn debugger_command(runner: i32, command: String) -> JsValue {
+    with_runner(runner, |r| {
+        let rows = r.debugger.command(&mut r.allocator, &command);
+        let array = js_sys::Array::new();
+        for (i, row) in rows.iter().enumerate() {
+            array.set(i as u32, btreemap_to_object(row.iter()));
+        }
+        Some(JsValue::from(array))
+    })
+    .unwrap_or_else(|| JsValue::null())
+}
+
 fn make_compile_output(result_stream: &Stream, symbol_table: &HashMap<String, String>) -> JsValue {
     let output_hex = result_stream.get_value().hex();
     let array = js_sys::Array::new();
@@ -323,15 +344,6 @@ pub fn compile(input_js: JsValue, filename_js: JsValue, search_paths_js: Vec<JsV
     }
 }
 
-fn find_function_hash(symbol_table: &HashMap<String, String>, f: &String) -> Option<String> {
-    for (k, v) in symbol_table.iter() {
-        if v == f {
-            return Some(k.clone());
-        }
-    }
-    None
-}
-
 // Given a program hex and symbols, compose the program that executes a given
 // function with some given arguments as though the program's primary expression
 // had been that.
@@ -350,45 +362,23 @@ pub fn compose_run_function(
             return create_clvm_compile_failure(&CompileErr(loc.clone(), e));
         }
     };
-    let function_hash = match find_function_hash(&symbol_table, &function_name) {
-        Some(f) => f,
-        _ => {
-            return create_clvm_compile_failure(&CompileErr(
-                loc.clone(),
-                format!("function not found in symbols: {}", function_name),
-            ));
-        }
-    };
     let program = match hex_to_modern_sexp(&mut allocator, &symbol_table, loc.clone(), &hex_prog) {
         Ok(v) => v,
         Err(e) => {
             return create_clvm_runner_run_failure(&e);
         }
     };
-    let main_env = match extract_program_and_env(program.clone()) {
-        Some(em) => em,
-        _ => {
-            return create_clvm_compile_failure(&CompileErr(
-                program.loc(),
-                "could not extract env from program".to_string(),
-            ));
-        }
-    };
-    let hash_bytes = Bytes::new(Some(BytesFromType::Hex(function_hash.clone())));
-    let function_path = match path_to_function(main_env.1.clone(), &hash_bytes.data().clone()) {
-        Some(p) => p,
-        _ => {
-            return create_clvm_compile_failure(&CompileErr(
-                program.loc(),
-                format!(
-                    "could not find function with hash from symbols: {}",
-                    function_name
-                ),
-            ));
+    let new_program = match clvm_tools_rs::compiler::compiler::compose_run_function(
+        program,
+        &symbol_table,
+        &function_name,
+    ) {
+        Ok(p) => p,
+        Err(e) => {
+            return create_clvm_compile_failure(&e);
         }
     };
 
-    let new_program = rewrite_in_program(function_path, main_env.1);
     let mut result_stream = Stream::new(None);
     let clvm_rs_value = match convert_to_clvm_rs(&mut allocator, new_program) {
         Ok(c) => c,